@@ -1,228 +1,4375 @@
 use std::{
-    collections::{BTreeSet, HashMap, HashSet},
-    io::Read,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io::{Read, Write},
     path::{Path, PathBuf},
     str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
     thread,
 };
 
-use bindex::{address, Location};
+use bindex::{
+    address,
+    cache::{self, Cache},
+    descriptor, electrum,
+    network::Network,
+    notify,
+    redact::{self, Redacted},
+    zmq, Location,
+};
 
 use bitcoin::consensus::deserialize;
 use chrono::{TimeZone, Utc};
-use clap::{Parser, ValueEnum};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use log::*;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 
-#[derive(tabled::Tabled)]
+#[derive(tabled::Tabled, Clone)]
 struct Row {
     txid: String,
     time: String,
     height: String,
+    confirmations: String,
     offset: String,
     delta: String,
+    change: String,
     balance: String,
     ms: String,
     bytes: String,
+    vsize: String,
+    weight: String,
+    fee: String,
+    locktime: String,
+    rbf: String,
+    sequences: String,
+    taproot: String,
+    wsh: String,
+    assets: String,
+    paths: String,
+    payout: String,
 }
 
+/// Column shown only with `--ordinals`
+const ASSETS_COLUMN: &str = "assets";
+
+/// Column shown only with `--fees`
+const FEE_COLUMN: &str = "fee";
+
+/// Column shown only if some rows have key derivation paths
+const PATHS_COLUMN: &str = "paths";
+
+/// Column shown only if some rows are batch payouts
+const PAYOUT_COLUMN: &str = "payout";
+
+/// Transactions paying at least this number of (unwatched) recipients are summarized as batch payouts
+const BATCH_MIN_RECIPIENTS: usize = 10;
+
+/// Columns shown only with `--tx-details`
+const TX_DETAILS_COLUMNS: &[&str] = &["locktime", "rbf", "sequences", "taproot", "wsh"];
+
 impl Row {
+    /// Approximate memory used by the row (in bytes)
+    fn mem_size(&self) -> usize {
+        let fields = [
+            &self.txid,
+            &self.time,
+            &self.height,
+            &self.confirmations,
+            &self.offset,
+            &self.delta,
+            &self.change,
+            &self.balance,
+            &self.ms,
+            &self.bytes,
+            &self.vsize,
+            &self.weight,
+            &self.fee,
+            &self.locktime,
+            &self.rbf,
+            &self.sequences,
+            &self.taproot,
+            &self.wsh,
+            &self.assets,
+            &self.paths,
+            &self.payout,
+        ];
+        std::mem::size_of::<Self>() + fields.iter().map(|s| s.capacity()).sum::<usize>()
+    }
+
     fn dots() -> Self {
         let s = "...";
         Self {
             txid: s.to_owned(),
             time: s.to_owned(),
             height: s.to_owned(),
+            confirmations: s.to_owned(),
             offset: s.to_owned(),
             delta: s.to_owned(),
+            change: s.to_owned(),
             balance: s.to_owned(),
             ms: s.to_owned(),
             bytes: s.to_owned(),
+            vsize: s.to_owned(),
+            weight: s.to_owned(),
+            fee: s.to_owned(),
+            locktime: s.to_owned(),
+            rbf: s.to_owned(),
+            sequences: s.to_owned(),
+            taproot: s.to_owned(),
+            wsh: s.to_owned(),
+            assets: s.to_owned(),
+            paths: s.to_owned(),
+            payout: s.to_owned(),
         }
     }
 }
 
-fn compute_balance(
-    scripts: &HashSet<bitcoin::ScriptBuf>,
-    index: &address::Index,
-    history_limit: usize,
-) -> Result<(), address::Error> {
-    if scripts.is_empty() {
-        return Ok(());
+#[derive(tabled::Tabled)]
+struct TipRow {
+    tip: &'static str,
+    height: usize,
+    hash: bitcoin::BlockHash,
+    time: String,
+    age: String,
+}
+
+impl TipRow {
+    fn new(tip: &'static str, height: usize, header: &bitcoin::block::Header) -> Self {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            tip,
+            height,
+            hash: header.block_hash(),
+            time: Utc
+                .timestamp_opt(header.time.into(), 0)
+                .unwrap()
+                .to_string(),
+            age: format!("{}s", now.as_secs() as i64 - i64::from(header.time)),
+        }
     }
-    let t = std::time::Instant::now();
-    // sort and dedup transaction locations to be analyzed
-    let locations = scripts
-        .iter()
-        .flat_map(|script| index.find(script).expect("script lookup failed"))
-        .collect::<BTreeSet<Location>>();
-    info!(
-        "{} address history: {} txs ({:?})",
-        scripts.len(),
-        locations.len(),
-        t.elapsed()
-    );
+}
 
-    if locations.is_empty() {
-        return Ok(());
+struct Utxo<'a> {
+    value: bitcoin::Amount,
+    script: &'a bitcoin::Script,
+    height: usize,
+    /// Likely change (see `is_change`)
+    change: bool,
+    /// Known inscription/rune envelopes (see `asset_labels`)
+    assets: Vec<&'static str>,
+    /// The derivation paths of the script's keys (if derived from a descriptor)
+    key_path: Option<&'a str>,
+}
+
+/// Whether the input reveals an inscription (an `OP_FALSE OP_IF "ord" ...` envelope in its tapscript).
+fn has_inscription(witness: &bitcoin::Witness) -> bool {
+    use bitcoin::{opcodes::all::OP_IF, script::Instruction};
+    let annex = usize::from(witness.taproot_annex().is_some());
+    if witness.len() < annex + 2 {
+        return false; // no tapscript (e.g. key path spend)
     }
+    let Some(leaf) = witness.taproot_leaf_script() else {
+        return false;
+    };
+    let instructions: Vec<_> = leaf.script.instructions().filter_map(Result::ok).collect();
+    instructions.windows(3).any(|w| match w {
+        [Instruction::PushBytes(op_false), Instruction::Op(op_if), Instruction::PushBytes(tag)] => {
+            op_false.is_empty() && *op_if == OP_IF && tag.as_bytes() == b"ord"
+        }
+        _ => false,
+    })
+}
 
-    let t = std::time::Instant::now();
-    let mut rows = Vec::with_capacity(locations.len());
-    let mut total_bytes = 0;
-    let mut unspent = HashMap::<bitcoin::OutPoint, bitcoin::Amount>::new();
-    let mut balance = bitcoin::SignedAmount::ZERO;
-    for loc in &locations {
-        let t = std::time::Instant::now();
-        let tx_bytes = index.get_tx_bytes(loc)?;
-        total_bytes += tx_bytes.len();
-        let tx: bitcoin::Transaction = deserialize(&tx_bytes).expect("bad tx bytes");
-        let txid = tx.compute_txid();
-        let dt = t.elapsed();
-        let mut delta = bitcoin::SignedAmount::ZERO;
-        for txi in tx.input {
-            if let Some(spent) = unspent.remove(&txi.previous_output) {
-                delta -= spent.to_signed().expect("spent overflow");
-            }
-        }
-        for (n, txo) in tx.output.into_iter().enumerate() {
-            if scripts.contains(&txo.script_pubkey) {
-                delta += txo.value.to_signed().expect("txo.value overflow");
-                unspent.insert(
-                    bitcoin::OutPoint::new(txid, n.try_into().unwrap()),
-                    txo.value,
-                );
+/// Whether the output is a runestone (`OP_RETURN OP_13 ...`).
+fn is_runestone(script: &bitcoin::Script) -> bool {
+    use bitcoin::opcodes::all::{OP_PUSHNUM_13, OP_RETURN};
+    script
+        .as_bytes()
+        .starts_with(&[OP_RETURN.to_u8(), OP_PUSHNUM_13.to_u8()])
+}
+
+/// Heuristic (without tracking sats across transactions): the first output receives
+/// a newly revealed inscription, and any output of a runestone transaction may hold runes.
+fn asset_labels(vout: usize, inscription: bool, runestone: bool) -> Vec<&'static str> {
+    let mut labels = vec![];
+    if inscription && vout == 0 {
+        labels.push("inscription");
+    }
+    if runestone {
+        labels.push("runes");
+    }
+    labels
+}
+
+fn script_type(script: &bitcoin::Script) -> &'static str {
+    if script.is_p2pkh() {
+        "p2pkh"
+    } else if script.is_p2sh() {
+        "p2sh"
+    } else if script.is_p2wpkh() {
+        "p2wpkh"
+    } else if script.is_p2wsh() {
+        "p2wsh"
+    } else if script.is_p2tr() {
+        "p2tr"
+    } else {
+        "other"
+    }
+}
+
+/// Whether a P2TR input was spent using the key path or a script path (and its leaf), following BIP341.
+fn taproot_spend_path(witness: &bitcoin::Witness) -> String {
+    let annex = usize::from(witness.taproot_annex().is_some());
+    if witness.len() - annex <= 1 {
+        return "key".to_owned();
+    }
+    match witness.taproot_leaf_script() {
+        Some(leaf) => format!(
+            "script:{}",
+            bitcoin::taproot::TapLeafHash::from_script(leaf.script, leaf.version)
+        ),
+        None => "script:?".to_owned(),
+    }
+}
+
+/// A watched output is likely change if its transaction spends watched outputs of the same script type.
+fn is_change(script: &bitcoin::Script, spent_types: &HashSet<&str>) -> bool {
+    spent_types.contains(script_type(script))
+}
+
+/// A watched address' net amount in a transaction
+struct Transfer<'a> {
+    script: &'a bitcoin::Script,
+    txid: bitcoin::Txid,
+    height: usize,
+    offset: u64,
+    blockhash: bitcoin::BlockHash,
+    time: u32,
+    delta: bitcoin::SignedAmount,
+    coinbase: bool,
+}
+
+struct Status<'a> {
+    rows: Vec<Row>,               // in confirmation order
+    transfers: Vec<Transfer<'a>>, // in confirmation order
+    /// Each address' rows (with its own delta and running balance)
+    script_rows: HashMap<&'a bitcoin::Script, Vec<Row>>,
+    unspent: HashMap<bitcoin::OutPoint, Utxo<'a>>,
+    /// Spent outputs (with their spending height)
+    spent: Vec<(bitcoin::OutPoint, Utxo<'a>, usize)>,
+    balance: bitcoin::SignedAmount,
+    history: Vec<(&'a bitcoin::Script, Vec<cache::Entry>)>,
+    tip: Option<(usize, bitcoin::BlockHash)>,
+}
+
+// progress is reported after each chunk of addresses
+const FIND_CHUNK_SIZE: usize = 10_000;
+
+// prefetched transactions are sent to the cache writer thread in chunks
+const PREFETCH_CHUNK_SIZE: usize = 100;
+
+/// The height each watched address was created at (if known)
+type Birthdays = HashMap<bitcoin::ScriptBuf, usize>;
+
+/// The watched scripts (with their birthday heights and key derivation paths)
+#[derive(Default, Clone)]
+struct WatchList {
+    scripts: HashSet<bitcoin::ScriptBuf>,
+    birthdays: Birthdays,
+    key_paths: HashMap<bitcoin::ScriptBuf, String>,
+    /// Descriptors whose child scripts can be derived beyond `--descriptor-range`
+    ranged: Vec<RangedDescriptor>,
+    /// The scripts of each named wallet (see `--wallet`)
+    wallets: BTreeMap<String, HashSet<bitcoin::ScriptBuf>>,
+}
+
+#[derive(Clone)]
+struct RangedDescriptor {
+    entry: String,
+    desc: descriptor::Descriptor,
+    birthday: usize,
+    /// Number of child scripts derived so far
+    derived: u32,
+    /// The named wallet it belongs to
+    wallet: Option<String>,
+}
+
+impl RangedDescriptor {
+    /// Whether any of the last `gap_limit` derived child scripts has history.
+    fn is_exhausted(
+        &self,
+        index: &address::Index,
+        gap_limit: u32,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let secp = bitcoin::secp256k1::Secp256k1::verification_only();
+        for i in self.derived.saturating_sub(gap_limit)..self.derived {
+            let script = self.desc.script_pubkey(&secp, i)?;
+            if !index.find_since(&script, self.birthday)?.is_empty() {
+                return Ok(true);
             }
         }
-        balance += delta;
-        rows.push(Row {
-            txid: txid.to_string(),
-            time: format!(
-                "{}",
-                Utc.timestamp_opt(loc.indexed_header.header().time.into(), 0)
-                    .unwrap()
-            ),
-            height: loc.height.to_string(),
-            offset: loc.offset.to_string(),
-            delta: format!("{:+.8}", delta.to_btc()),
-            balance: format!("{:.8}", balance.to_btc()),
-            ms: format!("{:.3}", dt.as_micros() as f64 / 1e3),
-            bytes: tx_bytes.len().to_string(),
-        });
+        Ok(false)
     }
+}
 
-    let dt = t.elapsed();
-    info!(
-        "fetched {} txs, {:.3} MB, balance: {}, UTXOs: {} ({:?})",
-        locations.len(),
-        total_bytes as f64 / 1e6,
-        balance,
-        unspent.len(),
-        dt,
-    );
+impl WatchList {
+    fn new(scripts: HashSet<bitcoin::ScriptBuf>) -> Self {
+        Self {
+            scripts,
+            ..Default::default()
+        }
+    }
 
-    if history_limit > 0 {
-        let is_truncated = rows.len() > history_limit;
-        rows.reverse();
-        rows.truncate(history_limit);
-        if is_truncated {
-            rows.push(Row::dots());
+    /// The watch list of a single named wallet.
+    fn wallet(&self, scripts: &HashSet<bitcoin::ScriptBuf>) -> Self {
+        fn select<T: Clone>(
+            map: &HashMap<bitcoin::ScriptBuf, T>,
+            scripts: &HashSet<bitcoin::ScriptBuf>,
+        ) -> HashMap<bitcoin::ScriptBuf, T> {
+            map.iter()
+                .filter(|(script, _)| scripts.contains(*script))
+                .map(|(script, value)| (script.clone(), value.clone()))
+                .collect()
+        }
+        Self {
+            scripts: scripts.clone(),
+            birthdays: select(&self.birthdays, scripts),
+            key_paths: select(&self.key_paths, scripts),
+            ..Default::default()
         }
+    }
 
-        let mut tbl = tabled::Table::new(rows);
-        tbl.with(tabled::settings::Style::rounded());
-        tbl.modify(
-            tabled::settings::object::Rows::new(1..),
-            tabled::settings::Alignment::right(),
+    fn needs_more_scripts(
+        &self,
+        index: &address::Index,
+        gap_limit: u32,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        for ranged in &self.ranged {
+            if ranged.is_exhausted(index, gap_limit)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Derive more child scripts of the ranged descriptors, until the last `gap_limit` ones
+    /// have no history. Returns the number of added scripts.
+    fn extend_to_gap_limit(
+        &mut self,
+        index: &address::Index,
+        gap_limit: u32,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let secp = bitcoin::secp256k1::Secp256k1::verification_only();
+        let mut added = 0;
+        for ranged in &mut self.ranged {
+            while ranged.is_exhausted(index, gap_limit)? {
+                let end = ranged.derived.saturating_add(gap_limit);
+                for i in ranged.derived..end {
+                    let label = format!("{}/{}", ranged.entry, i);
+                    let derived = derive_script(&ranged.desc, &secp, i, label)?;
+                    if !self.scripts.insert(derived.script.clone()) {
+                        continue;
+                    }
+                    if let Some(wallet) = &ranged.wallet {
+                        let scripts = self.wallets.get_mut(wallet).unwrap();
+                        scripts.insert(derived.script.clone());
+                    }
+                    if ranged.birthday > 0 {
+                        self.birthdays
+                            .insert(derived.script.clone(), ranged.birthday);
+                    }
+                    if let Some(key_path) = derived.key_path {
+                        self.key_paths.insert(derived.script, key_path);
+                    }
+                    added += 1;
+                }
+                ranged.derived = end;
+            }
+        }
+        Ok(added)
+    }
+}
+
+/// Merge two sorted and deduplicated Vecs (keeping the result deduplicated).
+fn merge_sorted<T: Ord>(a: Vec<T>, b: Vec<T>) -> Vec<T> {
+    let mut merged = Vec::with_capacity(a.len() + b.len());
+    let (mut a, mut b) = (a.into_iter().peekable(), b.into_iter().peekable());
+    while let (Some(x), Some(y)) = (a.peek(), b.peek()) {
+        match x.cmp(y) {
+            std::cmp::Ordering::Less => merged.push(a.next().unwrap()),
+            std::cmp::Ordering::Greater => merged.push(b.next().unwrap()),
+            std::cmp::Ordering::Equal => {
+                merged.push(a.next().unwrap());
+                b.next();
+            }
+        }
+    }
+    merged.extend(a);
+    merged.extend(b);
+    merged
+}
+
+/// Use the cached history (if it is still part of the indexed chain),
+/// so only the blocks after it need to be scanned.
+fn find<'a>(
+    script: &bitcoin::Script,
+    birthday: usize,
+    index: &'a address::Index,
+    cache: Option<&Cache>,
+) -> Result<Vec<Location<'a>>, Box<dyn std::error::Error>> {
+    if let Some(cache) = cache {
+        if let Some((height, hash)) = cache.synced(script)? {
+            if index
+                .get_location(height, 0)
+                .map(|loc| loc.indexed_header.hash())
+                == Some(hash)
+            {
+                let mut locations = cache
+                    .history(script)?
+                    .into_iter()
+                    .filter(|entry| !entry.stale && entry.block_height <= height)
+                    .map(|entry| {
+                        index
+                            .get_location(entry.block_height, entry.block_offset)
+                            .expect("cached location is not indexed")
+                    })
+                    .collect::<Vec<_>>();
+                locations.extend(index.find_since(script, height + 1)?);
+                return Ok(locations);
+            }
+        }
+    }
+    Ok(index.find_since(script, birthday)?)
+}
+
+impl<'a> Status<'a> {
+    fn create(
+        watch: &'a WatchList,
+        index: &'a address::Index,
+        cache: Option<&Cache>,
+        merkle_proofs: bool,
+        time_source: TimeSource,
+        max_bytes: Option<usize>,
+        mut rows_output: RowsOutput,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let WatchList {
+            scripts,
+            birthdays,
+            key_paths,
+            ranged: _,
+            wallets: _,
+        } = watch;
+        let mut status = Status {
+            rows: vec![],
+            transfers: vec![],
+            script_rows: HashMap::new(),
+            unspent: HashMap::new(),
+            spent: vec![],
+            balance: bitcoin::SignedAmount::ZERO,
+            history: vec![],
+            tip: index.tip_height().zip(index.tip_hash()),
+        };
+        if scripts.is_empty() {
+            return Ok(status);
+        }
+        let t = std::time::Instant::now();
+        // the sorted (and deduplicated) locations to be analyzed, merged after each chunk
+        // (a sorted Vec takes much less memory than a BTreeSet for large watch sets)
+        let mut locations: Vec<Location> = vec![];
+        // each script's history is kept only for syncing it into the cache
+        let mut script_positions: Vec<(&bitcoin::Script, Vec<(usize, u64)>)> = vec![];
+        let mut merge = |chunk: Vec<(&'a bitcoin::Script, Vec<Location<'a>>)>| {
+            let mut merged = vec![];
+            for (script, script_locations) in chunk {
+                if cache.is_some() {
+                    let positions = script_locations
+                        .iter()
+                        .map(|loc| (loc.height, loc.offset))
+                        .collect();
+                    script_positions.push((script, positions));
+                }
+                merged.extend(script_locations);
+            }
+            merged.sort_unstable();
+            merged.dedup();
+            locations = merge_sorted(std::mem::take(&mut locations), merged);
+        };
+        let synced_history = match (cache, index.tip_hash()) {
+            (Some(cache), Some(tip)) => cache.synced_history(scripts, tip)?,
+            _ => None,
+        };
+        if let Some(synced_history) = synced_history {
+            // the tip is unchanged since the last run (so the cached history is up-to-date)
+            let chunk = synced_history
+                .into_iter()
+                .map(|(script, entries)| {
+                    let locations = entries
+                        .into_iter()
+                        .map(|entry| {
+                            index
+                                .get_location(entry.block_height, entry.block_offset)
+                                .expect("cached location is not indexed")
+                        })
+                        .collect();
+                    (script.as_script(), locations)
+                })
+                .collect();
+            merge(chunk);
+        } else {
+            let mut done = 0;
+            for chunk in scripts.iter().collect::<Vec<_>>().chunks(FIND_CHUNK_SIZE) {
+                done += chunk.len();
+                let chunk = chunk
+                    .iter()
+                    .map(|script| {
+                        let birthday = birthdays.get(*script).copied().unwrap_or_default();
+                        Ok((script.as_script(), find(script, birthday, index, cache)?))
+                    })
+                    .collect::<Result<_, Box<dyn std::error::Error>>>()?;
+                merge(chunk);
+                if scripts.len() > FIND_CHUNK_SIZE {
+                    info!(
+                        "scanned {}/{} addresses ({:?})",
+                        done,
+                        scripts.len(),
+                        t.elapsed()
+                    );
+                }
+            }
+        }
+        let locations_bytes = locations.capacity() * std::mem::size_of::<Location>()
+            + script_positions
+                .iter()
+                .map(|(_, positions)| positions.capacity() * std::mem::size_of::<(usize, u64)>())
+                .sum::<usize>();
+        info!(
+            "{} address history: {} txs, {:.3} MB of locations ({:?})",
+            scripts.len(),
+            locations.len(),
+            locations_bytes as f64 / 1e6,
+            t.elapsed()
         );
-        if is_truncated {
-            tbl.modify(
-                tabled::settings::object::LastRow,
-                tabled::settings::Alignment::center(),
+
+        let t = std::time::Instant::now();
+        let mut total_bytes = 0;
+        let mut txids = HashMap::new();
+        let Status {
+            rows,
+            transfers,
+            script_rows,
+            unspent,
+            spent: spent_outputs,
+            balance,
+            history,
+            tip: _,
+        } = &mut status;
+        if max_bytes.is_none() {
+            rows.reserve(locations.len());
+        }
+        let mut rows_bytes = 0;
+        let mut status_bytes = 0;
+        let mut over_cap = false;
+        let mut streamed_rows = 0;
+        let mut script_balances = HashMap::<&bitcoin::Script, bitcoin::SignedAmount>::new();
+        for loc in &locations {
+            let t = std::time::Instant::now();
+            let tx_bytes = match cache {
+                Some(cache) => index.get_cached_tx_bytes(loc, cache)?,
+                None => index.get_tx_bytes(loc)?,
+            };
+            if let Some(cache) = cache.filter(|_| merkle_proofs) {
+                if !cache.has_merkle_proof(loc)? {
+                    cache.add_merkle_proof(loc, &index.get_merkle_proof(loc)?)?;
+                }
+            }
+            total_bytes += tx_bytes.len();
+            let tx: bitcoin::Transaction = deserialize(&tx_bytes).expect("bad tx bytes");
+            let txid = tx.compute_txid();
+            if cache.is_some() {
+                txids.insert((loc.height, loc.offset), txid);
+            }
+            let dt = t.elapsed();
+            let weight = tx.weight();
+            let locktime = tx.lock_time.to_string();
+            let rbf = if tx.is_explicitly_rbf() { "yes" } else { "no" };
+            let coinbase = tx.is_coinbase();
+            let sequences = tx
+                .input
+                .iter()
+                .map(|txi| format!("{:#010x}", txi.sequence.to_consensus_u32()))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let inscription = tx.input.iter().any(|txi| has_inscription(&txi.witness));
+            let runestone = tx.output.iter().any(|txo| is_runestone(&txo.script_pubkey));
+            let mut assets = BTreeSet::new();
+            let mut delta = bitcoin::SignedAmount::ZERO;
+            let mut spent_types = HashSet::new();
+            let mut taproot = vec![];
+            let mut wsh = vec![];
+            // per-address delta and change
+            let mut script_deltas =
+                HashMap::<&bitcoin::Script, (bitcoin::SignedAmount, bitcoin::Amount)>::new();
+            let mut unwatched_inputs = tx.input.len();
+            let mut spent_value = bitcoin::Amount::ZERO;
+            let output_value: bitcoin::Amount = tx.output.iter().map(|txo| txo.value).sum();
+            for txi in tx.input {
+                if let Some(spent) = unspent.remove(&txi.previous_output) {
+                    delta -= spent.value.to_signed().expect("spent overflow");
+                    script_deltas.entry(spent.script).or_default().0 -=
+                        spent.value.to_signed().expect("spent overflow");
+                    spent_types.insert(script_type(spent.script));
+                    if spent.script.is_p2tr() {
+                        taproot.push(taproot_spend_path(&txi.witness));
+                    }
+                    if spent.script.is_p2wsh() {
+                        let path = descriptor::spend_path(&txi, tx.lock_time);
+                        wsh.push(path.map_or_else(|| "?".to_owned(), |path| path.to_string()));
+                    }
+                    spent_value += spent.value;
+                    unwatched_inputs -= 1;
+                    spent_outputs.push((txi.previous_output, spent, loc.height));
+                }
+            }
+            let mut change = bitcoin::Amount::ZERO;
+            let (mut recipients, mut sent) = (0, bitcoin::Amount::ZERO);
+            for (n, txo) in tx.output.into_iter().enumerate() {
+                if let Some(script) = scripts.get(&txo.script_pubkey) {
+                    delta += txo.value.to_signed().expect("txo.value overflow");
+                    let is_change = is_change(script, &spent_types);
+                    let script_delta = script_deltas.entry(script.as_script()).or_default();
+                    script_delta.0 += txo.value.to_signed().expect("txo.value overflow");
+                    if is_change {
+                        change += txo.value;
+                        script_delta.1 += txo.value;
+                    }
+                    let labels = asset_labels(n, inscription, runestone);
+                    assets.extend(labels.iter().copied());
+                    unspent.insert(
+                        bitcoin::OutPoint::new(txid, n.try_into().unwrap()),
+                        Utxo {
+                            value: txo.value,
+                            script,
+                            height: loc.height,
+                            change: is_change,
+                            assets: labels,
+                            key_path: key_paths.get(script).map(String::as_str),
+                        },
+                    );
+                } else {
+                    recipients += 1;
+                    sent += txo.value;
+                }
+            }
+            let payout =
+                if spent_value > bitcoin::Amount::ZERO && recipients >= BATCH_MIN_RECIPIENTS {
+                    // the fee is known only if all the inputs are watched
+                    let fee = (unwatched_inputs == 0).then(|| spent_value - output_value);
+                    format!(
+                        "{} recipients, {:.8} out, fee {}",
+                        recipients,
+                        sent.to_btc(),
+                        fee.map_or_else(|| "?".to_owned(), |fee| format!("{:.8}", fee.to_btc()))
+                    )
+                } else {
+                    String::new()
+                };
+            // the fee is known only if all the inputs are watched (otherwise see `resolve_fees`)
+            let fee = if coinbase {
+                String::new()
+            } else if unwatched_inputs == 0 {
+                format!("{:.8}", (spent_value - output_value).to_btc())
+            } else {
+                "?".to_owned()
+            };
+            *balance += delta;
+            let time = time_source.block_time(index, loc);
+            let row = Row {
+                txid: txid.to_string(),
+                time: format!("{}", Utc.timestamp_opt(time.into(), 0).unwrap()),
+                height: loc.height.to_string(),
+                confirmations: loc
+                    .confirmations(index.chain())
+                    .map_or_else(String::new, |n| n.to_string()),
+                offset: loc.offset.to_string(),
+                delta: format!("{:+.8}", delta.to_btc()),
+                change: format_change(change),
+                balance: format!("{:.8}", balance.to_btc()),
+                ms: format!("{:.3}", dt.as_micros() as f64 / 1e3),
+                bytes: tx_bytes.len().to_string(),
+                vsize: weight.to_vbytes_ceil().to_string(),
+                weight: weight.to_wu().to_string(),
+                fee,
+                locktime,
+                rbf: rbf.to_owned(),
+                sequences,
+                taproot: taproot.join(" "),
+                wsh: wsh.join(" "),
+                assets: assets.into_iter().collect::<Vec<_>>().join(","),
+                paths: script_deltas
+                    .keys()
+                    .filter_map(|script| key_paths.get(*script).map(String::as_str))
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                payout,
+            };
+            let mut script_deltas: Vec<_> = script_deltas.into_iter().collect();
+            script_deltas.sort_unstable_by_key(|(script, _)| *script);
+            for (script, (delta, change)) in script_deltas {
+                transfers.push(Transfer {
+                    script,
+                    txid,
+                    height: loc.height,
+                    offset: loc.offset,
+                    blockhash: loc.indexed_header.hash(),
+                    time,
+                    delta,
+                    coinbase,
+                });
+                let balance = script_balances.entry(script).or_default();
+                *balance += delta;
+                if streamed_rows == 0 {
+                    let script_row = Row {
+                        delta: format!("{:+.8}", delta.to_btc()),
+                        change: format_change(change),
+                        balance: format!("{:.8}", balance.to_btc()),
+                        paths: key_paths.get(script).cloned().unwrap_or_default(),
+                        ..row.clone()
+                    };
+                    rows_bytes += script_row.mem_size();
+                    script_rows.entry(script).or_default().push(script_row);
+                }
+            }
+            if streamed_rows == 0 {
+                rows_bytes += row.mem_size();
+                rows.push(row);
+            } else if let RowsOutput::Stream(writer) = &mut rows_output {
+                writer.write(row, index)?;
+                streamed_rows += 1;
+            }
+            status_bytes = locations_bytes
+                + rows_bytes
+                + txids.capacity() * std::mem::size_of::<((usize, u64), bitcoin::Txid)>()
+                + transfers.capacity() * std::mem::size_of::<Transfer>()
+                + unspent.capacity() * std::mem::size_of::<(bitcoin::OutPoint, Utxo)>()
+                + spent_outputs.capacity()
+                    * std::mem::size_of::<(bitcoin::OutPoint, Utxo, usize)>()
+                + tx_bytes.len();
+            if streamed_rows > 0 || over_cap || max_bytes.is_none_or(|max| status_bytes <= max) {
+                continue;
+            }
+            over_cap = true;
+            match &mut rows_output {
+                RowsOutput::Stream(writer) => {
+                    warn!(
+                        "status exceeds {:.3} MB, writing the history rows as they are created",
+                        status_bytes as f64 / 1e6
+                    );
+                    for row in rows.drain(..) {
+                        writer.write(row, index)?;
+                        streamed_rows += 1;
+                    }
+                    script_rows.clear();
+                    rows_bytes = 0;
+                }
+                RowsOutput::Keep => warn!(
+                    "status exceeds {:.3} MB (its history rows are kept, for printing them later)",
+                    status_bytes as f64 / 1e6
+                ),
+            }
+        }
+        for (script, positions) in script_positions {
+            let entries = positions
+                .into_iter()
+                .map(|(height, offset)| {
+                    let loc = index
+                        .get_location(height, offset)
+                        .expect("history location is not indexed");
+                    cache::Entry::new(&loc, txids[&(height, offset)])
+                })
+                .collect();
+            history.push((script, entries));
+        }
+
+        if !locations.is_empty() {
+            info!(
+                "fetched {} txs, {:.3} MB, balance: {}, UTXOs: {} ({:?})",
+                locations.len(),
+                total_bytes as f64 / 1e6,
+                balance,
+                unspent.len(),
+                t.elapsed(),
+            );
+            info!(
+                "status: {:.3} MB, {:.3} MB of history rows ({} rows written as they were created)",
+                status_bytes as f64 / 1e6,
+                rows_bytes as f64 / 1e6,
+                streamed_rows
             );
         }
-        println!("{}", tbl);
+        Ok(status)
+    }
+
+    /// The UTXOs as of the given (indexed) height.
+    fn unspent_at(&self, height: usize) -> Vec<(&bitcoin::OutPoint, &Utxo<'a>)> {
+        let spent_later = self
+            .spent
+            .iter()
+            .filter(|(_, _, spent_height)| *spent_height > height)
+            .map(|(outpoint, utxo, _)| (outpoint, utxo));
+        self.unspent
+            .iter()
+            .chain(spent_later)
+            .filter(|(_, utxo)| utxo.height <= height)
+            .collect()
+    }
+
+    /// Balance of the UTXOs confirmed at `max_height` or below (none if `max_height` is `None`).
+    fn trusted_balance(&self, max_height: Option<usize>) -> bitcoin::Amount {
+        self.unspent
+            .values()
+            .filter(|utxo| max_height.is_some_and(|h| utxo.height <= h))
+            .map(|utxo| utxo.value)
+            .sum()
+    }
+}
+
+/// Create the status of the watched scripts, using the history options given by `args`.
+fn watch_status<'a>(
+    watch: &'a WatchList,
+    index: &'a address::Index,
+    cache: Option<&Cache>,
+    args: &Args,
+) -> Result<Status<'a>, Box<dyn std::error::Error>> {
+    watch_status_with(watch, index, cache, args, RowsOutput::Keep)
+}
+
+fn watch_status_with<'a>(
+    watch: &'a WatchList,
+    index: &'a address::Index,
+    cache: Option<&Cache>,
+    args: &Args,
+    rows_output: RowsOutput,
+) -> Result<Status<'a>, Box<dyn std::error::Error>> {
+    Status::create(
+        watch,
+        index,
+        cache,
+        args.merkle_proofs,
+        args.time_source,
+        args.status_max_mb.map(|mb| mb << 20),
+        rows_output,
+    )
+}
+
+fn sync_sqlite(status: &Status, cache: &mut Cache) -> Result<cache::SyncStats, cache::Error> {
+    match status.tip {
+        Some(tip) => sync_history(&status.history, tip, cache),
+        None => Ok(cache::SyncStats::default()),
+    }
+}
+
+/// A snapshot of the watched scripts' history (and the tip it was synced to),
+/// with the cache writes deferred while it was created and reported
+type HistoryUpdate = (
+    Vec<(bitcoin::ScriptBuf, Vec<cache::Entry>)>,
+    (usize, bitcoin::BlockHash),
+    cache::Writes,
+);
+
+/// Sent to the cache writer thread
+enum CacheUpdate {
+    History(HistoryUpdate),
+    /// The prefetched transactions (see `prefetch_txs`)
+    Writes(cache::Writes),
+}
+
+/// Write the history snapshots into the cache, until the sending side is dropped.
+/// Only the latest snapshot is written if several are pending (e.g. due to a slow disk),
+/// but all their deferred writes are applied (before syncing, so they are not pruned).
+fn sync_sqlite_thread(
+    updates: std::sync::mpsc::Receiver<CacheUpdate>,
+    mut cache: Cache,
+) -> Result<(), cache::Error> {
+    while let Ok(update) = updates.recv() {
+        let mut latest = None;
+        let mut writes = cache::Writes::default();
+        for update in std::iter::once(update).chain(std::iter::from_fn(|| updates.try_recv().ok()))
+        {
+            match update {
+                CacheUpdate::History((history, tip, newer_writes)) => {
+                    latest = Some((history, tip));
+                    writes.extend(newer_writes);
+                }
+                CacheUpdate::Writes(newer_writes) => writes.extend(newer_writes),
+            }
+        }
+        cache.apply(writes)?;
+        if let Some((history, tip)) = latest {
+            sync_history(&history, tip, &mut cache)?;
+        }
     }
     Ok(())
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
-enum Network {
-    Bitcoin,
-    Testnet,
-    Testnet4,
-    Regtest,
-    Signet,
+fn sync_history<S: AsRef<bitcoin::Script>>(
+    history: &[(S, Vec<cache::Entry>)],
+    tip: (usize, bitcoin::BlockHash),
+    cache: &mut Cache,
+) -> Result<cache::SyncStats, cache::Error> {
+    let t = std::time::Instant::now();
+    let stats = cache.sync(history, tip)?;
+    if stats.inserted > 0
+        || stats.pruned > 0
+        || stats.inserted_txs > 0
+        || stats.pruned_txs > 0
+        || stats.evicted_txs > 0
+    {
+        info!(
+            "cache: {} history rows inserted, {} marked stale, {} txs inserted, {} pruned, {} evicted ({:?})",
+            stats.inserted,
+            stats.pruned,
+            stats.inserted_txs,
+            stats.pruned_txs,
+            stats.evicted_txs,
+            t.elapsed()
+        );
+    }
+    Ok(stats)
 }
 
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-/// Bitcoin address indexer
-struct Args {
-    #[arg(value_enum, short = 'n', long = "network", default_value_t = Network::Bitcoin)]
-    network: Network,
+fn format_change(change: bitcoin::Amount) -> String {
+    if change > bitcoin::Amount::ZERO {
+        format!("{:.8}", change.to_btc())
+    } else {
+        String::new()
+    }
+}
 
-    #[arg(short = 'l', long = "limit", default_value_t = 100)]
-    history_limit: usize,
+/// The columns hidden by the command-line options
+fn hidden_columns(args: &Args) -> Vec<&'static str> {
+    let mut hidden = vec![];
+    if !args.fees {
+        hidden.push(FEE_COLUMN);
+    }
+    if !args.tx_details {
+        hidden.extend_from_slice(TX_DETAILS_COLUMNS);
+    }
+    if !args.ordinals {
+        hidden.push(ASSETS_COLUMN);
+    }
+    hidden
+}
 
-    #[arg(short = 'a', long = "address-file")]
-    address_file: Option<PathBuf>,
+/// What `Status::create` does with the history rows
+enum RowsOutput {
+    /// Keep them (for printing them after the status is created)
+    Keep,
+    /// Keep them while the status fits `--status-max-mb`, and then write them (and the following
+    /// ones) as they are created
+    Stream(RowWriter),
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
-    env_logger::builder().format_timestamp_micros().init();
-    let default_rpc_port = match args.network {
-        Network::Bitcoin => 8332,
-        Network::Testnet => 18332,
-        Network::Testnet4 => 48332,
-        Network::Regtest => 18443,
-        Network::Signet => 38332,
-    };
-    let default_db_dir = match args.network {
-        Network::Bitcoin => "bitcoin",
-        Network::Testnet => "testnet",
-        Network::Testnet4 => "testnet4",
-        Network::Regtest => "regtest",
-        Network::Signet => "signet",
-    };
-    let url = format!("http://localhost:{}", default_rpc_port);
-    let db_path = format!("db/{default_db_dir}");
-    info!("index DB: {}, node URL: {}", db_path, url);
-
-    let addresses = args.address_file.as_ref().map_or_else(
-        || Ok(String::new()),
-        |path| {
-            if path == Path::new("-") {
-                let mut buf = String::new();
-                std::io::stdin().read_to_string(&mut buf)?;
-                return Ok(buf);
-            }
-            std::fs::read_to_string(path)
-        },
-    )?;
-    let scripts: HashSet<_> = addresses
-        .split_ascii_whitespace()
-        .map(|addr| {
-            bitcoin::Address::from_str(addr)
-                .unwrap()
-                .assume_checked()
-                .script_pubkey()
+/// Writes the history rows to stdout in confirmation order (as JSON lines with `--format json`,
+/// and as tab-separated fields unless `--format csv` is used).
+struct RowWriter {
+    format: HistoryFormat,
+    fees: bool,
+    shown: Vec<bool>,
+    started: bool,
+}
+
+impl RowWriter {
+    fn new(args: &Args) -> Self {
+        use tabled::Tabled;
+        let hidden = hidden_columns(args);
+        let shown = Row::headers()
+            .iter()
+            .map(|name| !hidden.contains(&name.as_ref()))
+            .collect();
+        Self {
+            format: args.format,
+            fees: args.fees,
+            shown,
+            started: false,
+        }
+    }
+
+    fn select(&self, fields: Vec<std::borrow::Cow<str>>) -> Vec<String> {
+        fields
+            .into_iter()
+            .zip(&self.shown)
+            .filter_map(|(field, &shown)| shown.then(|| field.into_owned()))
+            .collect()
+    }
+
+    fn write(&mut self, mut row: Row, index: &address::Index) -> std::io::Result<()> {
+        if self.fees {
+            resolve_fees(std::slice::from_mut(&mut row), index);
+        }
+        let mut stdout = std::io::stdout().lock();
+        for line in self.lines(&row) {
+            writeln!(stdout, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    /// The row's line (preceded by the columns' header, for the first CSV or tab-separated row)
+    fn lines(&mut self, row: &Row) -> Vec<String> {
+        use tabled::Tabled;
+        let columns = self.select(Row::headers());
+        let fields = self.select(row.fields());
+        let header = !std::mem::replace(&mut self.started, true);
+        let (header, line) = match self.format {
+            HistoryFormat::Json => {
+                let object: serde_json::Map<_, _> = columns
+                    .into_iter()
+                    .zip(fields)
+                    .map(|(name, field)| (name, field.into()))
+                    .collect();
+                return vec![serde_json::Value::Object(object).to_string()];
+            }
+            HistoryFormat::Csv => {
+                let join = |fields: Vec<String>| {
+                    let fields: Vec<_> = fields.iter().map(|field| csv_field(field)).collect();
+                    fields.join(",")
+                };
+                (header.then(|| join(columns)), join(fields))
+            }
+            HistoryFormat::Table => (header.then(|| columns.join("\t")), fields.join("\t")),
+        };
+        header.into_iter().chain([line]).collect()
+    }
+}
+
+/// Print the merged history, or a separate history for each address (with `--group-by-address`).
+fn print_status(status: Status, index: &address::Index, args: &Args) {
+    if !args.group_by_address {
+        return print_history(status.rows, index, args);
+    }
+    let network: bitcoin::Network = args.network.into();
+    let mut sections: Vec<_> = status
+        .script_rows
+        .into_iter()
+        .map(|(script, rows)| {
+            let name = bitcoin::Address::from_script(script, network)
+                .map_or_else(|_| script.to_hex_string(), |addr| addr.to_string());
+            (name, rows)
         })
         .collect();
-    if let Some(path) = args.address_file {
-        info!("watching {} addresses from {:?}", scripts.len(), path);
+    sections.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+    for (name, rows) in sections {
+        println!("{}:", name);
+        print_history(rows, index, args);
     }
+}
 
-    let mut index = address::Index::open(db_path, url)?;
-    let mut updated = true;
-    loop {
-        while index.sync(1000)?.indexed_blocks > 0 {
-            updated = true;
+fn print_history(mut rows: Vec<Row>, index: &address::Index, args: &Args) {
+    let history_limit = args.history_limit;
+    if history_limit == 0 || rows.is_empty() {
+        return;
+    }
+    let is_truncated = rows.len() > history_limit;
+    rows.reverse();
+    rows.truncate(history_limit);
+
+    if args.fees {
+        resolve_fees(&mut rows, index);
+    }
+    let mut hidden = hidden_columns(args);
+    // only descriptor-derived scripts have key derivation paths
+    if rows.iter().all(|row| row.paths.is_empty()) {
+        hidden.push(PATHS_COLUMN);
+    }
+    if rows.iter().all(|row| row.payout.is_empty()) {
+        hidden.push(PAYOUT_COLUMN);
+    }
+
+    if let HistoryFormat::Json | HistoryFormat::Csv = args.format {
+        use tabled::Tabled;
+        let is_shown = |name: &str| !hidden.contains(&name);
+        let columns: Vec<String> = Row::headers()
+            .into_iter()
+            .filter(|name| is_shown(name))
+            .map(|name| name.into_owned())
+            .collect();
+        let select = |row: &Row| -> Vec<String> {
+            Row::headers()
+                .into_iter()
+                .zip(row.fields())
+                .filter(|(name, _)| is_shown(name))
+                .map(|(_, field)| field.into_owned())
+                .collect()
+        };
+        if args.format == HistoryFormat::Json {
+            let rows: Vec<serde_json::Map<_, _>> = rows
+                .iter()
+                .map(|row| {
+                    let fields = columns.iter().cloned().zip(select(row));
+                    fields.map(|(name, field)| (name, field.into())).collect()
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&rows).unwrap());
+        } else {
+            let join = |fields: &[String]| {
+                let fields: Vec<_> = fields.iter().map(|field| csv_field(field)).collect();
+                fields.join(",")
+            };
+            println!("{}", join(&columns));
+            for row in &rows {
+                println!("{}", join(&select(row)));
+            }
         }
-        if updated {
-            compute_balance(&scripts, &index, args.history_limit)?;
-            updated = false;
+        return;
+    }
+
+    if args.plain {
+        use tabled::Tabled;
+        let shown: Vec<bool> = Row::headers()
+            .iter()
+            .map(|name| !hidden.contains(&name.as_ref()))
+            .collect();
+        let select = |fields: Vec<std::borrow::Cow<str>>| {
+            fields
+                .into_iter()
+                .zip(&shown)
+                .filter_map(|(field, &shown)| shown.then_some(field))
+                .collect::<Vec<_>>()
+                .join("\t")
+        };
+        println!("{}", select(Row::headers()));
+        for row in &rows {
+            println!("{}", select(row.fields()));
+        }
+        return;
+    }
+
+    if is_truncated {
+        rows.push(Row::dots());
+    }
+
+    let mut tbl = tabled::Table::new(rows);
+    for &name in &hidden {
+        tbl.with(tabled::settings::Remove::column(
+            tabled::settings::location::ByColumnName::new(name),
+        ));
+    }
+    tbl.with(tabled::settings::Style::rounded());
+    tbl.modify(
+        tabled::settings::object::Rows::new(1..),
+        tabled::settings::Alignment::right(),
+    );
+    if is_truncated {
+        tbl.modify(
+            tabled::settings::object::LastRow,
+            tabled::settings::Alignment::center(),
+        );
+    }
+    println!("{}", tbl);
+}
+
+/// Fetch the fees of the (printed) transactions having unwatched inputs.
+fn resolve_fees(rows: &mut [Row], index: &address::Index) {
+    for row in rows.iter_mut().filter(|row| row.fee == "?") {
+        match fetch_fee(row, index) {
+            Ok(Some(fee)) => row.fee = format!("{:.8}", fee.to_btc()),
+            Ok(None) => row.fee.clear(),
+            Err(e) => warn!("failed to get the fee of {}: {}", row.txid, e),
+        }
+    }
+}
+
+fn fetch_fee(
+    row: &Row,
+    index: &address::Index,
+) -> Result<Option<bitcoin::Amount>, Box<dyn std::error::Error>> {
+    let loc = index
+        .get_location(row.height.parse()?, row.offset.parse()?)
+        .ok_or("transaction is not indexed")?;
+    let tx_bytes = index.get_tx_bytes_by_txid(&loc, row.txid.parse()?)?;
+    let tx: bitcoin::Transaction = deserialize(&tx_bytes)?;
+    Ok(index.get_tx_fee(&loc, &tx)?)
+}
+
+#[derive(Copy, Clone, ValueEnum, Debug, PartialEq, Eq)]
+enum HistoryFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+/// Quote a CSV field if needed (RFC 4180).
+fn csv_field(field: &str) -> std::borrow::Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
+
+fn print_tip(index: &address::Index, plain: bool) -> Result<(), address::Error> {
+    let mut rows = vec![];
+    if let Some(height) = index.tip_height() {
+        rows.push(TipRow::new(
+            "index",
+            height,
+            index.get_header(height).unwrap(),
+        ));
+    }
+    if let Some((height, header)) = index.node_tip()? {
+        rows.push(TipRow::new("node", height, &header));
+    }
+    if plain {
+        use tabled::Tabled;
+        println!("{}", TipRow::headers().join("\t"));
+        for row in &rows {
+            println!("{}", row.fields().join("\t"));
+        }
+        return Ok(());
+    }
+    let mut tbl = tabled::Table::new(rows);
+    tbl.with(tabled::settings::Style::rounded());
+    println!("{}", tbl);
+    Ok(())
+}
+
+#[derive(Copy, Clone, ValueEnum, Debug)]
+enum ExportFormat {
+    Json,
+    Csv,
+}
+
+fn export_utxos(
+    status: &Status,
+    index: &address::Index,
+    network: bitcoin::Network,
+    format: ExportFormat,
+    min_conf: usize,
+    height: Option<usize>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tip_height = index.tip_height().unwrap_or_default();
+    let mut utxos: Vec<_> = match height {
+        Some(height) if height > tip_height => {
+            return Err(format!("height {} is above the indexed tip {}", height, tip_height).into())
+        }
+        // confirmations are counted as of `height`
+        Some(height) => status.unspent_at(height),
+        None => status.unspent.iter().collect(),
+    };
+    let tip_height = height.unwrap_or(tip_height);
+    utxos.sort_unstable_by_key(|(outpoint, utxo)| (utxo.height, **outpoint));
+    let utxos = utxos
+        .into_iter()
+        .map(|(outpoint, utxo)| {
+            Ok((
+                outpoint,
+                utxo,
+                bitcoin::Address::from_script(utxo.script, network)?,
+                tip_height + 1 - utxo.height,
+            ))
+        })
+        .collect::<Result<Vec<_>, bitcoin::address::FromScriptError>>()?;
+    match format {
+        ExportFormat::Json => {
+            let utxos: Vec<_> = utxos
+                .iter()
+                .map(|(outpoint, utxo, address, confirmations)| {
+                    serde_json::json!({
+                        "txid": outpoint.txid,
+                        "vout": outpoint.vout,
+                        "value": utxo.value.to_sat(),
+                        "address": address,
+                        "height": utxo.height,
+                        "confirmations": confirmations,
+                        "trusted": *confirmations >= min_conf,
+                        "change": utxo.change,
+                        "assets": utxo.assets,
+                        "key_path": utxo.key_path,
+                    })
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&utxos)?);
+        }
+        ExportFormat::Csv => {
+            println!("txid,vout,value,address,height,confirmations,trusted,change,assets,key_path");
+            for (outpoint, utxo, address, confirmations) in utxos {
+                println!(
+                    "{},{},{},{},{},{},{},{},{},{}",
+                    outpoint.txid,
+                    outpoint.vout,
+                    utxo.value.to_sat(),
+                    address,
+                    utxo.height,
+                    confirmations,
+                    confirmations >= min_conf,
+                    utxo.change,
+                    utxo.assets.join(" "),
+                    utxo.key_path.unwrap_or_default()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Print the watched addresses' transfers, formatted like Core's `listtransactions` RPC
+/// (with a single "send" or "receive" entry per address and transaction, using its net amount).
+/// Print an entry per output (like Core's `listtransactions`): the payments of the spending
+/// transactions, and the received (or mined) outputs of the others. Since there is no address
+/// book, the watched outputs of a spending transaction are considered its change.
+fn list_transactions(
+    status: &Status,
+    index: &address::Index,
+    scripts: &HashSet<bitcoin::ScriptBuf>,
+    network: bitcoin::Network,
+    count: Option<usize>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tip_height = index.tip_height().unwrap_or_default();
+    let maturity = bitcoin::blockdata::constants::COINBASE_MATURITY as usize;
+    let spent: HashSet<bitcoin::OutPoint> = status
+        .spent
+        .iter()
+        .map(|(outpoint, _utxo, _height)| *outpoint)
+        .collect();
+    let mut txids = HashSet::new();
+    let mut entries = vec![];
+    // a transaction has a transfer per watched address
+    for transfer in status.transfers.iter().filter(|t| txids.insert(t.txid)) {
+        let loc = index
+            .get_location(transfer.height, transfer.offset)
+            .ok_or("transaction is not indexed")?;
+        let tx: bitcoin::Transaction =
+            deserialize(&index.get_tx_bytes_by_txid(&loc, transfer.txid)?)?;
+        let confirmations = tip_height + 1 - transfer.height;
+        let debit = tx
+            .input
+            .iter()
+            .any(|txin| spent.contains(&txin.previous_output));
+        let fee = match debit {
+            true => index.get_tx_fee(&loc, &tx)?,
+            false => None,
+        };
+        for (vout, txo) in tx.output.iter().enumerate() {
+            let category = match (debit, scripts.contains(&txo.script_pubkey)) {
+                (true, false) => "send",
+                (false, true) if !tx.is_coinbase() => "receive",
+                (false, true) if confirmations < maturity => "immature",
+                (false, true) => "generate",
+                (true, true) | (false, false) => continue, // change, or not watched
+            };
+            let amount = txo.value.to_signed()?;
+            let mut entry = serde_json::json!({
+                "involvesWatchonly": true,
+                "category": category,
+                "amount": if debit { -amount } else { amount }.to_btc(),
+                "vout": vout,
+                "confirmations": confirmations,
+                "blockhash": transfer.blockhash,
+                "blockheight": transfer.height,
+                "blocktime": transfer.time,
+                "txid": transfer.txid,
+                "walletconflicts": [],
+                "time": transfer.time,
+                "timereceived": transfer.time,
+                "bip125-replaceable": "no",
+            });
+            if let Ok(address) = bitcoin::Address::from_script(&txo.script_pubkey, network) {
+                entry["address"] = serde_json::json!(address);
+            }
+            if tx.is_coinbase() {
+                entry["generated"] = serde_json::json!(true);
+            }
+            if debit {
+                if let Some(fee) = fee {
+                    entry["fee"] = serde_json::json!(-fee.to_signed()?.to_btc());
+                }
+                entry["abandoned"] = serde_json::json!(false);
+            }
+            entries.push(entry);
+        }
+    }
+    let skip = count.map_or(0, |count| entries.len().saturating_sub(count));
+    println!("{}", serde_json::to_string_pretty(&entries[skip..])?);
+    Ok(())
+}
+
+/// Which timestamp to show (and export) for a block
+#[derive(Copy, Clone, ValueEnum, Debug)]
+enum TimeSource {
+    /// The block header's timestamp (may be skewed by up to 2 hours)
+    Header,
+    /// Median-time-past of the last 11 blocks (monotonic)
+    Mtp,
+}
+
+impl TimeSource {
+    fn block_time(self, index: &address::Index, loc: &Location) -> u32 {
+        match self {
+            TimeSource::Header => loc.indexed_header.header().time,
+            TimeSource::Mtp => index
+                .median_time_past(loc.height)
+                .expect("location is not indexed"),
         }
-        thread::sleep(std::time::Duration::from_secs(1));
+    }
+}
+
+#[derive(Copy, Clone, ValueEnum, Debug)]
+enum RewardPeriod {
+    Day,
+    Month,
+    Year,
+}
+
+impl RewardPeriod {
+    fn label(self, time: u32) -> String {
+        use chrono::Datelike;
+        let date = Utc.timestamp_opt(time.into(), 0).unwrap().date_naive();
+        match self {
+            RewardPeriod::Day => date.to_string(),
+            RewardPeriod::Month => format!("{:04}-{:02}", date.year(), date.month()),
+            RewardPeriod::Year => date.year().to_string(),
+        }
+    }
+}
+
+/// A coinbase transaction paying to the watched addresses
+#[derive(tabled::Tabled)]
+struct RewardRow {
+    height: usize,
+    time: String,
+    reward: bitcoin::Amount,
+    subsidy: String,
+    fees: String,
+    maturity: String,
+}
+
+#[derive(tabled::Tabled)]
+struct RewardPeriodRow {
+    period: String,
+    blocks: usize,
+    reward: bitcoin::Amount,
+    subsidy: bitcoin::Amount,
+    fees: String,
+}
+
+fn block_subsidy(height: usize, network: bitcoin::Network) -> bitcoin::Amount {
+    let halving_interval = match network {
+        bitcoin::Network::Regtest => 150,
+        _ => 210_000,
+    };
+    let halvings = height / halving_interval;
+    if halvings >= 64 {
+        return bitcoin::Amount::ZERO;
+    }
+    bitcoin::Amount::from_sat(bitcoin::Amount::from_int_btc(50).to_sat() >> halvings)
+}
+
+#[derive(tabled::Tabled)]
+struct WalletRow {
+    wallet: String,
+    txs: usize,
+    utxos: usize,
+    balance: String,
+}
+
+impl WalletRow {
+    fn new(wallet: &str, status: &Status) -> Self {
+        Self {
+            wallet: wallet.to_owned(),
+            txs: status.rows.len(),
+            utxos: status.unspent.len(),
+            balance: format!("{:.8}", status.balance.to_btc()),
+        }
+    }
+}
+
+/// Print each named wallet's history, followed by a summary of their balances (and the aggregate one).
+fn print_wallets(
+    watch: &WatchList,
+    index: &address::Index,
+    cache: Option<&Cache>,
+    args: &Args,
+    total: WalletRow,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut rows = vec![];
+    for (name, scripts) in &watch.wallets {
+        let wallet = watch.wallet(scripts);
+        let status = watch_status(&wallet, index, cache, args)?;
+        rows.push(WalletRow::new(name, &status));
+        println!("{}:", name);
+        print_status(status, index, args);
+    }
+    rows.push(total);
+    print_table(rows, args.plain);
+    Ok(())
+}
+
+fn print_table<T: tabled::Tabled>(rows: Vec<T>, plain: bool) {
+    if plain {
+        println!("{}", T::headers().join("\t"));
+        for row in &rows {
+            println!("{}", row.fields().join("\t"));
+        }
+        return;
+    }
+    let mut tbl = tabled::Table::new(rows);
+    tbl.with(tabled::settings::Style::rounded());
+    println!("{}", tbl);
+}
+
+/// Print the watched addresses' coinbase rewards (split into subsidy and fees, using the blocks' undo data),
+/// and their totals per period.
+fn print_rewards(
+    status: &Status,
+    index: &address::Index,
+    network: bitcoin::Network,
+    period: RewardPeriod,
+    plain: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // the watched outputs of each coinbase transaction
+    let mut rewards = BTreeMap::<usize, (u32, bitcoin::Amount)>::new();
+    for transfer in status.transfers.iter().filter(|t| t.coinbase) {
+        let reward = &mut rewards.entry(transfer.height).or_default();
+        reward.0 = transfer.time;
+        reward.1 += transfer.delta.to_unsigned()?;
+    }
+    let tip_height = index.tip_height().unwrap_or_default();
+    let maturity = bitcoin::blockdata::constants::COINBASE_MATURITY as usize;
+    let mut rows = vec![];
+    // (blocks, reward, subsidy, fees) per period (`None` if some of the blocks have no undo data)
+    let mut periods = BTreeMap::<String, (usize, bitcoin::Amount, bitcoin::Amount, _)>::new();
+    for (&height, &(time, reward)) in &rewards {
+        let subsidy = block_subsidy(height, network);
+        let fees = index.get_block_summary(height)?.and_then(|s| s.fees);
+        // the rewards are split proportionally to the block's subsidy and fees
+        let subsidy_share = match fees {
+            Some(fees) if fees > bitcoin::Amount::ZERO => {
+                let total = u128::from((subsidy + fees).to_sat());
+                let share = u128::from(reward.to_sat()) * u128::from(subsidy.to_sat()) / total;
+                bitcoin::Amount::from_sat(share.try_into()?)
+            }
+            Some(_) => reward,
+            None => reward.min(subsidy),
+        };
+        let fee_share = fees.map(|_| reward - subsidy_share);
+        let confirmations = tip_height + 1 - height;
+        rows.push(RewardRow {
+            height,
+            time: format!("{}", Utc.timestamp_opt(time.into(), 0).unwrap()),
+            reward,
+            subsidy: subsidy_share.to_string(),
+            fees: fee_share.map_or_else(|| "unknown".to_owned(), |fees| fees.to_string()),
+            maturity: match maturity.checked_sub(confirmations) {
+                None | Some(0) => "mature".to_owned(),
+                Some(left) => format!("immature ({} blocks left)", left),
+            },
+        });
+        let total = periods.entry(period.label(time)).or_insert((
+            0,
+            bitcoin::Amount::ZERO,
+            bitcoin::Amount::ZERO,
+            Some(bitcoin::Amount::ZERO),
+        ));
+        total.0 += 1;
+        total.1 += reward;
+        total.2 += subsidy_share;
+        total.3 = total.3.zip(fee_share).map(|(total, fees)| total + fees);
+    }
+    let periods = periods
+        .into_iter()
+        .map(
+            |(period, (blocks, reward, subsidy, fees))| RewardPeriodRow {
+                period,
+                blocks,
+                reward,
+                subsidy,
+                fees: fees.map_or_else(|| "unknown".to_owned(), |fees| fees.to_string()),
+            },
+        )
+        .collect();
+    print_table(rows, plain);
+    print_table(periods, plain);
+    Ok(())
+}
+
+#[derive(Copy, Clone, ValueEnum, Debug)]
+enum LedgerFormat {
+    Beancount,
+    Ledger,
+}
+
+/// Posting accounts for `export_ledger`
+struct LedgerAccounts<'a> {
+    wallet: &'a str,
+    income: &'a str,
+    expenses: &'a str,
+}
+
+/// Print the watched addresses' history as plaintext-accounting transactions
+/// (posting each transaction's net amount against the income or expenses account).
+fn export_ledger(status: &Status, format: LedgerFormat, accounts: &LedgerAccounts) {
+    let mut txs: Vec<(&Transfer, bitcoin::SignedAmount)> = vec![];
+    for transfer in &status.transfers {
+        match txs.last_mut() {
+            Some((last, delta)) if last.txid == transfer.txid => *delta += transfer.delta,
+            _ => txs.push((transfer, transfer.delta)),
+        }
+    }
+    let date = |time: u32| Utc.timestamp_opt(time.into(), 0).unwrap().date_naive();
+    if let (LedgerFormat::Beancount, Some((first, _))) = (format, txs.first()) {
+        let opened = date(first.time);
+        println!("{} commodity BTC", opened);
+        for account in [accounts.wallet, accounts.income, accounts.expenses] {
+            println!("{} open {} BTC", opened, account);
+        }
+        println!();
+    }
+    for (transfer, delta) in txs {
+        let counter = if delta.is_negative() {
+            accounts.expenses
+        } else {
+            accounts.income
+        };
+        match format {
+            LedgerFormat::Beancount => {
+                println!("{} * \"{}\"", date(transfer.time), transfer.txid);
+                println!("  height: {}", transfer.height);
+            }
+            LedgerFormat::Ledger => {
+                // ledger-cli and hledger also accept ISO dates
+                println!("{} * {}", date(transfer.time), transfer.txid);
+                println!("    ; height: {}", transfer.height);
+            }
+        }
+        println!("  {}  {:.8} BTC", accounts.wallet, delta.to_btc());
+        println!("  {}", counter);
+        println!();
+    }
+}
+
+fn bench(
+    scripts: &HashSet<bitcoin::ScriptBuf>,
+    index: &address::Index,
+    from: Option<usize>,
+    to: Option<usize>,
+) -> Result<(), address::Error> {
+    let to = to.or(index.tip_height()).unwrap_or_default();
+    let from = from.unwrap_or(to.saturating_sub(9));
+    let stats = index.bench(from..=to)?;
+    let total = stats.fetch + stats.index;
+    println!(
+        "indexing {}..={}: {} blocks, {:.3} MB, {} rows",
+        from,
+        to,
+        stats.blocks,
+        stats.bytes as f64 / 1e6,
+        stats.rows
+    );
+    println!(
+        "  fetch: {:.3}s, index: {:.3}s => {:.3} blocks/s, {:.3} MB/s ({:.3} MB/s excluding fetch)",
+        stats.fetch.as_secs_f64(),
+        stats.index.as_secs_f64(),
+        stats.blocks as f64 / total.as_secs_f64(),
+        stats.bytes as f64 / (1e6 * total.as_secs_f64()),
+        stats.bytes as f64 / (1e6 * stats.index.as_secs_f64()),
+    );
+
+    if scripts.is_empty() {
+        return Ok(());
+    }
+    let mut latencies = Vec::with_capacity(scripts.len());
+    let mut txs = 0;
+    for script in scripts {
+        let t = std::time::Instant::now();
+        txs += index.find(script)?.len();
+        latencies.push(t.elapsed());
+    }
+    latencies.sort_unstable();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100].as_secs_f64() * 1e3;
+    println!(
+        "querying {} addresses ({} txs): p50={:.3}ms p90={:.3}ms p99={:.3}ms max={:.3}ms",
+        scripts.len(),
+        txs,
+        percentile(50),
+        percentile(90),
+        percentile(99),
+        percentile(100),
+    );
+    Ok(())
+}
+
+/// Search the watched addresses' history for `txid` (when the node has no `-txindex`),
+/// skipping the transactions whose txid is already cached.
+fn find_watched_tx<'a>(
+    txid: bitcoin::Txid,
+    scripts: &HashSet<bitcoin::ScriptBuf>,
+    index: &'a address::Index,
+    cache: Option<&Cache>,
+) -> Result<Option<(bitcoin::Transaction, Location<'a>)>, Box<dyn std::error::Error>> {
+    let mut locations = BTreeSet::new();
+    for script in scripts {
+        locations.extend(index.find(script)?);
+    }
+    for loc in locations {
+        if let Some(cached) = cache
+            .map(|cache| cache.get_txid(&loc))
+            .transpose()?
+            .flatten()
+        {
+            if cached != txid {
+                continue;
+            }
+        }
+        let tx: bitcoin::Transaction = deserialize(&index.get_tx_bytes(&loc)?)?;
+        if tx.compute_txid() == txid {
+            return Ok(Some((tx, loc)));
+        }
+    }
+    Ok(None)
+}
+
+fn print_tx(
+    txid: bitcoin::Txid,
+    scripts: &HashSet<bitcoin::ScriptBuf>,
+    index: &address::Index,
+    cache: Option<&Cache>,
+    network: bitcoin::Network,
+    expand: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let found = match index.get_transaction(txid) {
+        Ok(tx) => index.locate_tx(&tx)?.map(|loc| (tx, loc)),
+        Err(e) => {
+            warn!(
+                "failed to get {} from the node ({}), searching the watched addresses' history",
+                txid, e
+            );
+            find_watched_tx(txid, scripts, index, cache)?
+        }
+    };
+    let Some((tx, loc)) = found else {
+        return Err(format!("{} is not confirmed in the indexed chain", txid).into());
+    };
+    let describe = |script: &bitcoin::Script| {
+        let watched = if scripts.contains(script) {
+            " (watched)"
+        } else {
+            ""
+        };
+        match bitcoin::Address::from_script(script, network) {
+            Ok(addr) => format!("{}{}", addr, watched),
+            Err(_) => format!("{}{}", script.to_asm_string(), watched),
+        }
+    };
+    let hash = loc.indexed_header.hash();
+    println!("txid: {}", txid);
+    println!(
+        "block: {} height={} offset={} confirmations={}",
+        hash,
+        loc.height,
+        loc.offset,
+        index.confirmations(&hash).unwrap_or_default()
+    );
+    // the spent outputs are taken from the block's undo data (if available)
+    let spent = match index.get_spent_outputs(&loc) {
+        Ok(spent) if !tx.is_coinbase() => Some(spent),
+        Ok(_) => None,
+        Err(e) => {
+            warn!("block={} has no undo data: {}", hash, e);
+            None
+        }
+    };
+    for (n, txi) in tx.input.iter().enumerate() {
+        match spent.as_ref().and_then(|spent| spent.get(n)) {
+            Some(txo) => println!(
+                "input #{}: {} {} {}",
+                n,
+                txi.previous_output,
+                txo.value,
+                describe(&txo.script_pubkey)
+            ),
+            None => println!("input #{}: {}", n, txi.previous_output),
+        }
+    }
+    let (recipients, sent) = tx
+        .output
+        .iter()
+        .filter(|txo| !scripts.contains(&txo.script_pubkey))
+        .fold((0, bitcoin::Amount::ZERO), |(n, sent), txo| {
+            (n + 1, sent + txo.value)
+        });
+    let summarize = !expand && recipients >= BATCH_MIN_RECIPIENTS;
+    for (n, txo) in tx.output.iter().enumerate() {
+        if summarize && !scripts.contains(&txo.script_pubkey) {
+            continue;
+        }
+        println!(
+            "output #{}: {} {}",
+            n,
+            txo.value,
+            describe(&txo.script_pubkey)
+        );
+    }
+    if summarize {
+        println!(
+            "batch payout: {} recipients, {} (use --expand to list them)",
+            recipients, sent
+        );
+    }
+    if let Some(spent) = spent {
+        let inputs: bitcoin::Amount = spent.iter().map(|txo| txo.value).sum();
+        let outputs: bitcoin::Amount = tx.output.iter().map(|txo| txo.value).sum();
+        println!("fee: {}", inputs - outputs);
+    }
+    Ok(())
+}
+
+fn print_block(
+    block: &str,
+    scripts: &HashSet<bitcoin::ScriptBuf>,
+    index: &address::Index,
+    network: bitcoin::Network,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let height = match block.parse::<usize>() {
+        Ok(height) => Some(height),
+        Err(_) => index.get_height(&block.parse()?),
+    };
+    let summary = match height.map(|h| index.get_block_summary(h)).transpose()? {
+        Some(Some(summary)) => summary,
+        _ => return Err(format!("block {} is not indexed", block).into()),
+    };
+    println!(
+        "block: {} height={}",
+        summary.header.block_hash(),
+        summary.height
+    );
+    println!(
+        "time: {}",
+        Utc.timestamp_opt(summary.header.time.into(), 0).unwrap()
+    );
+    if let Some(mtp) = index.median_time_past(summary.height) {
+        println!("mediantime: {}", Utc.timestamp_opt(mtp.into(), 0).unwrap());
+    }
+    println!("txs: {}", summary.tx_count);
+    match summary.fees {
+        Some(fees) => println!("fees: {}", fees),
+        None => println!("fees: unknown (no undo data)"),
+    }
+    for script in scripts {
+        let txs = index
+            .find_since(script, summary.height)?
+            .into_iter()
+            .filter(|loc| loc.height == summary.height)
+            .count();
+        if txs > 0 {
+            let addr = bitcoin::Address::from_script(script, network)?;
+            println!("watched: {} ({} txs)", addr, txs);
+        }
+    }
+    Ok(())
+}
+
+/// Returns `false` if the index and the node disagree.
+fn verify_against_node(
+    watch: &WatchList,
+    index: &address::Index,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    // only the UTXOs are compared
+    let history = Status::create(
+        watch,
+        index,
+        None,
+        false,
+        TimeSource::Header,
+        None,
+        RowsOutput::Keep,
+    )?;
+    let scripts: Vec<_> = watch.scripts.iter().cloned().collect();
+    let scan = index.scan_node_utxos(&scripts)?;
+    if Some(scan.height) != index.tip_height() {
+        warn!(
+            "node scanned UTXOs at height={}, index tip height={:?}",
+            scan.height,
+            index.tip_height()
+        );
+    }
+    let node_unspent: HashMap<_, _> = scan
+        .unspents
+        .iter()
+        .map(|u| (bitcoin::OutPoint::new(u.txid, u.vout), u.amount))
+        .collect();
+
+    let mut ok = true;
+    for (outpoint, utxo) in &history.unspent {
+        let amount = &utxo.value;
+        match node_unspent.get(outpoint) {
+            None => {
+                error!(
+                    "{} ({}) is missing from the node's UTXO set",
+                    Redacted(outpoint),
+                    amount
+                );
+                ok = false;
+            }
+            Some(node_amount) if node_amount != amount => {
+                error!(
+                    "{} amount mismatch: {} != {}",
+                    Redacted(outpoint),
+                    amount,
+                    node_amount
+                );
+                ok = false;
+            }
+            Some(_) => (),
+        }
+    }
+    for (outpoint, amount) in &node_unspent {
+        if !history.unspent.contains_key(outpoint) {
+            error!(
+                "{} ({}) is missing from the index",
+                Redacted(outpoint),
+                amount
+            );
+            ok = false;
+        }
+    }
+    let node_balance: bitcoin::Amount = node_unspent.values().copied().sum();
+    info!(
+        "index: {} UTXOs, balance: {} | node: {} UTXOs, balance: {}",
+        history.unspent.len(),
+        history.balance,
+        node_unspent.len(),
+        node_balance,
+    );
+    Ok(ok)
+}
+
+fn read_address_file(path: &Path) -> std::io::Result<String> {
+    if path == Path::new("-") {
+        let mut buf = String::new();
+        std::io::stdin().read_to_string(&mut buf)?;
+        return Ok(buf);
+    }
+    std::fs::read_to_string(path)
+}
+
+#[derive(serde::Deserialize)]
+struct WatchEntry {
+    /// An address or a descriptor
+    #[serde(alias = "descriptor")]
+    address: String,
+    height: Option<usize>,
+}
+
+/// Parse a watch file: a JSON array of `{"address": ..., "height": ...}` objects,
+/// or whitespace-separated `address[,height]` entries (an `address,height` CSV header is skipped).
+/// Descriptors can be used instead of addresses.
+fn parse_address_file(path: &Path) -> Result<Vec<WatchEntry>, Box<dyn std::error::Error>> {
+    let content = read_address_file(path)?;
+    if content.trim_start().starts_with('[') {
+        return serde_json::from_str(&content).map_err(|e| format!("{:?}: {}", path, e).into());
+    }
+    parse_address_list(&content, path)
+}
+
+#[derive(serde::Deserialize)]
+struct CoreDescriptors {
+    descriptors: Vec<CoreDescriptor>,
+}
+
+#[derive(serde::Deserialize)]
+struct CoreDescriptor {
+    desc: String,
+}
+
+/// Parse Bitcoin Core's `listdescriptors` output, or a `dumpwallet` file (using the addresses
+/// of its keys and scripts, e.g. `<key> <time> label= # addr=bc1q...,1...`).
+fn parse_core_wallet(path: &Path) -> Result<Vec<WatchEntry>, Box<dyn std::error::Error>> {
+    let content = read_address_file(path)?;
+    let entry = |address: &str| WatchEntry {
+        address: address.to_owned(),
+        height: None,
+    };
+    if content.trim_start().starts_with('{') {
+        let wallet: CoreDescriptors =
+            serde_json::from_str(&content).map_err(|e| format!("{:?}: {}", path, e))?;
+        let entries = wallet.descriptors.iter().map(|d| {
+            // imported addresses are exported as `addr()` descriptors
+            let desc = d.desc.split('#').next().unwrap_or_default();
+            match desc.strip_prefix("addr(").and_then(|s| s.strip_suffix(')')) {
+                Some(address) => entry(address),
+                None => entry(&d.desc),
+            }
+        });
+        return Ok(entries.collect());
+    }
+    let entries: Vec<_> = content
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once("# addr=").map(|(_, addrs)| addrs))
+        .flat_map(|addrs| {
+            addrs
+                .split_ascii_whitespace()
+                .next()
+                .unwrap_or_default()
+                .split(',')
+        })
+        .filter(|addr| !addr.is_empty())
+        .map(entry)
+        .collect();
+    if entries.is_empty() {
+        return Err(format!("{:?}: no addresses found in the wallet dump", path).into());
+    }
+    Ok(entries)
+}
+
+/// Parse whitespace-separated `address[,height]` entries (`path` is used for error messages).
+fn parse_address_list(
+    content: &str,
+    path: &Path,
+) -> Result<Vec<WatchEntry>, Box<dyn std::error::Error>> {
+    content
+        .split_ascii_whitespace()
+        .filter(|entry| !entry.starts_with("address,"))
+        .map(|entry| {
+            // descriptors contain commas, so the height must follow the closing parenthesis
+            let comma = match entry.rfind(')') {
+                Some(end) => entry[end..].find(',').map(|i| end + i),
+                None => entry.find(','),
+            };
+            let (address, height) = match comma.map(|i| (&entry[..i], &entry[i + 1..])) {
+                None => (entry, None),
+                Some((address, height)) => {
+                    let height = height.parse().map_err(|e| {
+                        format!("{:?}: {}: invalid height: {}", path, Redacted(address), e)
+                    })?;
+                    (address, Some(height))
+                }
+            };
+            Ok(WatchEntry {
+                address: address.to_owned(),
+                height,
+            })
+        })
+        .collect()
+}
+
+/// Remove the given addresses from the watch file (keeping the other entries and lines as-is).
+/// Returns the number of removed entries.
+fn forget_addresses(
+    path: &Path,
+    scripts: &HashSet<bitcoin::ScriptBuf>,
+    network: bitcoin::Network,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    if path == Path::new("-") {
+        return Err("cannot remove addresses from stdin".into());
+    }
+    let is_forgotten = |address: &str| {
+        bitcoin::Address::from_str(address)
+            .ok()
+            .and_then(|addr| addr.require_network(network).ok())
+            .is_some_and(|addr| scripts.contains(&addr.script_pubkey()))
+    };
+    let content = read_address_file(path)?;
+    let mut removed = 0;
+    let content = if content.trim_start().starts_with('[') {
+        let mut entries: Vec<serde_json::Value> = serde_json::from_str(&content)?;
+        entries.retain(|entry| {
+            let address = entry
+                .get("address")
+                .or_else(|| entry.get("descriptor"))
+                .and_then(serde_json::Value::as_str);
+            let forgotten = address.is_some_and(is_forgotten);
+            removed += usize::from(forgotten);
+            !forgotten
+        });
+        serde_json::to_string_pretty(&entries)? + "\n"
+    } else {
+        let mut lines = vec![];
+        for line in content.lines() {
+            let tokens: Vec<_> = line.split_ascii_whitespace().collect();
+            // addresses contain no commas or parentheses (so the height follows the first comma)
+            let kept: Vec<_> = tokens
+                .iter()
+                .filter(|token| !is_forgotten(token.split(',').next().unwrap_or_default()))
+                .collect();
+            if kept.len() == tokens.len() {
+                lines.push(line.to_owned());
+            } else {
+                removed += tokens.len() - kept.len();
+                if !kept.is_empty() {
+                    lines.push(kept.into_iter().copied().collect::<Vec<_>>().join(" "));
+                }
+            }
+        }
+        lines.into_iter().map(|line| line + "\n").collect()
+    };
+    if removed > 0 {
+        // replace the file atomically
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(&tmp, path)?;
+    }
+    Ok(removed)
+}
+
+/// A watched script (labeled for logging)
+struct DerivedScript {
+    label: String,
+    script: bitcoin::ScriptBuf,
+    /// The derivation paths of its keys (if derived from a descriptor)
+    key_path: Option<String>,
+}
+
+/// Derive the scripts of a watch file entry, or `None` if it is for another network.
+fn entry_scripts(
+    entry: &str,
+    network: bitcoin::Network,
+    descriptor_range: u32,
+) -> Result<Option<Vec<DerivedScript>>, Box<dyn std::error::Error>> {
+    if !entry.contains('(') {
+        let addr = bitcoin::Address::from_str(entry)
+            .map_err(|e| format!("invalid address {}: {}", Redacted(entry), e))?;
+        if !addr.is_valid_for_network(network) {
+            return Ok(None);
+        }
+        return Ok(Some(vec![DerivedScript {
+            label: entry.to_owned(),
+            script: addr.assume_checked().script_pubkey(),
+            key_path: None,
+        }]));
+    }
+    let desc = descriptor::Descriptor::from_str(entry)
+        .map_err(|e| format!("invalid descriptor {}: {}", Redacted(entry), e))?;
+    if desc
+        .networks()
+        .any(|n| n != bitcoin::NetworkKind::from(network))
+    {
+        return Ok(None);
+    }
+    let secp = bitcoin::secp256k1::Secp256k1::verification_only();
+    let derive = |i: u32, label: String| derive_script(&desc, &secp, i, label);
+    if !desc.is_ranged() {
+        return Ok(Some(vec![derive(0, entry.to_owned())?]));
+    }
+    let scripts = (0..descriptor_range)
+        .map(|i| derive(i, format!("{}/{}", entry, i)))
+        .collect::<Result<Vec<_>, descriptor::Error>>()?;
+    Ok(Some(scripts))
+}
+
+fn derive_script(
+    desc: &descriptor::Descriptor,
+    secp: &bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::VerifyOnly>,
+    index: u32,
+    label: String,
+) -> Result<DerivedScript, descriptor::Error> {
+    let key_paths = desc.key_paths(index);
+    Ok(DerivedScript {
+        label,
+        script: desc.script_pubkey(secp, index)?,
+        key_path: (!key_paths.is_empty()).then(|| key_paths.join(" ")),
+    })
+}
+
+/// Parse the watched addresses and descriptors (and their birthday heights), reporting (and skipping)
+/// duplicate and mismatching entries.
+fn load_scripts(args: &Args) -> Result<WatchList, Box<dyn std::error::Error>> {
+    let network = args.network.into();
+    let descriptor_range = args.descriptor_range;
+    let mut sources = vec![];
+    for path in &args.address_file {
+        sources.push((path.as_path(), None, parse_address_file(path)?));
+    }
+    for (name, path) in &args.wallet {
+        sources.push((path.as_path(), Some(name), parse_address_file(path)?));
+    }
+    for path in &args.core_wallet {
+        sources.push((path.as_path(), None, parse_core_wallet(path)?));
+    }
+    if !args.watch.is_empty() {
+        let path = Path::new("--watch");
+        sources.push((path, None, parse_address_list(&args.watch.join(" "), path)?));
+    }
+    let mut seen = HashMap::<bitcoin::ScriptBuf, (String, &Path)>::new();
+    let mut watch = WatchList::default();
+    for (path, wallet, entries) in sources {
+        // deriving the scripts is the bottleneck for huge watch files
+        let derived: Vec<_> = entries
+            .par_iter()
+            .map(|entry| {
+                entry_scripts(&entry.address, network, descriptor_range).map_err(|e| e.to_string())
+            })
+            .collect();
+        for (WatchEntry { address, height }, scripts) in entries.into_iter().zip(derived) {
+            let scripts = scripts.map_err(|e| format!("{:?}: {}", path, e))?;
+            let Some(scripts) = scripts else {
+                warn!(
+                    "{:?}: {} is not for {} (skipped)",
+                    path,
+                    Redacted(&address),
+                    network
+                );
+                continue;
+            };
+            for derived in scripts {
+                if let Some(wallet) = wallet {
+                    // duplicated scripts belong to the first wallet (like their labels)
+                    if !seen.contains_key(&derived.script) {
+                        let scripts = watch.wallets.entry(wallet.clone()).or_default();
+                        scripts.insert(derived.script.clone());
+                    }
+                }
+                load_script(&mut seen, &mut watch, path, derived, height);
+            }
+            if let Ok(desc) = descriptor::Descriptor::from_str(&address) {
+                if desc.is_ranged() {
+                    watch.ranged.push(RangedDescriptor {
+                        entry: address,
+                        desc,
+                        birthday: height.unwrap_or_default(),
+                        derived: descriptor_range,
+                        wallet: wallet.cloned(),
+                    });
+                }
+            }
+        }
+    }
+    watch.birthdays.retain(|_, height| *height > 0);
+    watch.scripts = seen.into_keys().collect();
+    Ok(watch)
+}
+
+/// Add a watched script, reporting duplicate entries.
+fn load_script<'a>(
+    seen: &mut HashMap<bitcoin::ScriptBuf, (String, &'a Path)>,
+    watch: &mut WatchList,
+    path: &'a Path,
+    derived: DerivedScript,
+    height: Option<usize>,
+) {
+    let DerivedScript {
+        label,
+        script,
+        key_path,
+    } = derived;
+    let entry = label.as_str();
+    // an address without a birthday is scanned from genesis
+    let birthday = height.unwrap_or_default();
+    watch
+        .birthdays
+        .entry(script.clone())
+        .and_modify(|h| *h = (*h).min(birthday))
+        .or_insert(birthday);
+    match seen.get(&script) {
+        None => {
+            if let Some(key_path) = key_path {
+                watch.key_paths.insert(script.clone(), key_path);
+            }
+            seen.insert(script, (label, path));
+        }
+        Some((prev, prev_path)) if prev != entry => warn!(
+            "{:?}: {} has the same script as {} (from {:?})",
+            path,
+            Redacted(entry),
+            Redacted(prev),
+            prev_path
+        ),
+        Some((_, prev_path)) if *prev_path != path => warn!(
+            "{:?}: {} is already watched (from {:?})",
+            path,
+            Redacted(entry),
+            prev_path
+        ),
+        Some(_) => warn!("{:?}: {} is duplicated", path, Redacted(entry)),
+    }
+}
+
+const NODE_CHECK_PERIOD: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Polling period when block notifications are used (in case a notification is missed)
+const ZMQ_POLL_PERIOD: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Subscribe to new block notifications in a background thread (reconnecting on failures).
+fn block_notifications(args: &Args) -> Option<std::sync::mpsc::Receiver<()>> {
+    let endpoint = args.zmq_block.clone()?;
+    // a single pending notification is enough to trigger a sync
+    let (tx, rx) = std::sync::mpsc::sync_channel(1);
+    thread::spawn(move || loop {
+        let result = zmq::Subscriber::connect(&endpoint, &["hashblock"]).and_then(|mut sub| {
+            info!("subscribed to block notifications from {}", endpoint);
+            loop {
+                let frames = sub.recv()?;
+                debug!("{:?} notification", String::from_utf8_lossy(&frames[0]));
+                if let Err(std::sync::mpsc::TrySendError::Disconnected(())) = tx.try_send(()) {
+                    return Ok(());
+                }
+            }
+        });
+        match result {
+            Ok(()) => return,
+            Err(e) => warn!("block notifications from {} failed: {}", endpoint, e),
+        }
+        thread::sleep(ZMQ_POLL_PERIOD);
+    });
+    Some(rx)
+}
+
+/// Wait for a new block notification (or just sleep when not subscribed).
+fn wait_for_block(notifications: Option<&std::sync::mpsc::Receiver<()>>) {
+    match notifications {
+        Some(rx) => {
+            // wake up every second to check for shutdown requests
+            for _ in 0..ZMQ_POLL_PERIOD.as_secs() {
+                match rx.recv_timeout(std::time::Duration::from_secs(1)) {
+                    Err(std::sync::mpsc::RecvTimeoutError::Timeout) if !shutdown_requested() => (),
+                    _ => break,
+                }
+            }
+        }
+        None => thread::sleep(std::time::Duration::from_secs(1)),
+    }
+}
+
+/// Set upon SIGUSR1 (to re-print the report)
+static REPORT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+fn handle_sigusr1() {
+    extern "C" fn handler(_signal: libc::c_int) {
+        REPORT_REQUESTED.store(true, Ordering::Relaxed);
+    }
+    // SAFETY: the handler only stores an atomic flag (which is async-signal-safe)
+    unsafe {
+        let handler: extern "C" fn(libc::c_int) = handler;
+        libc::signal(libc::SIGUSR1, handler as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+fn handle_sigusr1() {}
+
+/// Set upon SIGINT/SIGTERM (to stop syncing after writing the blocks indexed so far)
+static SHUTDOWN: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+fn shutdown_requested() -> bool {
+    SHUTDOWN
+        .get()
+        .is_some_and(|shutdown| shutdown.load(Ordering::Relaxed))
+}
+
+#[cfg(unix)]
+fn handle_shutdown(index: &mut address::Index) {
+    extern "C" fn handler(signal: libc::c_int) {
+        if let Some(shutdown) = SHUTDOWN.get() {
+            shutdown.store(true, Ordering::Relaxed);
+        }
+        // a repeated signal terminates the process immediately
+        // SAFETY: `signal` is async-signal-safe
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+        }
+    }
+    index.set_cancel(SHUTDOWN.get_or_init(Default::default).clone());
+    // SAFETY: the handler only stores an atomic flag and resets itself (see above)
+    unsafe {
+        let handler: extern "C" fn(libc::c_int) = handler;
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+fn handle_shutdown(_index: &mut address::Index) {}
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+/// Bitcoin address indexer
+struct Args {
+    /// Inferred from the node if only `--rpc-url` is given
+    #[arg(value_enum, short = 'n', long = "network", default_value_t = Network::Bitcoin)]
+    network: Network,
+
+    #[arg(short = 'l', long = "limit", default_value_t = 100)]
+    history_limit: usize,
+
+    /// Replace addresses, txids and scripts in log output by short hashes
+    #[arg(long = "redact")]
+    redact: bool,
+
+    /// Print tab-separated history rows (instead of a table)
+    #[arg(long = "plain")]
+    plain: bool,
+
+    /// History output format
+    #[arg(
+        value_enum,
+        long = "format",
+        default_value_t = HistoryFormat::Table,
+        conflicts_with_all = ["plain", "group_by_address", "wallet"]
+    )]
+    format: HistoryFormat,
+
+    /// Show each transaction's nLockTime, RBF signaling, input sequence numbers
+    /// and the spend paths of watched taproot and P2WSH (e.g. miniscript) inputs
+    #[arg(long = "tx-details")]
+    tx_details: bool,
+
+    /// Show each transaction's fee (fetching its inputs' values from the node,
+    /// unless all of them are watched)
+    #[arg(long = "fees")]
+    fees: bool,
+
+    /// Print a separate history (with its own running balance) for each address
+    #[arg(long = "group-by-address")]
+    group_by_address: bool,
+
+    /// Show whether the watched outputs may carry inscriptions or runes
+    #[arg(long = "ordinals")]
+    ordinals: bool,
+
+    /// File with addresses to watch (`-` for stdin), can be repeated.
+    /// Entries may specify the height the address was created at (`address,height` or JSON).
+    #[arg(short = 'a', long = "address-file", conflicts_with = "headers_only")]
+    address_file: Vec<PathBuf>,
+
+    /// A named wallet's address file (`name=path`), can be repeated.
+    /// Each wallet's history and balance are reported separately (as well as the aggregate ones).
+    #[arg(long = "wallet", value_parser = parse_wallet, conflicts_with = "headers_only")]
+    wallet: Vec<(String, PathBuf)>,
+
+    /// Bitcoin Core wallet export to watch: a `dumpwallet` file, or `listdescriptors` JSON output
+    /// (`-` for stdin), can be repeated
+    #[arg(long = "core-wallet", conflicts_with = "headers_only")]
+    core_wallet: Vec<PathBuf>,
+
+    /// Address or descriptor to watch (`address[,height]`), can be repeated
+    #[arg(short = 'w', long = "watch", conflicts_with = "headers_only")]
+    watch: Vec<String>,
+
+    /// TOML file with default options, e.g. `rpc_url = "http://node:8332"` or `watch = ["wpkh(...)"]`
+    /// (default: `~/.config/bindex/config.toml`, if it exists). Supported keys: `network`, `rpc_url`,
+    /// `db_dir`, `rpc_user`, `rpc_pass`, `rpc_cookie`, `watch`, `address_file`, `cache_file`,
+    /// `cache_key_file`, `metrics`, `zmq_block` and `notify`
+    #[arg(long = "config", env = "BINDEX_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Number of scripts to derive from each ranged descriptor (e.g. `wpkh(xpub/0/*)`)
+    #[arg(long = "descriptor-range", default_value_t = 1000)]
+    descriptor_range: u32,
+
+    /// Keep deriving scripts from each ranged descriptor (beyond `--descriptor-range`),
+    /// until this many consecutive ones have no history
+    #[arg(long = "gap-limit")]
+    gap_limit: Option<u32>,
+
+    /// Maintain only the header chain (no address index)
+    #[arg(long = "headers-only")]
+    headers_only: bool,
+
+    /// Build and store a BIP158 filter for each newly indexed block
+    #[arg(long = "filters", conflicts_with = "headers_only")]
+    filters: bool,
+
+    /// Memory budget (in MB) for DB caches and sync batches
+    #[arg(long = "mem-budget")]
+    mem_budget: Option<usize>,
+
+    /// Memory cap (in MB) for the watched addresses' status (its locations, transactions, UTXOs
+    /// and history rows): above it, the history rows are written as they are created (in
+    /// confirmation order, ignoring `--limit`) instead of being kept for the report
+    #[arg(long = "status-max-mb")]
+    status_max_mb: Option<usize>,
+
+    /// Maximum number of node requests per second
+    #[arg(long = "rpc-rate-limit")]
+    rpc_rate_limit: Option<u32>,
+
+    /// Node URL (default: localhost, using the network's RPC port).
+    /// Can be repeated (or comma-separated) to fail over to the next nodes when the first is unreachable.
+    #[arg(long = "rpc-url", env = "BINDEX_RPC_URL", value_delimiter = ',')]
+    rpc_url: Vec<String>,
+
+    /// Directory for the index DBs (each network's index is stored in its own subdirectory)
+    #[arg(long = "db-dir", env = "BINDEX_DB_DIR", default_value = "db")]
+    db_dir: PathBuf,
+
+    /// JSON-RPC user
+    #[arg(long = "rpc-user", env = "BINDEX_RPC_USER", requires = "rpc_pass")]
+    rpc_user: Option<String>,
+
+    /// JSON-RPC password (prefer setting `BINDEX_RPC_PASS`, since command lines are visible to other users)
+    #[arg(
+        long = "rpc-pass",
+        env = "BINDEX_RPC_PASS",
+        hide_env_values = true,
+        requires = "rpc_user"
+    )]
+    rpc_pass: Option<String>,
+
+    /// JSON-RPC cookie file
+    #[arg(
+        long = "rpc-cookie",
+        env = "BINDEX_RPC_COOKIE",
+        conflicts_with_all = ["rpc_user", "rpc_pass"]
+    )]
+    rpc_cookie: Option<PathBuf>,
+
+    /// Timeout (in seconds) for connecting to the node
+    #[arg(long = "rpc-connect-timeout")]
+    rpc_connect_timeout: Option<u64>,
+
+    /// Timeout (in seconds) for receiving each node response
+    #[arg(long = "rpc-read-timeout")]
+    rpc_read_timeout: Option<u64>,
+
+    /// Number of retries for timed-out node requests
+    #[arg(long = "rpc-retries", default_value_t = 2)]
+    rpc_retries: usize,
+
+    /// Serve Prometheus metrics over HTTP (at `/metrics`)
+    #[arg(long = "metrics", env = "BINDEX_METRICS")]
+    metrics: Option<std::net::SocketAddr>,
+
+    /// Sync upon bitcoind's `zmqpubhashblock` notifications (e.g. tcp://127.0.0.1:28332),
+    /// instead of polling the node every second
+    #[arg(long = "zmq-block", env = "BINDEX_ZMQ_BLOCK")]
+    zmq_block: Option<String>,
+
+    /// Report the balance of UTXOs with at least this many confirmations as trusted
+    #[arg(long = "min-conf")]
+    min_conf: Option<usize>,
+
+    /// Warn when the total balance drops below this amount (in BTC)
+    #[arg(long = "alert-balance-below", value_parser = parse_btc)]
+    alert_balance_below: Option<bitcoin::Amount>,
+
+    /// Warn when an address receives more than this amount (in BTC) in a new transaction
+    #[arg(long = "alert-receive-above", value_parser = parse_btc)]
+    alert_receive_above: Option<bitcoin::Amount>,
+
+    /// Notify about new transactions of the watched addresses (may be repeated): `webhook=<url>`
+    /// (POSTing JSON) or `exec=<command>` (setting `BINDEX_{TXID,ADDRESS,AMOUNT,HEIGHT}`),
+    /// optionally filtered by `,direction=incoming|outgoing`, `,min-amount=<BTC>` and
+    /// `,address=<address>`, e.g. `webhook=http://localhost:8000/,direction=incoming,min-amount=0.001`
+    #[arg(long = "notify")]
+    notify: Vec<notify::Channel>,
+
+    /// Warn if the node's tip is older than this (in seconds), if it stops validating blocks,
+    /// or if it rejects a longer chain (also alerting the `--notify` channels)
+    #[arg(long = "max-tip-age")]
+    max_tip_age: Option<u64>,
+
+    /// Stop indexing at this height (rolling back any blocks above it)
+    #[arg(long = "sync-to-height")]
+    sync_to_height: Option<usize>,
+
+    /// SQLite file for caching the watched addresses' history and transactions
+    #[arg(short = 'c', long = "cache-file")]
+    cache_file: Option<PathBuf>,
+
+    /// Encrypt the cache file (using SQLCipher) with the passphrase stored in this file
+    #[arg(long = "cache-key-file", requires = "cache_file")]
+    cache_key_file: Option<PathBuf>,
+
+    /// Encrypt the cache file (using SQLCipher) with this passphrase
+    #[arg(
+        long = "cache-key",
+        env = "BINDEX_CACHE_KEY",
+        hide_env_values = true,
+        requires = "cache_file",
+        conflicts_with = "cache_key_file"
+    )]
+    cache_key: Option<String>,
+
+    /// Encrypt the cache file (using SQLCipher) with a passphrase read from the terminal
+    #[arg(
+        long = "cache-key-prompt",
+        requires = "cache_file",
+        conflicts_with_all = ["cache_key_file", "cache_key"]
+    )]
+    cache_key_prompt: bool,
+
+    /// Timestamp to show and export for each transaction's block
+    #[arg(long = "time-source", value_enum, default_value = "header")]
+    time_source: TimeSource,
+
+    /// Evict the least recently used transactions when the cached ones exceed this size (in MB)
+    #[arg(long = "cache-max-tx-mb", requires = "cache_file")]
+    cache_max_tx_mb: Option<u64>,
+
+    /// Store a merkle proof (and block header) for each cached transaction
+    #[arg(long = "merkle-proofs", requires = "cache_file")]
+    merkle_proofs: bool,
+
+    /// Sync once, print only the transactions missing from the cache and exit (with status 2 if any)
+    #[arg(long = "diff", requires = "cache_file")]
+    diff: bool,
+
+    /// Like `--diff`, but without printing the history and info logs
+    /// (exit status: 0 = no new transactions, 2 = new transactions, 1 = error)
+    #[arg(
+        short = 'q',
+        long = "quiet",
+        requires = "cache_file",
+        conflicts_with = "dry_run"
+    )]
+    quiet: bool,
+
+    /// Sync once, report how many cache rows would be inserted or pruned (without modifying it) and exit
+    #[arg(long = "dry-run", requires = "cache_file", conflicts_with = "diff")]
+    dry_run: bool,
+
+    /// Sync once and print a Nagios plugin status line with perfdata
+    /// (exit status: 0 = OK, 1 = WARNING, 2 = CRITICAL, 3 = UNKNOWN)
+    #[arg(long = "check", conflicts_with_all = ["diff", "quiet", "dry_run"])]
+    check: bool,
+
+    /// Sync once, print the report (updating the cache) and exit (e.g. when running from cron)
+    #[arg(long = "once", conflicts_with_all = ["diff", "quiet", "dry_run", "check"])]
+    once: bool,
+
+    /// Keep syncing and re-printing the report after new blocks (the default)
+    #[arg(
+        long = "daemon",
+        conflicts_with_all = ["once", "diff", "quiet", "dry_run", "check"]
+    )]
+    daemon: bool,
+
+    /// Print each newly confirmed deposit (a non-change output to a watched address) as a JSON line
+    /// (with its amount in satoshis), once it has `--min-conf` confirmations (instead of printing the history)
+    #[arg(long = "deposits", requires = "cache_file", conflicts_with_all = ["diff", "quiet", "dry_run", "check"])]
+    deposits: bool,
+
+    /// Re-verify a randomly sampled indexed block against the node every this many seconds
+    #[arg(long = "audit-interval")]
+    audit_interval: Option<u64>,
+
+    /// Re-read the address files when SIGUSR1 is received (which also re-prints the report)
+    #[arg(long = "reload-on-sigusr1")]
+    reload_on_sigusr1: bool,
+
+    /// Warm up the index for the watched addresses on startup, and fetch their transactions
+    /// into the cache in the background (while syncing)
+    #[arg(
+        long = "prefetch",
+        requires = "cache_file",
+        conflicts_with_all = ["diff", "quiet", "dry_run", "check"]
+    )]
+    prefetch: bool,
+
+    /// `--check` returns WARNING if the indexed tip is older than this (in seconds),
+    /// or if there are new transactions (missing from the cache)
+    #[arg(long = "check-warn-lag", default_value_t = 3600)]
+    check_warn_lag: u64,
+
+    /// `--check` returns CRITICAL if the indexed tip is older than this (in seconds)
+    #[arg(long = "check-crit-lag", default_value_t = 10800)]
+    check_crit_lag: u64,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Measure indexing throughput and address query latency
+    Bench {
+        /// First block height to index (default: 10 blocks below the indexed tip)
+        #[arg(long = "from")]
+        from: Option<usize>,
+
+        /// Last block height to index (default: the indexed tip)
+        #[arg(long = "to")]
+        to: Option<usize>,
+    },
+
+    /// Print the history and balance of the given addresses (using the existing index) and exit
+    Query {
+        #[arg(required = true)]
+        addresses: Vec<bitcoin::Address<bitcoin::address::NetworkUnchecked>>,
+    },
+
+    /// Print the indexed tip and the node's tip (using the existing index) and exit
+    Tip,
+
+    /// Print the confirmed balance of the given addresses (using the existing index) and exit
+    Balance {
+        #[arg(required = true)]
+        addresses: Vec<bitcoin::Address<bitcoin::address::NetworkUnchecked>>,
+    },
+
+    /// Exit with status 1 unless the address' confirmed balance matches the given amount
+    /// (using the existing index)
+    AssertBalance {
+        address: bitcoin::Address<bitcoin::address::NetworkUnchecked>,
+
+        /// Expected balance (in BTC)
+        #[arg(value_parser = parse_btc)]
+        amount: bitcoin::Amount,
+
+        /// Allowed difference (in BTC)
+        #[arg(long = "tolerance", value_parser = parse_btc, default_value = "0")]
+        tolerance: bitcoin::Amount,
+
+        /// Count only the UTXOs with at least this number of confirmations
+        #[arg(long = "min-conf", default_value_t = 1)]
+        min_conf: usize,
+    },
+
+    /// Export the watched addresses' UTXOs (using the existing index) and exit
+    ExportUtxos {
+        #[arg(value_enum, long = "format", default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+
+        /// Export the UTXOs as of this (past) height
+        #[arg(long = "height")]
+        height: Option<usize>,
+    },
+
+    /// Print the watched addresses' history as `listtransactions` JSON (using the existing index) and exit
+    ListTransactions {
+        /// Print only the most recent entries
+        #[arg(long = "count")]
+        count: Option<usize>,
+    },
+
+    /// Print the watched addresses' history as beancount/ledger-cli transactions (using the existing index) and exit
+    ExportLedger {
+        #[arg(value_enum, long = "format", default_value_t = LedgerFormat::Beancount)]
+        format: LedgerFormat,
+
+        /// Account holding the watched addresses' funds
+        #[arg(long = "account", default_value = "Assets:Bitcoin")]
+        account: String,
+
+        /// Account for received funds
+        #[arg(long = "income-account", default_value = "Income:Bitcoin")]
+        income_account: String,
+
+        /// Account for sent funds
+        #[arg(long = "expenses-account", default_value = "Expenses:Bitcoin")]
+        expenses_account: String,
+    },
+
+    /// Print the watched (coinbase payout) addresses' mining rewards and their totals per period
+    /// (using the existing index) and exit
+    Rewards {
+        #[arg(value_enum, long = "period", default_value_t = RewardPeriod::Month)]
+        period: RewardPeriod,
+    },
+
+    /// Print a confirmed transaction (fetched from the node, and located using the existing index) and exit
+    Tx {
+        txid: bitcoin::Txid,
+
+        /// List every recipient of a batch payout (instead of summarizing them)
+        #[arg(long = "expand")]
+        expand: bool,
+    },
+
+    /// Print a block summary and the watched addresses it touches (using the existing index) and exit
+    Block {
+        /// Block height or hash
+        block: String,
+    },
+
+    /// Re-index a range of blocks, restoring the watched addresses' missing index rows
+    Rescan {
+        /// First block height to rescan
+        #[arg(long = "from")]
+        from: usize,
+
+        /// Last block height to rescan (default: the indexed tip)
+        #[arg(long = "to")]
+        to: Option<usize>,
+    },
+
+    /// Remove addresses from the address files and delete their history from the cache
+    Forget {
+        #[arg(required = true)]
+        addresses: Vec<bitcoin::Address<bitcoin::address::NetworkUnchecked>>,
+    },
+
+    /// Keep syncing the index, and serve it to Electrum wallets
+    Serve {
+        /// Address to listen on for Electrum clients
+        #[arg(long = "listen", default_value = "127.0.0.1:50001")]
+        listen: std::net::SocketAddr,
+
+        /// Address to listen on for HTTP clients (e.g. `GET /address/{address}/balance`,
+        /// or `GET /events` for the watched addresses' new blocks)
+        #[cfg(feature = "http")]
+        #[arg(long = "http")]
+        http: Option<std::net::SocketAddr>,
+    },
+
+    /// Write the index to a portable dump file (which can be loaded on another machine)
+    Dump {
+        /// Output dump file
+        output: PathBuf,
+    },
+
+    /// Load a dump file (written by `dump`) into a new index DB (without connecting to the node)
+    Load {
+        /// Input dump file
+        input: PathBuf,
+    },
+
+    /// Verify the index's integrity, without syncing it (exiting with status 1 if it is corrupted)
+    Verify {
+        /// Number of randomly sampled blocks to re-index and compare with the index
+        #[arg(long = "samples", default_value_t = 100)]
+        samples: usize,
+
+        /// Compare the watched addresses' UTXOs with the node's `scantxoutset` results
+        #[arg(long = "against-node")]
+        against_node: bool,
+    },
+}
+
+fn parse_wallet(s: &str) -> Result<(String, PathBuf), String> {
+    match s.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !path.is_empty() => {
+            Ok((name.to_owned(), PathBuf::from(path)))
+        }
+        _ => Err(format!("expected name=path: {:?}", s)),
+    }
+}
+
+fn parse_btc(s: &str) -> Result<bitcoin::Amount, bitcoin::amount::ParseAmountError> {
+    bitcoin::Amount::from_str_in(s, bitcoin::Denomination::Bitcoin)
+}
+
+/// A single value, or an array of them (passed as repeated options)
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    fn into_vec(self) -> Vec<T> {
+        match self {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+/// The config file settings (overridden by the command-line options and their environment variables)
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    network: Option<String>,
+    rpc_url: Option<OneOrMany<String>>,
+    db_dir: Option<PathBuf>,
+    rpc_user: Option<String>,
+    rpc_pass: Option<String>,
+    rpc_cookie: Option<PathBuf>,
+    watch: Option<OneOrMany<String>>,
+    address_file: Option<OneOrMany<PathBuf>>,
+    cache_file: Option<PathBuf>,
+    cache_key_file: Option<PathBuf>,
+    metrics: Option<std::net::SocketAddr>,
+    zmq_block: Option<String>,
+    notify: Option<OneOrMany<String>>,
+}
+
+impl Config {
+    /// Convert into (long) command-line options, e.g. `rpc_url = ["http://a:8332", "http://b:8332"]`
+    /// into `--rpc-url http://a:8332 --rpc-url http://b:8332`.
+    fn into_args(self) -> Vec<(&'static str, std::ffi::OsString)> {
+        fn many<T>(values: Option<OneOrMany<T>>) -> Vec<T> {
+            values.map(OneOrMany::into_vec).unwrap_or_default()
+        }
+        let mut args = vec![];
+        let mut push = |option, values: Vec<std::ffi::OsString>| {
+            args.extend(values.into_iter().map(|value| (option, value)));
+        };
+        push(
+            "network",
+            self.network.into_iter().map(Into::into).collect(),
+        );
+        push(
+            "rpc-url",
+            many(self.rpc_url).into_iter().map(Into::into).collect(),
+        );
+        push("db-dir", self.db_dir.into_iter().map(Into::into).collect());
+        push(
+            "rpc-user",
+            self.rpc_user.into_iter().map(Into::into).collect(),
+        );
+        push(
+            "rpc-pass",
+            self.rpc_pass.into_iter().map(Into::into).collect(),
+        );
+        push(
+            "rpc-cookie",
+            self.rpc_cookie.into_iter().map(Into::into).collect(),
+        );
+        push(
+            "watch",
+            many(self.watch).into_iter().map(Into::into).collect(),
+        );
+        push(
+            "address-file",
+            many(self.address_file)
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+        );
+        push(
+            "cache-file",
+            self.cache_file.into_iter().map(Into::into).collect(),
+        );
+        push(
+            "cache-key-file",
+            self.cache_key_file.into_iter().map(Into::into).collect(),
+        );
+        push(
+            "metrics",
+            self.metrics
+                .map(|addr| addr.to_string().into())
+                .into_iter()
+                .collect(),
+        );
+        push(
+            "zmq-block",
+            self.zmq_block.into_iter().map(Into::into).collect(),
+        );
+        push(
+            "notify",
+            many(self.notify).into_iter().map(Into::into).collect(),
+        );
+        args
+    }
+}
+
+/// Insert the options from the config file (`--config`, `BINDEX_CONFIG` or the default one) before
+/// the command-line ones, skipping the options that are given on the command line.
+fn with_config_args(
+    argv: Vec<std::ffi::OsString>,
+) -> Result<Vec<std::ffi::OsString>, Box<dyn std::error::Error>> {
+    let explicit = argv.iter().enumerate().find_map(|(i, arg)| {
+        let arg = arg.to_str()?;
+        match arg.strip_prefix("--config") {
+            Some("") => argv.get(i + 1).map(PathBuf::from),
+            Some(rest) => rest.strip_prefix('=').map(PathBuf::from),
+            None => None,
+        }
+    });
+    let explicit = explicit.or_else(|| std::env::var_os("BINDEX_CONFIG").map(PathBuf::from));
+    let path = match explicit {
+        Some(path) => path,
+        None => {
+            let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+            match config_dir.map(|dir| dir.join("bindex").join("config.toml")) {
+                Some(path) if path.exists() => path,
+                _ => return Ok(argv),
+            }
+        }
+    };
+    let text = std::fs::read_to_string(&path).map_err(|e| format!("{:?}: {}", path, e))?;
+    let config: Config = toml::from_str(&text).map_err(|e| format!("{:?}: {}", path, e))?;
+    Ok(merge_config_args(argv, config, |var| {
+        std::env::var_os(var).is_some()
+    }))
+}
+
+/// Insert the config file's options after `argv[0]`, unless they (or conflicting options) are
+/// given on the command line, or in their environment variables (e.g. `BINDEX_RPC_USER`).
+fn merge_config_args(
+    mut argv: Vec<std::ffi::OsString>,
+    config: Config,
+    is_env_set: impl Fn(&std::ffi::OsStr) -> bool,
+) -> Vec<std::ffi::OsString> {
+    let command = Args::command();
+    let given: HashSet<&str> = argv
+        .iter()
+        .skip(1)
+        .filter_map(|arg| arg.to_str())
+        .filter_map(|arg| match arg.strip_prefix("--") {
+            Some(long) => long.split('=').next(),
+            None => {
+                let short = arg.strip_prefix('-')?.chars().next()?;
+                command
+                    .get_arguments()
+                    .find(|arg| arg.get_short() == Some(short))?
+                    .get_long()
+            }
+        })
+        .collect();
+    let is_set = |arg: &clap::Arg| {
+        arg.get_long().is_some_and(|long| given.contains(long))
+            || arg.get_env().is_some_and(&is_env_set)
+    };
+    let mut config_argv = vec![];
+    for (option, value) in config.into_args() {
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(option))
+            .expect("unknown config option");
+        let conflicts = |a: &clap::Arg, b: &clap::Arg| {
+            command
+                .get_arg_conflicts_with(a)
+                .iter()
+                .any(|c| c.get_id() == b.get_id())
+        };
+        let conflicting = command
+            .get_arguments()
+            .filter(|other| is_set(other))
+            .any(|other| conflicts(arg, other) || conflicts(other, arg));
+        if is_set(arg) || conflicting {
+            continue;
+        }
+        config_argv.push(format!("--{}", option).into());
+        config_argv.push(value);
+    }
+    let rest = argv.split_off(argv.len().min(1));
+    argv.extend(config_argv);
+    argv.extend(rest);
+    argv
+}
+
+impl Command {
+    /// Commands that only query the existing index (so they can run alongside a syncing process)
+    fn is_read_only(&self) -> bool {
+        match self {
+            Command::Query { .. }
+            | Command::Balance { .. }
+            | Command::AssertBalance { .. }
+            | Command::Tip
+            | Command::Tx { .. }
+            | Command::Block { .. }
+            | Command::ExportUtxos { .. }
+            | Command::ListTransactions { .. }
+            | Command::ExportLedger { .. }
+            | Command::Forget { .. }
+            | Command::Rewards { .. }
+            | Command::Dump { .. }
+            | Command::Verify { .. } => true,
+            Command::Bench { .. }
+            | Command::Rescan { .. }
+            | Command::Load { .. }
+            | Command::Serve { .. } => false,
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches =
+        Args::command().get_matches_from(with_config_args(std::env::args_os().collect())?);
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let mut logger = env_logger::builder();
+    if args.quiet || args.check {
+        logger.filter_level(LevelFilter::Warn);
+    }
+    logger.format_timestamp_micros().init();
+    redact::enable(args.redact);
+    let default_network =
+        matches.value_source("network") == Some(clap::parser::ValueSource::DefaultValue);
+    if let Some(url) = args.rpc_url.first().filter(|_| default_network) {
+        match address::node_network(url.clone()) {
+            Ok(network) => {
+                args.network = network.try_into()?;
+                info!("detected {} node", network);
+            }
+            Err(e) => warn!("failed to detect the node's network: {}", e),
+        }
+    }
+    for channel in &args.notify {
+        channel.check_network(args.network.into())?;
+    }
+    let (url, rpc_fallback_urls) = match args.rpc_url.split_first() {
+        Some((url, fallbacks)) => (url.clone(), fallbacks.to_vec()),
+        None => (args.network.default_rpc_url(), vec![]),
+    };
+    let rpc_auth = match (&args.rpc_user, &args.rpc_pass, &args.rpc_cookie) {
+        (Some(user), Some(pass), _) => Some(bindex::Auth::UserPass(user.clone(), pass.clone())),
+        (_, _, Some(path)) => Some(bindex::Auth::Cookie(path.clone())),
+        _ => None,
+    };
+    let db_path = args.db_dir.join(args.network.db_dir());
+    info!("index DB: {:?}, node URL: {}", db_path, url);
+    if let Some(Command::Load { input }) = &args.command {
+        let file = std::io::BufReader::new(std::fs::File::open(input)?);
+        let stats = address::load(&db_path, file, args.mem_budget.map(|mb| mb << 20))?;
+        info!(
+            "loaded {} headers and {} rows from {:?}",
+            stats.headers, stats.rows, input
+        );
+        return Ok(());
+    }
+
+    let mut watch = load_scripts(&args)?;
+    let files: Vec<_> = (args.address_file.iter())
+        .chain(args.wallet.iter().map(|(_name, path)| path))
+        .chain(&args.core_wallet)
+        .collect();
+    if !files.is_empty() {
+        info!(
+            "watching {} addresses from {:?}",
+            watch.scripts.len(),
+            files
+        );
+    }
+
+    let options = address::Options {
+        headers_only: args.headers_only,
+        mem_budget: args.mem_budget.map(|mb| mb << 20),
+        rpc_rate_limit: args.rpc_rate_limit,
+        rpc_connect_timeout: args.rpc_connect_timeout.map(std::time::Duration::from_secs),
+        rpc_read_timeout: args.rpc_read_timeout.map(std::time::Duration::from_secs),
+        rpc_retries: args.rpc_retries,
+        rpc_auth,
+        rpc_fallback_urls,
+        read_only: args.command.as_ref().is_some_and(Command::is_read_only),
+        filters: args.filters,
+        network: Some(args.network.into()),
+    };
+    let sync_limit = options.sync_limit();
+    let mut index = address::Index::open_with(&db_path, url.clone(), options.clone())?;
+    index.set_max_height(args.sync_to_height);
+    // read-only commands don't write to the DB (so they can be killed at any time)
+    if !options.read_only {
+        handle_shutdown(&mut index);
+    }
+    match index.node_prune_height() {
+        Ok(Some(prune_height)) => warn!(
+            "node is pruned (below height={}): new blocks must be indexed before they are pruned, \
+            and transactions from pruned blocks are fetched via getrawtransaction (requiring -txindex) \
+            unless they are cached (using --cache-file)",
+            prune_height
+        ),
+        Ok(None) => (),
+        Err(e) => warn!("failed to check whether the node is pruned: {}", e),
+    }
+    if let Some(addr) = args.metrics {
+        let listener = std::net::TcpListener::bind(addr)?;
+        info!("serving metrics on http://{}/metrics", addr);
+        let metrics = std::sync::Arc::new(bindex::metrics::Metrics::default());
+        index.set_metrics(metrics.clone());
+        thread::spawn(move || {
+            if let Err(e) = bindex::metrics::serve(&metrics, listener) {
+                error!("metrics server failed: {}", e);
+            }
+        });
+    }
+    if let Some(gap_limit) = args.gap_limit {
+        let added = watch.extend_to_gap_limit(&index, gap_limit)?;
+        if added > 0 {
+            info!("derived {} more scripts (gap limit: {})", added, gap_limit);
+        }
+    }
+    let mut cache = open_cache(&args)?;
+
+    match &args.command {
+        None if args.diff || args.quiet => {
+            if !sync_to_tip(&mut index, sync_limit)? {
+                return Ok(());
+            }
+            let cache = cache.as_mut().expect("--diff requires --cache-file");
+            let status = watch_status(&watch, &index, Some(cache), &args)?;
+            let stats = sync_sqlite(&status, cache)?;
+            let new_txids: HashSet<String> = stats
+                .new_txids
+                .iter()
+                .map(|txid| txid.to_string())
+                .collect();
+            let mut rows = status.rows;
+            rows.retain(|row| new_txids.contains(&row.txid));
+            if rows.is_empty() {
+                return Ok(());
+            }
+            if !args.quiet {
+                print_history(rows, &index, &args);
+            }
+            std::process::exit(2);
+        }
+        None if args.check => {
+            let (state, output) = match check(&args, &watch, &mut index, sync_limit, cache) {
+                Ok(res) => res,
+                Err(e) => (CheckState::Unknown, e.to_string()),
+            };
+            println!("BINDEX {} - {}", state.label(), output);
+            std::process::exit(state as i32);
+        }
+        None if args.dry_run => {
+            if !sync_to_tip(&mut index, sync_limit)? {
+                return Ok(());
+            }
+            let cache = cache.as_mut().expect("--dry-run requires --cache-file");
+            let status = watch_status(&watch, &index, Some(cache), &args)?;
+            let stats = sync_sqlite(&status, cache)?;
+            println!(
+                "history rows: {} would be inserted, {} marked stale",
+                stats.inserted, stats.pruned
+            );
+            println!(
+                "txcache rows: {} would be inserted, {} pruned",
+                stats.inserted_txs, stats.pruned_txs
+            );
+            Ok(())
+        }
+        None => {
+            if !args.once && !args.daemon {
+                info!("running until interrupted (use --once to exit after the report)");
+            }
+            run(&args, &watch, &mut index, sync_limit, cache)
+        }
+        Some(Command::Bench { from, to }) => Ok(bench(&watch.scripts, &index, *from, *to)?),
+        Some(Command::Query { addresses }) => {
+            let network = args.network.into();
+            let scripts: HashSet<_> = addresses
+                .iter()
+                .map(|addr| addr.clone().require_network(network))
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .map(|addr| addr.script_pubkey())
+                .collect();
+            let watch = WatchList::new(scripts);
+            let status = watch_status(&watch, &index, None, &args)?;
+            let mut summary = format!(
+                "balance: {}, UTXOs: {}",
+                status.balance,
+                status.unspent.len()
+            );
+            if let Some(min_conf) = args.min_conf {
+                let trusted = status.trusted_balance(index.trusted_height(min_conf));
+                summary += &format!(", trusted: {}", trusted);
+            }
+            print_status(status, &index, &args);
+            println!("{}", summary);
+            Ok(())
+        }
+        Some(Command::Balance { addresses }) => {
+            let network = args.network.into();
+            let addresses: Vec<_> = addresses
+                .iter()
+                .map(|addr| addr.clone().require_network(network))
+                .collect::<Result<_, _>>()?;
+            let watch = WatchList::new(addresses.iter().map(|addr| addr.script_pubkey()).collect());
+            let status = watch_status(&watch, &index, None, &args)?;
+            let trusted_height = args.min_conf.map(|n| index.trusted_height(n));
+            // (balance, trusted balance) of each address
+            let mut balances =
+                HashMap::<&bitcoin::Script, (bitcoin::Amount, bitcoin::Amount)>::new();
+            for utxo in status.unspent.values() {
+                let balance = balances.entry(utxo.script).or_default();
+                balance.0 += utxo.value;
+                if trusted_height.is_some_and(|h| h.is_some_and(|h| utxo.height <= h)) {
+                    balance.1 += utxo.value;
+                }
+            }
+            let format_trusted = |trusted: bitcoin::Amount| match trusted_height {
+                Some(_) => format!(" (trusted: {})", trusted),
+                None => String::new(),
+            };
+            for addr in &addresses {
+                let (balance, trusted) = balances
+                    .get(addr.script_pubkey().as_script())
+                    .copied()
+                    .unwrap_or_default();
+                println!("{}: {}{}", addr, balance, format_trusted(trusted));
+            }
+            let trusted = status.trusted_balance(trusted_height.flatten());
+            println!("total: {}{}", status.balance, format_trusted(trusted));
+            Ok(())
+        }
+        Some(Command::AssertBalance {
+            address,
+            amount,
+            tolerance,
+            min_conf,
+        }) => {
+            let address = address.clone().require_network(args.network.into())?;
+            let watch = WatchList::new(HashSet::from([address.script_pubkey()]));
+            let status = watch_status(&watch, &index, None, &args)?;
+            let balance = status.trusted_balance(index.trusted_height(*min_conf));
+            let diff = if balance > *amount {
+                balance - *amount
+            } else {
+                *amount - balance
+            };
+            if diff > *tolerance {
+                error!(
+                    "balance mismatch: {} != {} (min-conf={}, tolerance={})",
+                    balance, amount, min_conf, tolerance
+                );
+                std::process::exit(1);
+            }
+            info!("balance matches: {}", balance);
+            Ok(())
+        }
+        Some(Command::Tip) => Ok(print_tip(&index, args.plain)?),
+        Some(Command::ExportUtxos { format, height }) => {
+            let status = watch_status(&watch, &index, None, &args)?;
+            let min_conf = args.min_conf.unwrap_or(1);
+            export_utxos(
+                &status,
+                &index,
+                args.network.into(),
+                *format,
+                min_conf,
+                *height,
+            )
+        }
+        Some(Command::ListTransactions { count }) => {
+            let status = watch_status(&watch, &index, None, &args)?;
+            list_transactions(&status, &index, &watch.scripts, args.network.into(), *count)
+        }
+        Some(Command::ExportLedger {
+            format,
+            account,
+            income_account,
+            expenses_account,
+        }) => {
+            let status = watch_status(&watch, &index, None, &args)?;
+            let accounts = LedgerAccounts {
+                wallet: account,
+                income: income_account,
+                expenses: expenses_account,
+            };
+            export_ledger(&status, *format, &accounts);
+            Ok(())
+        }
+        Some(Command::Rewards { period }) => {
+            let status = watch_status(&watch, &index, None, &args)?;
+            print_rewards(&status, &index, args.network.into(), *period, args.plain)
+        }
+        Some(Command::Block { block }) => {
+            print_block(block, &watch.scripts, &index, args.network.into())
+        }
+        Some(Command::Tx { txid, expand }) => print_tx(
+            *txid,
+            &watch.scripts,
+            &index,
+            cache.as_ref(),
+            args.network.into(),
+            *expand,
+        ),
+        Some(Command::Rescan { from, to }) => {
+            let to = to.or(index.tip_height()).unwrap_or_default();
+            let scripts: Vec<_> = watch
+                .scripts
+                .iter()
+                .map(|script| {
+                    let birthday = watch.birthdays.get(script).copied().unwrap_or_default();
+                    (script.clone(), birthday)
+                })
+                .collect();
+            let stats = index.rescan(*from..=to, &scripts)?;
+            println!(
+                "rescanned {}..={}: {} blocks, {} matching rows, {} restored",
+                from, to, stats.blocks, stats.matched, stats.restored
+            );
+            Ok(())
+        }
+        Some(Command::Forget { addresses }) => {
+            let network = args.network.into();
+            let scripts: HashSet<_> = addresses
+                .iter()
+                .map(|addr| addr.clone().require_network(network))
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .map(|addr| addr.script_pubkey())
+                .collect();
+            if !args.dry_run {
+                for path in &args.address_file {
+                    let removed = forget_addresses(path, &scripts, network)?;
+                    info!("removed {} entries from {:?}", removed, path);
+                }
+                let watch = load_scripts(&args)?;
+                for script in scripts.iter().filter(|s| watch.scripts.contains(*s)) {
+                    let addr = bitcoin::Address::from_script(script, network)?;
+                    warn!(
+                        "{} is still watched (derived from a descriptor?)",
+                        Redacted(&addr)
+                    );
+                }
+            }
+            if let Some(cache) = cache.as_mut() {
+                let scripts: Vec<_> = scripts.iter().map(|s| s.as_script()).collect();
+                let (rows, txs) = cache.forget(&scripts)?;
+                info!(
+                    "deleted {} history rows and {} transactions from cache",
+                    rows, txs
+                );
+            }
+            Ok(())
+        }
+        Some(Command::Serve {
+            listen,
+            #[cfg(feature = "http")]
+            http,
+        }) => {
+            if !sync_to_tip(&mut index, sync_limit)? {
+                return Ok(());
+            }
+            let listener = std::net::TcpListener::bind(listen)?;
+            info!("serving Electrum clients on {}", listen);
+            #[cfg(feature = "http")]
+            let http_listener = http.map(std::net::TcpListener::bind).transpose()?;
+            // the events are received only by the HTTP server (so they don't pile up otherwise)
+            #[cfg(feature = "http")]
+            let events = (http.is_some() && !watch.scripts.is_empty())
+                .then(|| index.subscribe(watch.scripts.iter().cloned()));
+            #[cfg(feature = "http")]
+            if let Some(listener) = &http_listener {
+                info!("serving HTTP clients on {}", listener.local_addr()?);
+            }
+            let index = std::sync::Mutex::new(index);
+            let server = electrum::Server::new(&index);
+            // nothing may fail within the scope, since it waits for the servers' threads
+            thread::scope(|s| -> Result<(), Box<dyn std::error::Error>> {
+                s.spawn(|| {
+                    if let Err(e) = server.serve(listener) {
+                        error!("Electrum server failed: {}", e);
+                    }
+                });
+                #[cfg(feature = "http")]
+                if let Some(listener) = http_listener {
+                    let index = &index;
+                    let network = args.network.into();
+                    s.spawn(move || {
+                        if let Err(e) = bindex::http::serve(index, listener, network, events) {
+                            error!("HTTP server failed: {}", e);
+                        }
+                    });
+                }
+                let notifications = block_notifications(&args);
+                loop {
+                    wait_for_block(notifications.as_ref());
+                    // the clients are blocked only while a batch is indexed
+                    let mut indexed = false;
+                    loop {
+                        match index.lock().unwrap().sync(sync_limit) {
+                            Ok(stats) if stats.indexed_blocks > 0 => indexed = true,
+                            Ok(_) => break,
+                            // the servers keep running, so the sync is retried on the next block
+                            Err(e) => {
+                                error!("sync failed: {}", e);
+                                break;
+                            }
+                        }
+                    }
+                    if indexed {
+                        server.notify();
+                    }
+                    if shutdown_requested() {
+                        // the server threads can't be joined, but the indexed blocks are flushed
+                        info!("shutting down");
+                        std::process::exit(0);
+                    }
+                }
+            })
+        }
+        Some(Command::Dump { output }) => {
+            let file = std::io::BufWriter::new(std::fs::File::create(output)?);
+            let stats = index.dump(file)?;
+            info!(
+                "dumped {} headers and {} rows to {:?}",
+                stats.headers, stats.rows, output
+            );
+            Ok(())
+        }
+        Some(Command::Load { .. }) => unreachable!("handled before opening the index"),
+        Some(Command::Verify {
+            samples,
+            against_node,
+        }) => {
+            let mut corruptions = index.verify_headers();
+            info!(
+                "verified {} headers",
+                index.tip_height().map_or(0, |h| h + 1)
+            );
+            if let Some(tip_height) = index.tip_height() {
+                for _ in 0..*samples {
+                    let height = usize::try_from(random_u64() % (tip_height as u64 + 1)).unwrap();
+                    match index.audit(height, random_u64()) {
+                        Ok(found) => corruptions.extend(found),
+                        // e.g. the sampled block may be pruned by the node
+                        Err(e) => warn!("failed to audit block at height={}: {}", height, e),
+                    }
+                }
+                info!("audited {} sampled blocks", samples);
+            }
+            corruptions
+                .iter()
+                .for_each(|corruption| error!("index corruption: {}", corruption));
+            let mut ok = corruptions.is_empty();
+            if *against_node {
+                ok &= verify_against_node(&watch, &index)?;
+            }
+            if !ok {
+                error!("index verification failed");
+                std::process::exit(1);
+            }
+            info!("index is consistent");
+            Ok(())
+        }
+    }
+}
+
+/// The cache passphrase (from `--cache-key-file`, `--cache-key` or `--cache-key-prompt`).
+/// It is read once, since the cache may be opened more than once.
+fn cache_key(args: &Args) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    static KEY: OnceLock<Option<String>> = OnceLock::new();
+    if let Some(key) = KEY.get() {
+        return Ok(key.clone());
+    }
+    let key = if let Some(key_file) = &args.cache_key_file {
+        let key = std::fs::read_to_string(key_file)?;
+        Some(key.trim_end_matches(['\r', '\n']).to_owned())
+    } else if args.cache_key_prompt {
+        Some(read_passphrase("cache passphrase: ")?)
+    } else {
+        args.cache_key.clone()
+    };
+    Ok(KEY.get_or_init(|| key).clone())
+}
+
+/// Read a line from stdin (without echoing it, if it is a terminal).
+fn read_passphrase(prompt: &str) -> Result<String, Box<dyn std::error::Error>> {
+    use std::io::IsTerminal;
+
+    let terminal = std::io::stdin().is_terminal();
+    let stty = |arg| std::process::Command::new("stty").arg(arg).status();
+    eprint!("{}", prompt);
+    if terminal {
+        stty("-echo")?;
+    }
+    let mut line = String::new();
+    let result = std::io::stdin().read_line(&mut line);
+    if terminal {
+        stty("echo")?;
+        eprintln!();
+    }
+    result?;
+    let key = line.trim_end_matches(['\r', '\n']);
+    if key.is_empty() {
+        return Err("empty cache passphrase".into());
+    }
+    Ok(key.to_owned())
+}
+
+fn open_cache(args: &Args) -> Result<Option<Cache>, Box<dyn std::error::Error>> {
+    let mut cache = match &args.cache_file {
+        Some(path) => match cache_key(args)? {
+            Some(key) => Cache::open_encrypted(path, &key)?,
+            None => Cache::open(path)?,
+        },
+        None => return Ok(None),
+    };
+    cache.set_dry_run(args.dry_run);
+    cache.set_max_tx_bytes(args.cache_max_tx_mb.map(|mb| mb << 20));
+    Ok(Some(cache))
+}
+
+/// Query the watched scripts' history (so that the DB blocks needed by `Status::create` are warm),
+/// returning the positions of the transactions missing from the cache.
+fn prefetch_positions(
+    watch: &WatchList,
+    index: &address::Index,
+    cache: &Cache,
+) -> Result<Vec<address::TxPosition>, Box<dyn std::error::Error>> {
+    let t = std::time::Instant::now();
+    let mut txs = 0;
+    let mut positions = vec![];
+    for script in &watch.scripts {
+        let birthday = watch.birthdays.get(script).copied().unwrap_or_default();
+        for loc in find(script, birthday, index, Some(cache))? {
+            txs += 1;
+            if cache.get_tx_bytes(&loc)?.is_none() {
+                positions.push(address::TxPosition {
+                    height: loc.height,
+                    offset: loc.offset,
+                    block_hash: loc.indexed_header.hash(),
+                });
+            }
+        }
+    }
+    info!(
+        "prefetched {} addresses: {} txs, {} missing from cache ({:?})",
+        watch.scripts.len(),
+        txs,
+        positions.len(),
+        t.elapsed()
+    );
+    Ok(positions)
+}
+
+/// Fetch the transactions (while the index is syncing), sending them to the cache writer thread.
+fn prefetch_txs(
+    fetcher: &address::Fetcher,
+    positions: &[address::TxPosition],
+    updates: &std::sync::mpsc::Sender<CacheUpdate>,
+) -> Result<(), address::Error> {
+    let t = std::time::Instant::now();
+    for chunk in positions.chunks(PREFETCH_CHUNK_SIZE) {
+        if shutdown_requested() {
+            return Ok(());
+        }
+        let mut writes = cache::Writes::default();
+        for pos in chunk {
+            writes.add_tx_bytes(pos.block_hash, pos.offset, fetcher.get_tx_bytes(pos)?);
+        }
+        if updates.send(CacheUpdate::Writes(writes)).is_err() {
+            return Ok(()); // the cache writer thread has stopped
+        }
+    }
+    info!(
+        "prefetched {} txs into cache ({:?})",
+        positions.len(),
+        t.elapsed()
+    );
+    Ok(())
+}
+
+/// User and system CPU time used by this process (Linux only).
+fn cpu_time() -> Option<std::time::Duration> {
+    parse_cpu_time(&std::fs::read_to_string("/proc/self/stat").ok()?)
+}
+
+/// Parse the `utime` and `stime` fields of a `/proc/<pid>/stat` line.
+fn parse_cpu_time(stat: &str) -> Option<std::time::Duration> {
+    // skip the command name, which may contain spaces
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    // `/proc` reports times in USER_HZ (100 on all Linux platforms) clock ticks
+    Some(std::time::Duration::from_millis((utime + stime) * 10))
+}
+
+/// Index all available blocks, logging a throughput summary.
+/// Returns `false` if interrupted by a shutdown request (after writing the indexed blocks).
+fn sync_to_tip(index: &mut address::Index, sync_limit: usize) -> Result<bool, address::Error> {
+    let t = std::time::Instant::now();
+    let cpu_start = cpu_time();
+    let size_start = index.db_size();
+    let (mut blocks, mut bytes) = (0, 0);
+    loop {
+        let stats = index.sync(sync_limit)?;
+        if stats.cancelled {
+            info!(
+                "sync interrupted (indexed {} blocks)",
+                blocks + stats.indexed_blocks
+            );
+            return Ok(false);
+        }
+        if stats.indexed_blocks == 0 {
+            break;
+        }
+        blocks += stats.indexed_blocks;
+        bytes += stats.size_read;
+    }
+    if blocks == 0 {
+        return Ok(true);
+    }
+    let wall = t.elapsed().as_secs_f64();
+    let cpu = cpu_time()
+        .zip(cpu_start)
+        .map_or(f64::NAN, |(end, start)| (end - start).as_secs_f64());
+    info!(
+        "sync summary: blocks={} read_MB={:.3} wall_s={:.3} cpu_s={:.3} blocks_per_s={:.3} db_written_MB={:.3}",
+        blocks,
+        bytes as f64 / 1e6,
+        wall,
+        cpu,
+        blocks as f64 / wall,
+        index.db_size().saturating_sub(size_start) as f64 / 1e6,
+    );
+    Ok(true)
+}
+
+/// Nagios plugin states (the discriminant is the exit status).
+#[derive(Clone, Copy)]
+enum CheckState {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+impl CheckState {
+    fn label(self) -> &'static str {
+        match self {
+            CheckState::Ok => "OK",
+            CheckState::Warning => "WARNING",
+            CheckState::Critical => "CRITICAL",
+            CheckState::Unknown => "UNKNOWN",
+        }
+    }
+}
+
+/// Sync the index (and cache), returning the check's state and output (with perfdata).
+fn check(
+    args: &Args,
+    watch: &WatchList,
+    index: &mut address::Index,
+    sync_limit: usize,
+    mut cache: Option<Cache>,
+) -> Result<(CheckState, String), Box<dyn std::error::Error>> {
+    if !sync_to_tip(index, sync_limit)? {
+        return Ok((CheckState::Unknown, "interrupted".to_owned()));
+    }
+    let status = watch_status(watch, index, cache.as_ref(), args)?;
+    let new_txs = match cache.as_mut() {
+        Some(cache) => sync_sqlite(&status, cache)?.new_txids.len(),
+        None => 0,
+    };
+    let tip = index
+        .tip_height()
+        .and_then(|height| index.get_header(height).map(|header| (height, header)));
+    let Some((height, header)) = tip else {
+        return Ok((CheckState::Unknown, "index is empty".to_owned()));
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let lag = now.as_secs().saturating_sub(header.time.into());
+
+    let mut state = CheckState::Ok;
+    if lag > args.check_crit_lag {
+        state = CheckState::Critical;
+    } else if lag > args.check_warn_lag || new_txs > 0 {
+        state = CheckState::Warning;
+    }
+    let output = format!(
+        "balance {}, tip {} is {}s old, {} new txs | balance={};;;; tip_lag={}s;{};{};0; new_txs={};;;0;",
+        status.balance,
+        height,
+        lag,
+        new_txs,
+        status.balance.to_btc(),
+        lag,
+        args.check_warn_lag,
+        args.check_crit_lag,
+        new_txs
+    );
+    Ok((state, output))
+}
+
+/// State of the `--alert-*` rules (so each condition is reported once).
+#[derive(Default)]
+struct BalanceAlerts {
+    below: bool,
+    /// The reported transfers (`None` before the first check, since the existing history
+    /// is not reported)
+    received: Option<HashSet<(bitcoin::Txid, bitcoin::ScriptBuf)>>,
+}
+
+impl BalanceAlerts {
+    /// Returns the new alerts (to be sent to the `--notify` channels).
+    fn check(&mut self, status: &Status, args: &Args) -> Vec<String> {
+        let mut alerts = vec![];
+        if let Some(threshold) = args.alert_balance_below {
+            let below = status.balance < threshold.to_signed().expect("threshold overflow");
+            if below && !self.below {
+                warn!("balance {} dropped below {}", status.balance, threshold);
+                alerts.push(format!(
+                    "balance {} dropped below {}",
+                    status.balance, threshold
+                ));
+            }
+            self.below = below;
+        }
+        if let Some(threshold) = args.alert_receive_above {
+            let received: HashSet<_> = status
+                .transfers
+                .iter()
+                .filter(|transfer| transfer.delta.to_unsigned().unwrap_or_default() > threshold)
+                .map(|transfer| (transfer.txid, transfer.script.to_owned()))
+                .collect();
+            if let Some(reported) = self.received.as_mut() {
+                // a reorg may confirm a transfer below the previous tip
+                for transfer in status.transfers.iter().filter(|transfer| {
+                    let key = (transfer.txid, transfer.script.to_owned());
+                    received.contains(&key) && !reported.contains(&key)
+                }) {
+                    let received = transfer.delta.to_unsigned().unwrap_or_default();
+                    warn!(
+                        "{} received {} (above {}) at height={}",
+                        Redacted(&transfer.txid),
+                        received,
+                        threshold,
+                        transfer.height
+                    );
+                    alerts.push(format!(
+                        "{} received {} (above {}) at height={}",
+                        transfer.txid, received, threshold, transfer.height
+                    ));
+                }
+            }
+            self.received = Some(received);
+        }
+        alerts
+    }
+}
+
+/// Sent to the notifications' thread
+enum Delivery {
+    Transfers(Vec<notify::Notification>),
+    Alerts(Vec<String>),
+}
+
+/// Sends the new transfers and the alerts to the `--notify` channels (in a background thread,
+/// so slow channels don't delay indexing).
+struct Notifier {
+    sender: Option<std::sync::mpsc::Sender<Delivery>>,
+    /// The reported transfers (`None` before the first check, since the existing history
+    /// is not reported)
+    reported: Option<HashSet<(bitcoin::Txid, bitcoin::ScriptBuf)>>,
+}
+
+impl Notifier {
+    fn new(channels: &[notify::Channel]) -> Self {
+        let sender = (!channels.is_empty()).then(|| {
+            let channels = channels.to_vec();
+            let (tx, rx) = std::sync::mpsc::channel::<Delivery>();
+            thread::spawn(move || {
+                for delivery in rx {
+                    match delivery {
+                        Delivery::Transfers(notifications) => {
+                            notify::send_all(&channels, &notifications)
+                        }
+                        Delivery::Alerts(alerts) => notify::alert_all(&channels, &alerts),
+                    }
+                }
+            });
+            tx
+        });
+        Notifier {
+            sender,
+            reported: None,
+        }
+    }
+
+    fn check(&mut self, status: &Status, network: bitcoin::Network) {
+        if self.sender.is_none() {
+            return;
+        }
+        let transfers: HashSet<_> = status
+            .transfers
+            .iter()
+            .map(|transfer| (transfer.txid, transfer.script.to_owned()))
+            .collect();
+        if let Some(reported) = &self.reported {
+            // a reorg may confirm a transfer below the previous tip
+            let notifications: Vec<_> = status
+                .transfers
+                .iter()
+                .filter(|transfer| !reported.contains(&(transfer.txid, transfer.script.to_owned())))
+                .map(|transfer| notify::Notification {
+                    txid: transfer.txid,
+                    address: bitcoin::Address::from_script(transfer.script, network)
+                        .map_or_else(|_| transfer.script.to_hex_string(), |a| a.to_string()),
+                    script: transfer.script.to_owned(),
+                    delta: transfer.delta,
+                    height: transfer.height,
+                })
+                .collect();
+            if !notifications.is_empty() {
+                self.deliver(Delivery::Transfers(notifications));
+            }
+        }
+        self.reported = Some(transfers);
+    }
+
+    fn alert(&self, alerts: Vec<String>) {
+        if !alerts.is_empty() {
+            self.deliver(Delivery::Alerts(alerts));
+        }
+    }
+
+    fn deliver(&self, delivery: Delivery) {
+        if let Some(sender) = &self.sender {
+            if sender.send(delivery).is_err() {
+                warn!("notifications thread failed");
+            }
+        }
+    }
+}
+
+fn run(
+    args: &Args,
+    watch: &WatchList,
+    index: &mut address::Index,
+    sync_limit: usize,
+    cache: Option<Cache>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // the cache is written only by a separate thread (using its own connection),
+    // so a slow cache disk doesn't delay indexing and notifications
+    let writer = match cache {
+        Some(_) => open_cache(args)?,
+        None => None,
+    };
+    let mut cache = cache;
+    if let Some(cache) = cache.as_mut() {
+        cache.defer_writes();
+    }
+    thread::scope(|s| {
+        let updates = writer.map(|writer| {
+            let (tx, rx) = std::sync::mpsc::channel();
+            s.spawn(move || {
+                if let Err(e) = sync_sqlite_thread(rx, writer) {
+                    error!("cache sync failed: {}", e);
+                }
+            });
+            tx
+        });
+        if let Some((updates, cache)) = updates.as_ref().zip(cache.as_ref()) {
+            if args.prefetch {
+                // the transactions are fetched without borrowing the index (which is syncing)
+                let positions = prefetch_positions(watch, index, cache)?;
+                let fetcher = index.fetcher();
+                let updates = updates.clone();
+                s.spawn(move || {
+                    if let Err(e) = prefetch_txs(&fetcher, &positions, &updates) {
+                        warn!("prefetch failed: {}", e);
+                    }
+                });
+            }
+        }
+        run_loop(args, watch, index, sync_limit, cache, updates)
+    })
+}
+
+fn run_loop(
+    args: &Args,
+    watch: &WatchList,
+    index: &mut address::Index,
+    sync_limit: usize,
+    cache: Option<Cache>,
+    updates: Option<std::sync::mpsc::Sender<CacheUpdate>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut updated = true;
+    let mut alerts = vec![];
+    let mut last_check = None;
+    let mut last_tip_check = std::time::Instant::now();
+    let mut balance_alerts = BalanceAlerts::default();
+    let mut notifier = Notifier::new(&args.notify);
+    let mut reloaded: Option<WatchList> = None;
+    let mut last_audit = std::time::Instant::now();
+    let notifications = block_notifications(args);
+    handle_sigusr1();
+    if !sync_to_tip(index, sync_limit)? {
+        return Ok(());
+    }
+    loop {
+        while index.sync(sync_limit)?.indexed_blocks > 0 {
+            updated = true;
+        }
+        if shutdown_requested() {
+            info!("shutting down");
+            return Ok(());
+        }
+        if REPORT_REQUESTED.swap(false, Ordering::Relaxed) {
+            info!("SIGUSR1 received, re-printing the report");
+            if args.reload_on_sigusr1 {
+                match load_scripts(args) {
+                    Ok(new_watch) => {
+                        info!("watching {} addresses", new_watch.scripts.len());
+                        reloaded = Some(new_watch);
+                    }
+                    Err(e) => warn!("failed to reload the address files: {}", e),
+                }
+            }
+            updated = true;
+        }
+        if let Some(gap_limit) = args.gap_limit.filter(|_| updated) {
+            let current = reloaded.as_ref().unwrap_or(watch);
+            if current.needs_more_scripts(index, gap_limit)? {
+                let mut extended = current.clone();
+                let added = extended.extend_to_gap_limit(index, gap_limit)?;
+                info!("derived {} more scripts (gap limit: {})", added, gap_limit);
+                reloaded = Some(extended);
+            }
+        }
+        let watch = reloaded.as_ref().unwrap_or(watch);
+        if updated {
+            // only the merged history can be written before the status is complete
+            let rows_output = if args.deposits
+                || args.group_by_address
+                || args.history_limit == 0
+                || !watch.wallets.is_empty()
+            {
+                RowsOutput::Keep
+            } else {
+                RowsOutput::Stream(RowWriter::new(args))
+            };
+            let mut status = watch_status_with(watch, index, cache.as_ref(), args, rows_output)?;
+            let update = updates.as_ref().zip(status.tip).map(|(updates, tip)| {
+                let history: Vec<_> = std::mem::take(&mut status.history)
+                    .into_iter()
+                    .map(|(script, entries)| (script.to_owned(), entries))
+                    .collect();
+                (updates, history, tip)
+            });
+            if let Some(min_conf) = args.min_conf {
+                let trusted = status.trusted_balance(index.trusted_height(min_conf));
+                info!("trusted balance ({}+ confirmations): {}", min_conf, trusted);
+            }
+            notifier.alert(balance_alerts.check(&status, args));
+            notifier.check(&status, args.network.into());
+            match cache.as_ref().filter(|_| args.deposits) {
+                Some(cache) => print_deposits(&status, index, cache, args)?,
+                None if !watch.wallets.is_empty() => {
+                    let total = WalletRow::new("(total)", &status);
+                    print_status(status, index, args);
+                    print_wallets(watch, index, cache.as_ref(), args, total)?;
+                }
+                None => print_status(status, index, args),
+            }
+            // sent after reporting, so that the reported deposits are written too
+            if let Some((updates, history, tip)) = update {
+                let writes = cache.as_ref().map(Cache::take_writes).unwrap_or_default();
+                updates
+                    .send(CacheUpdate::History((history, tip, writes)))
+                    .map_err(|_| "cache sync thread failed")?;
+            }
+            updated = false;
+        }
+        if args.once {
+            return Ok(());
+        }
+        if let Some(max_tip_age) = args.max_tip_age {
+            if last_check.is_none_or(|t: std::time::Instant| t.elapsed() >= NODE_CHECK_PERIOD) {
+                last_check = Some(std::time::Instant::now());
+                let new_alerts = index.check_node(std::time::Duration::from_secs(max_tip_age))?;
+                if new_alerts != alerts {
+                    new_alerts.iter().for_each(|alert| warn!("{}", alert));
+                    if new_alerts.is_empty() {
+                        info!("node is healthy");
+                    }
+                    notifier.alert(new_alerts.iter().map(ToString::to_string).collect());
+                    alerts = new_alerts;
+                }
+            }
+        }
+        if last_tip_check.elapsed() >= NODE_CHECK_PERIOD {
+            last_tip_check = std::time::Instant::now();
+            // compare the indexed tip with the node's block at the same height
+            match index.check_tip() {
+                Ok(0) => (),
+                Ok(rolled_back) => {
+                    warn!("rolled back {} diverged blocks", rolled_back);
+                    updated = true;
+                    continue; // re-sync from the common ancestor
+                }
+                Err(e) => warn!("tip check failed: {}", e),
+            }
+        }
+        if let Some(interval) = args.audit_interval {
+            if last_audit.elapsed() >= std::time::Duration::from_secs(interval) {
+                last_audit = std::time::Instant::now();
+                // e.g. the sampled block may be pruned by the node
+                if let Err(e) = audit_sample(index) {
+                    warn!("audit failed: {}", e);
+                }
+            }
+        }
+        wait_for_block(notifications.as_ref());
+    }
+}
+
+/// Print the deposits that weren't reported before (as JSON lines), recording them in the cache.
+fn print_deposits(
+    status: &Status,
+    index: &address::Index,
+    cache: &Cache,
+    args: &Args,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let network: bitcoin::Network = args.network.into();
+    let tip_height = match index.tip_height() {
+        Some(height) => height,
+        None => return Ok(()),
+    };
+    let max_height = index.trusted_height(args.min_conf.unwrap_or(1));
+    let mut deposits: Vec<_> = status
+        .unspent
+        .iter()
+        .chain(
+            status
+                .spent
+                .iter()
+                .map(|(outpoint, utxo, _)| (outpoint, utxo)),
+        )
+        .filter(|(_, utxo)| !utxo.change && max_height.is_some_and(|h| utxo.height <= h))
+        .collect();
+    deposits.sort_unstable_by_key(|(outpoint, utxo)| (utxo.height, **outpoint));
+    let mut stdout = std::io::stdout().lock();
+    for (outpoint, utxo) in deposits {
+        if cache.has_deposit(outpoint)? {
+            continue;
+        }
+        let address = bitcoin::Address::from_script(utxo.script, network)
+            .map_or_else(|_| utxo.script.to_hex_string(), |addr| addr.to_string());
+        let deposit = serde_json::json!({
+            "address": address,
+            "txid": outpoint.txid,
+            "vout": outpoint.vout,
+            "amount": utxo.value.to_sat(),
+            "height": utxo.height,
+            "confirmations": tip_height + 1 - utxo.height,
+        });
+        // recorded after being printed (so it is reported at least once)
+        writeln!(stdout, "{}", deposit)?;
+        cache.add_deposit(outpoint)?;
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+fn random_u64() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    // each `RandomState` is randomly keyed
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish()
+}
+
+/// Verify a randomly sampled indexed block (and one of its transactions) against the node.
+fn audit_sample(index: &address::Index) -> Result<(), address::Error> {
+    let tip_height = match index.tip_height() {
+        Some(height) => height,
+        None => return Ok(()),
+    };
+    let height = usize::try_from(random_u64() % (tip_height as u64 + 1)).unwrap();
+    let corruptions = index.audit(height, random_u64())?;
+    if corruptions.is_empty() {
+        debug!("audited block at height={}", height);
+    }
+    corruptions
+        .iter()
+        .for_each(|corruption| error!("index corruption: {}", corruption));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_row_writer() {
+        let mut writer = RowWriter {
+            format: HistoryFormat::Csv,
+            fees: false,
+            shown: vec![true, true],
+            started: false,
+        };
+        let row = Row::dots();
+        assert_eq!(writer.lines(&row), ["txid,time", "...,..."]);
+        assert_eq!(writer.lines(&row), ["...,..."]);
+
+        writer.format = HistoryFormat::Json;
+        assert_eq!(writer.lines(&row), [r#"{"time":"...","txid":"..."}"#]);
+    }
+
+    fn config(text: &str) -> Config {
+        toml::from_str(text).unwrap()
+    }
+
+    fn merged(argv: &[&str], text: &str, env: &[&str]) -> Vec<String> {
+        let argv = argv.iter().map(Into::into).collect();
+        let env_set = |var: &std::ffi::OsStr| env.iter().any(|e| var == *e);
+        merge_config_args(argv, config(text), env_set)
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_config_into_args() {
+        let args = config(
+            r#"
+            network = "signet"
+            rpc_url = ["http://a:8332", "http://b:8332"]
+            watch = "bc1q..."
+            metrics = "127.0.0.1:9332"
+            "#,
+        )
+        .into_args();
+        let args: Vec<_> = args
+            .iter()
+            .map(|(option, value)| (*option, value.to_str().unwrap()))
+            .collect();
+        assert_eq!(
+            args,
+            [
+                ("network", "signet"),
+                ("rpc-url", "http://a:8332"),
+                ("rpc-url", "http://b:8332"),
+                ("watch", "bc1q..."),
+                ("metrics", "127.0.0.1:9332"),
+            ]
+        );
+        assert!(toml::from_str::<Config>("unknown = 1").is_err());
+    }
+
+    #[test]
+    fn test_merge_config_args() {
+        let text = r#"
+            rpc_url = "http://config:8332"
+            rpc_cookie = "/config/.cookie"
+            db_dir = "/config/db"
+        "#;
+        assert_eq!(
+            merged(&["bindex", "tip"], text, &[]),
+            [
+                "bindex",
+                "--rpc-url",
+                "http://config:8332",
+                "--db-dir",
+                "/config/db",
+                "--rpc-cookie",
+                "/config/.cookie",
+                "tip"
+            ]
+        );
+        // the command-line options and the environment variables take precedence
+        assert_eq!(
+            merged(&["bindex", "--db-dir=/cli/db"], text, &["BINDEX_RPC_URL"]),
+            [
+                "bindex",
+                "--rpc-cookie",
+                "/config/.cookie",
+                "--db-dir=/cli/db"
+            ]
+        );
+        // `--rpc-cookie` conflicts with `--rpc-user`
+        assert_eq!(
+            merged(&["bindex"], text, &["BINDEX_RPC_USER", "BINDEX_RPC_PASS"]),
+            [
+                "bindex",
+                "--rpc-url",
+                "http://config:8332",
+                "--db-dir",
+                "/config/db"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_sorted() {
+        assert_eq!(merge_sorted(vec![1, 3, 5], vec![2, 3, 6]), [1, 2, 3, 5, 6]);
+        assert_eq!(merge_sorted(vec![], vec![1, 2]), [1, 2]);
+        assert_eq!(merge_sorted(vec![1, 2], vec![]), [1, 2]);
+        assert!(merge_sorted::<u8>(vec![], vec![]).is_empty());
+    }
+
+    /// A tapscript leaf spend: `[signature, leaf script, control block]`
+    fn script_path_witness(leaf: &bitcoin::Script) -> bitcoin::Witness {
+        let mut control_block = vec![0xc0];
+        control_block.extend([1; 32]);
+        bitcoin::Witness::from_slice(&[vec![2; 64], leaf.to_bytes(), control_block])
+    }
+
+    #[test]
+    fn test_assets() {
+        use bitcoin::opcodes::{all::*, OP_FALSE};
+        let envelope = bitcoin::script::Builder::new()
+            .push_slice([1; 32])
+            .push_opcode(OP_CHECKSIG)
+            .push_opcode(OP_FALSE)
+            .push_opcode(OP_IF)
+            .push_slice(b"ord")
+            .push_int(1)
+            .push_slice(b"text/plain")
+            .push_int(0)
+            .push_slice(b"hello")
+            .push_opcode(OP_ENDIF)
+            .into_script();
+        assert!(has_inscription(&script_path_witness(&envelope)));
+
+        // the tag must follow `OP_FALSE OP_IF`
+        let tagged = bitcoin::script::Builder::new()
+            .push_slice([1; 32])
+            .push_opcode(OP_CHECKSIG)
+            .push_slice(b"ord")
+            .push_opcode(OP_DROP)
+            .into_script();
+        assert!(!has_inscription(&script_path_witness(&tagged)));
+        // key path spends (with or without an annex)
+        assert!(!has_inscription(&bitcoin::Witness::from_slice(&[[2; 64]])));
+        let annex = [vec![2; 64], vec![0x50, 1]];
+        assert!(!has_inscription(&bitcoin::Witness::from_slice(&annex)));
+
+        let runestone = bitcoin::script::Builder::new()
+            .push_opcode(OP_RETURN)
+            .push_opcode(OP_PUSHNUM_13)
+            .push_slice([0, 1])
+            .into_script();
+        assert!(is_runestone(&runestone));
+        let op_return = bitcoin::ScriptBuf::new_op_return([13, 0, 1]);
+        assert!(!is_runestone(&op_return));
+
+        assert_eq!(asset_labels(0, true, false), ["inscription"]);
+        assert_eq!(asset_labels(1, true, false), Vec::<&str>::new());
+        assert_eq!(asset_labels(1, true, true), ["runes"]);
+        assert_eq!(asset_labels(0, true, true), ["inscription", "runes"]);
+    }
+
+    #[test]
+    fn test_taproot_spend_path() {
+        use bitcoin::opcodes::all::OP_CHECKSIG;
+        use bitcoin::taproot::{LeafVersion, TapLeafHash};
+        assert_eq!(
+            taproot_spend_path(&bitcoin::Witness::from_slice(&[[2; 64]])),
+            "key"
+        );
+        let annex = [vec![2; 64], vec![0x50, 1]];
+        assert_eq!(
+            taproot_spend_path(&bitcoin::Witness::from_slice(&annex)),
+            "key"
+        );
+
+        let leaf = bitcoin::script::Builder::new()
+            .push_slice([1; 32])
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        let expected = TapLeafHash::from_script(&leaf, LeafVersion::TapScript);
+        let witness = script_path_witness(&leaf);
+        assert_eq!(taproot_spend_path(&witness), format!("script:{}", expected));
+        // the annex follows the control block
+        let mut with_annex = witness.to_vec();
+        with_annex.push(vec![0x50]);
+        assert_eq!(
+            taproot_spend_path(&bitcoin::Witness::from_slice(&with_annex)),
+            format!("script:{}", expected)
+        );
+        // truncated control block
+        let invalid = [vec![2; 64], leaf.to_bytes(), vec![0xc0]];
+        assert_eq!(
+            taproot_spend_path(&bitcoin::Witness::from_slice(&invalid)),
+            "script:?"
+        );
+    }
+
+    #[test]
+    fn test_parse_cpu_time() {
+        let stat = "42 (bin dex (1)) S 1 42 42 0 -1 4194560 1000 0 0 0 150 25 0 0 20 0 8 0";
+        assert_eq!(
+            parse_cpu_time(stat),
+            Some(std::time::Duration::from_millis(1750))
+        );
+        assert_eq!(parse_cpu_time("42 (bindex) S 1 42"), None);
+        assert_eq!(
+            parse_cpu_time("42 bindex S 1 42 42 0 -1 0 0 0 0 0 1 2"),
+            None
+        );
+        assert_eq!(
+            parse_cpu_time("42 (bindex) S 1 42 42 0 -1 0 0 0 0 0 x 2"),
+            None
+        );
     }
 }
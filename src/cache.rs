@@ -0,0 +1,867 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use bitcoin::hashes::Hash;
+use rusqlite::{types::Value, OptionalExtension};
+
+use crate::{index::ScriptHash, Location};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("SQLite failed: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error("encrypted cache requires SQLCipher (build with `--features sqlcipher`)")]
+    NoSqlCipher,
+}
+
+/// A single transaction in a script's history
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Entry {
+    pub block_hash: bitcoin::BlockHash,
+    pub block_offset: u64,
+    pub block_height: usize,
+    pub txid: bitcoin::Txid,
+    /// The block was reorged away (so it is not part of the history anymore)
+    pub stale: bool,
+}
+
+impl Entry {
+    pub fn new(location: &Location, txid: bitcoin::Txid) -> Self {
+        Self {
+            block_hash: location.indexed_header.hash(),
+            block_offset: location.offset,
+            block_height: location.height,
+            txid,
+            stale: false,
+        }
+    }
+}
+
+/// The history entries of each script
+pub type ScriptEntries<'s, S> = Vec<(&'s S, Vec<Entry>)>;
+
+#[derive(Default, Debug)]
+pub struct SyncStats {
+    pub inserted: usize,
+    pub pruned: usize,
+    pub inserted_txs: usize,
+    pub pruned_txs: usize,
+    /// Transactions evicted to keep the cache below its size limit
+    pub evicted_txs: usize,
+    pub new_txids: HashSet<bitcoin::Txid>,
+}
+
+const SCHEMA: &str = r"
+CREATE TABLE IF NOT EXISTS history (
+    script_hash BLOB NOT NULL,
+    block_hash BLOB NOT NULL,
+    block_offset INTEGER NOT NULL,
+    block_height INTEGER NOT NULL,
+    txid BLOB NOT NULL,
+    stale INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (script_hash, block_hash, block_offset)
+) WITHOUT ROWID;
+
+CREATE TABLE IF NOT EXISTS watch (
+    script_hash BLOB NOT NULL PRIMARY KEY,
+    synced_height INTEGER NOT NULL,
+    synced_block_hash BLOB NOT NULL
+) WITHOUT ROWID;
+
+CREATE TABLE IF NOT EXISTS txcache (
+    block_hash BLOB NOT NULL,
+    block_offset INTEGER NOT NULL,
+    tx_bytes BLOB NOT NULL,
+    last_used INTEGER NOT NULL DEFAULT 0, -- UNIX timestamp
+    PRIMARY KEY (block_hash, block_offset)
+) WITHOUT ROWID;
+
+CREATE TABLE IF NOT EXISTS deposits (
+    txid BLOB NOT NULL,
+    vout INTEGER NOT NULL,
+    PRIMARY KEY (txid, vout)
+) WITHOUT ROWID;
+
+CREATE TABLE IF NOT EXISTS proofs (
+    block_hash BLOB NOT NULL,
+    block_offset INTEGER NOT NULL,
+    merkle_block BLOB NOT NULL,
+    PRIMARY KEY (block_hash, block_offset)
+) WITHOUT ROWID;
+";
+
+// keep the number of bound parameters below SQLite's limit
+const MAX_ROWS_PER_STATEMENT: usize = 1000;
+
+fn blob(value: [u8; 32]) -> Value {
+    Value::Blob(value.to_vec())
+}
+
+fn int(value: u64) -> Value {
+    Value::Integer(value.try_into().expect("integer overflow"))
+}
+
+/// Execute `prefix (?, ...), (?, ...) suffix` statements, with up to `MAX_ROWS_PER_STATEMENT` rows each.
+fn execute_multi<const N: usize>(
+    tx: &rusqlite::Transaction,
+    prefix: &str,
+    rows: &[[Value; N]],
+    suffix: &str,
+) -> rusqlite::Result<usize> {
+    let mut count = 0;
+    let row = format!("({})", vec!["?"; N].join(", "));
+    for chunk in rows.chunks(MAX_ROWS_PER_STATEMENT) {
+        let values = vec![row.as_str(); chunk.len()].join(", ");
+        let mut stmt = tx.prepare_cached(&format!("{} {}{}", prefix, values, suffix))?;
+        count += stmt.execute(rusqlite::params_from_iter(chunk.iter().flatten()))?;
+    }
+    Ok(count)
+}
+
+/// Drop the transactions (and their proofs) that are not referenced by any history row.
+fn prune_txs(tx: &rusqlite::Transaction) -> rusqlite::Result<usize> {
+    let pruned = tx.execute(
+        "DELETE FROM txcache WHERE NOT EXISTS (SELECT 1 FROM history h WHERE h.block_hash = txcache.block_hash AND h.block_offset = txcache.block_offset)",
+        (),
+    )?;
+    tx.execute(
+        "DELETE FROM proofs WHERE NOT EXISTS (SELECT 1 FROM txcache t WHERE t.block_hash = proofs.block_hash AND t.block_offset = proofs.block_offset)",
+        (),
+    )?;
+    Ok(pruned)
+}
+
+/// Drop the least recently used transactions (and their proofs), keeping at most `max_bytes` of them.
+fn evict_txs(tx: &rusqlite::Transaction, max_bytes: u64) -> rusqlite::Result<usize> {
+    let evicted = tx.execute(
+        "DELETE FROM txcache WHERE (block_hash, block_offset) IN (SELECT block_hash, block_offset FROM (SELECT block_hash, block_offset, SUM(length(tx_bytes)) OVER (ORDER BY last_used DESC, block_hash, block_offset) AS total FROM txcache) WHERE total > ?1)",
+        [int(max_bytes)],
+    )?;
+    if evicted > 0 {
+        tx.execute(
+            "DELETE FROM proofs WHERE NOT EXISTS (SELECT 1 FROM txcache t WHERE t.block_hash = proofs.block_hash AND t.block_offset = proofs.block_offset)",
+            (),
+        )?;
+    }
+    Ok(evicted)
+}
+
+fn unix_time() -> i64 {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+    now.map_or(0, |d| d.as_secs().try_into().expect("integer overflow"))
+}
+
+/// A cached transaction's key (its block hash and offset)
+type TxKey = ([u8; 32], u64);
+
+fn tx_key(location: &Location) -> TxKey {
+    (
+        location.indexed_header.hash().to_byte_array(),
+        location.offset,
+    )
+}
+
+/// Writes queued by a cache (see `Cache::defer_writes`), to be applied using another connection.
+#[derive(Default, Debug)]
+pub struct Writes {
+    txs: Vec<(TxKey, Vec<u8>)>,
+    used: Vec<TxKey>,
+    proofs: Vec<(TxKey, Vec<u8>)>,
+    deposits: Vec<bitcoin::OutPoint>,
+}
+
+impl Writes {
+    /// Queue a transaction fetched without borrowing the index (see `address::Fetcher`).
+    pub fn add_tx_bytes(&mut self, block_hash: bitcoin::BlockHash, offset: u64, tx_bytes: Vec<u8>) {
+        self.txs
+            .push(((block_hash.to_byte_array(), offset), tx_bytes));
+    }
+
+    /// Append the (later) writes of `other`.
+    pub fn extend(&mut self, other: Writes) {
+        self.txs.extend(other.txs);
+        self.used.extend(other.used);
+        self.proofs.extend(other.proofs);
+        self.deposits.extend(other.deposits);
+    }
+}
+
+/// The queued writes, and the deposits added since `Cache::defer_writes`
+/// (which are reported only once, even if they were not written yet).
+#[derive(Default)]
+struct Deferred {
+    writes: Writes,
+    deposits: HashSet<bitcoin::OutPoint>,
+}
+
+/// SQLite-based cache of the watched scripts' history and transactions
+pub struct Cache {
+    db: rusqlite::Connection,
+    dry_run: bool,
+    inserted_txs: Cell<usize>,
+    max_tx_bytes: Option<u64>,
+    deferred: Option<RefCell<Deferred>>,
+}
+
+impl Cache {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let db = rusqlite::Connection::open(path)?;
+        Self::init(db)
+    }
+
+    /// Open a SQLCipher-encrypted cache, using `key` as the passphrase.
+    pub fn open_encrypted(path: impl AsRef<Path>, key: &str) -> Result<Self, Error> {
+        let db = rusqlite::Connection::open(path)?;
+        // plain SQLite silently ignores `PRAGMA key`
+        let cipher_version: Option<String> = db
+            .query_row("PRAGMA cipher_version", [], |row| row.get(0))
+            .optional()?;
+        if cipher_version.is_none() {
+            return Err(Error::NoSqlCipher);
+        }
+        db.pragma_update(None, "key", key)?;
+        Self::init(db) // fails if the key is wrong
+    }
+
+    fn init(db: rusqlite::Connection) -> Result<Self, Error> {
+        db.execute_batch(SCHEMA)?;
+        // caches created before reorged rows were retained
+        let has_stale: bool = db.query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('history') WHERE name = 'stale'",
+            [],
+            |row| row.get(0),
+        )?;
+        if !has_stale {
+            db.execute_batch("ALTER TABLE history ADD COLUMN stale INTEGER NOT NULL DEFAULT 0")?;
+        }
+        // caches created before the transactions could be evicted
+        let has_last_used: bool = db.query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('txcache') WHERE name = 'last_used'",
+            [],
+            |row| row.get(0),
+        )?;
+        if !has_last_used {
+            db.execute_batch(
+                "ALTER TABLE txcache ADD COLUMN last_used INTEGER NOT NULL DEFAULT 0",
+            )?;
+        }
+        Ok(Self {
+            db,
+            dry_run: false,
+            inserted_txs: Cell::new(0),
+            max_tx_bytes: None,
+            deferred: None,
+        })
+    }
+
+    /// Queue the added transactions, proofs and deposits (and the transactions' usage) instead
+    /// of writing them, so that a single connection writes the cache (see `take_writes`).
+    pub fn defer_writes(&mut self) {
+        self.deferred = Some(RefCell::default());
+    }
+
+    /// The writes queued since the last call (see `defer_writes`).
+    pub fn take_writes(&self) -> Writes {
+        self.deferred
+            .as_ref()
+            .map(|deferred| std::mem::take(&mut deferred.borrow_mut().writes))
+            .unwrap_or_default()
+    }
+
+    /// Write the queued writes (e.g. before `sync`, so its pruning keeps the new transactions).
+    pub fn apply(&self, writes: Writes) -> Result<(), Error> {
+        for (key, tx_bytes) in writes.txs {
+            self.insert_tx_bytes(key, &tx_bytes)?;
+        }
+        for key in writes.used {
+            self.touch_tx(key)?;
+        }
+        for (key, merkle_block) in writes.proofs {
+            self.insert_merkle_proof(key, &merkle_block)?;
+        }
+        for outpoint in &writes.deposits {
+            self.insert_deposit(outpoint)?;
+        }
+        Ok(())
+    }
+
+    /// Don't modify the cache (`sync` only reports what would have changed).
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Evict the least recently used transactions (on `sync`) when their total size exceeds `max_bytes`.
+    pub fn set_max_tx_bytes(&mut self, max_bytes: Option<u64>) {
+        self.max_tx_bytes = max_bytes;
+    }
+
+    pub fn get_tx_bytes(&self, location: &Location) -> Result<Option<Vec<u8>>, Error> {
+        let mut stmt = self.db.prepare_cached(
+            "SELECT tx_bytes FROM txcache WHERE block_hash = ?1 AND block_offset = ?2",
+        )?;
+        let key = tx_key(location);
+        let tx_bytes: Option<Vec<u8>> = stmt.query_row(key, |row| row.get(0)).optional()?;
+        if tx_bytes.is_some() && self.max_tx_bytes.is_some() {
+            match &self.deferred {
+                Some(deferred) => deferred.borrow_mut().writes.used.push(key),
+                None => self.touch_tx(key)?,
+            }
+        }
+        Ok(tx_bytes)
+    }
+
+    fn touch_tx(&self, key: TxKey) -> Result<(), Error> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let mut stmt = self.db.prepare_cached(
+            "UPDATE txcache SET last_used = ?3 WHERE block_hash = ?1 AND block_offset = ?2",
+        )?;
+        stmt.execute((key.0, key.1, unix_time()))?;
+        Ok(())
+    }
+
+    /// The txid at `location`, if it is part of a cached history
+    pub fn get_txid(&self, location: &Location) -> Result<Option<bitcoin::Txid>, Error> {
+        let mut stmt = self.db.prepare_cached(
+            "SELECT txid FROM history WHERE block_hash = ?1 AND block_offset = ?2 LIMIT 1",
+        )?;
+        let key = (
+            location.indexed_header.hash().to_byte_array(),
+            location.offset,
+        );
+        let txid: Option<[u8; 32]> = stmt.query_row(key, |row| row.get(0)).optional()?;
+        Ok(txid.map(bitcoin::Txid::from_byte_array))
+    }
+
+    pub fn add_tx_bytes(&self, location: &Location, tx_bytes: &[u8]) -> Result<(), Error> {
+        let key = tx_key(location);
+        match &self.deferred {
+            Some(deferred) => {
+                let txs = &mut deferred.borrow_mut().writes.txs;
+                txs.push((key, tx_bytes.to_vec()));
+                Ok(())
+            }
+            None => self.insert_tx_bytes(key, tx_bytes),
+        }
+    }
+
+    fn insert_tx_bytes(&self, key: TxKey, tx_bytes: &[u8]) -> Result<(), Error> {
+        if self.dry_run {
+            self.inserted_txs.set(self.inserted_txs.get() + 1);
+            return Ok(());
+        }
+        let mut stmt = self.db.prepare_cached(
+            "INSERT OR IGNORE INTO txcache (block_hash, block_offset, tx_bytes, last_used) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        let inserted = stmt.execute((key.0, key.1, tx_bytes, unix_time()))?;
+        self.inserted_txs.set(self.inserted_txs.get() + inserted);
+        Ok(())
+    }
+
+    /// Whether the deposit was already reported
+    pub fn has_deposit(&self, outpoint: &bitcoin::OutPoint) -> Result<bool, Error> {
+        if let Some(deferred) = &self.deferred {
+            if deferred.borrow().deposits.contains(outpoint) {
+                return Ok(true);
+            }
+        }
+        let mut stmt = self
+            .db
+            .prepare_cached("SELECT 1 FROM deposits WHERE txid = ?1 AND vout = ?2")?;
+        Ok(stmt.exists((outpoint.txid.to_byte_array(), outpoint.vout))?)
+    }
+
+    pub fn add_deposit(&self, outpoint: &bitcoin::OutPoint) -> Result<(), Error> {
+        match &self.deferred {
+            Some(deferred) => {
+                let mut deferred = deferred.borrow_mut();
+                deferred.deposits.insert(*outpoint);
+                deferred.writes.deposits.push(*outpoint);
+                Ok(())
+            }
+            None => self.insert_deposit(outpoint),
+        }
+    }
+
+    fn insert_deposit(&self, outpoint: &bitcoin::OutPoint) -> Result<(), Error> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let mut stmt = self
+            .db
+            .prepare_cached("INSERT OR IGNORE INTO deposits (txid, vout) VALUES (?1, ?2)")?;
+        stmt.execute((outpoint.txid.to_byte_array(), outpoint.vout))?;
+        Ok(())
+    }
+
+    pub fn has_merkle_proof(&self, location: &Location) -> Result<bool, Error> {
+        let mut stmt = self
+            .db
+            .prepare_cached("SELECT 1 FROM proofs WHERE block_hash = ?1 AND block_offset = ?2")?;
+        let key = (
+            location.indexed_header.hash().to_byte_array(),
+            location.offset,
+        );
+        Ok(stmt.exists(key)?)
+    }
+
+    /// Store the transaction's merkle proof (serialized as in BIP37 `merkleblock` message).
+    pub fn add_merkle_proof(
+        &self,
+        location: &Location,
+        proof: &bitcoin::MerkleBlock,
+    ) -> Result<(), Error> {
+        let key = tx_key(location);
+        let merkle_block = bitcoin::consensus::serialize(proof);
+        match &self.deferred {
+            Some(deferred) => {
+                let proofs = &mut deferred.borrow_mut().writes.proofs;
+                proofs.push((key, merkle_block));
+                Ok(())
+            }
+            None => self.insert_merkle_proof(key, &merkle_block),
+        }
+    }
+
+    fn insert_merkle_proof(&self, key: TxKey, merkle_block: &[u8]) -> Result<(), Error> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let mut stmt = self.db.prepare_cached(
+            "INSERT OR IGNORE INTO proofs (block_hash, block_offset, merkle_block) VALUES (?1, ?2, ?3)",
+        )?;
+        stmt.execute((key.0, key.1, merkle_block))?;
+        Ok(())
+    }
+
+    /// Returns the block (height and hash) up to which the script's history was recorded.
+    pub fn synced(
+        &self,
+        script: &bitcoin::Script,
+    ) -> Result<Option<(usize, bitcoin::BlockHash)>, Error> {
+        let mut stmt = self.db.prepare_cached(
+            "SELECT synced_height, synced_block_hash FROM watch WHERE script_hash = ?1",
+        )?;
+        let script_hash = ScriptHash::hash(script.as_bytes());
+        let res = stmt
+            .query_row([script_hash.as_byte_array()], |row| {
+                Ok((row.get(0)?, row.get::<_, [u8; 32]>(1)?))
+            })
+            .optional()?;
+        Ok(res.map(|(height, hash)| (height, bitcoin::BlockHash::from_byte_array(hash))))
+    }
+
+    /// Returns the recorded history of the given script (in confirmation order),
+    /// including the stale entries.
+    pub fn history(&self, script: &bitcoin::Script) -> Result<Vec<Entry>, Error> {
+        let mut stmt = self.db.prepare_cached(
+            "SELECT block_hash, block_offset, block_height, txid, stale FROM history WHERE script_hash = ?1 ORDER BY block_height, block_offset",
+        )?;
+        let script_hash = ScriptHash::hash(script.as_bytes());
+        let rows = stmt.query_map([script_hash.as_byte_array()], |row| {
+            Ok(Entry {
+                block_hash: bitcoin::BlockHash::from_byte_array(row.get(0)?),
+                block_offset: row.get(1)?,
+                block_height: row.get(2)?,
+                txid: bitcoin::Txid::from_byte_array(row.get(3)?),
+                stale: row.get(4)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Delete the recorded history of the given scripts, and drop unreferenced transactions
+    /// (rolled back in dry-run mode).
+    /// Returns the number of deleted history rows and transactions.
+    pub fn forget(&mut self, scripts: &[&bitcoin::Script]) -> Result<(usize, usize), Error> {
+        let script_hashes: Vec<[Value; 1]> = scripts
+            .iter()
+            .map(|script| [blob(ScriptHash::hash(script.as_bytes()).to_byte_array())])
+            .collect();
+        let tx = self.db.transaction()?;
+        let deleted = execute_multi(
+            &tx,
+            "DELETE FROM history WHERE script_hash IN (VALUES",
+            &script_hashes,
+            ")",
+        )?;
+        execute_multi(
+            &tx,
+            "DELETE FROM watch WHERE script_hash IN (VALUES",
+            &script_hashes,
+            ")",
+        )?;
+        let pruned_txs = prune_txs(&tx)?;
+        if !self.dry_run {
+            tx.commit()?;
+        }
+        Ok((deleted, pruned_txs))
+    }
+
+    /// Returns the recorded (non-stale) history of each script, if all of them were synced up to `tip`,
+    /// so that an unchanged watch set doesn't have to be queried again after a restart.
+    pub fn synced_history<'s, S: AsRef<bitcoin::Script> + 's>(
+        &self,
+        scripts: impl IntoIterator<Item = &'s S>,
+        tip: bitcoin::BlockHash,
+    ) -> Result<Option<ScriptEntries<'s, S>>, Error> {
+        let mut stmt = self
+            .db
+            .prepare_cached("SELECT script_hash FROM watch WHERE synced_block_hash = ?1")?;
+        let mut history: HashMap<[u8; 32], Vec<Entry>> = stmt
+            .query_map([tip.to_byte_array()], |row| Ok((row.get(0)?, vec![])))?
+            .collect::<Result<_, _>>()?;
+        let scripts: Vec<_> = scripts
+            .into_iter()
+            .map(|script| (script, ScriptHash::hash(script.as_ref().as_bytes())))
+            .collect();
+        if !scripts
+            .iter()
+            .all(|(_script, script_hash)| history.contains_key(script_hash.as_byte_array()))
+        {
+            return Ok(None);
+        }
+        let mut stmt = self.db.prepare_cached(
+            "SELECT h.script_hash, h.block_hash, h.block_offset, h.block_height, h.txid FROM history h JOIN watch w USING (script_hash) WHERE w.synced_block_hash = ?1 AND NOT h.stale ORDER BY h.block_height, h.block_offset",
+        )?;
+        let mut rows = stmt.query([tip.to_byte_array()])?;
+        while let Some(row) = rows.next()? {
+            let entries = history
+                .get_mut(&row.get::<_, [u8; 32]>(0)?)
+                .expect("missing watch row");
+            entries.push(Entry {
+                block_hash: bitcoin::BlockHash::from_byte_array(row.get(1)?),
+                block_offset: row.get(2)?,
+                block_height: row.get(3)?,
+                txid: bitcoin::Txid::from_byte_array(row.get(4)?),
+                stale: false,
+            });
+        }
+        Ok(Some(
+            scripts
+                .into_iter()
+                .map(|(script, script_hash)| {
+                    let entries = history
+                        .get(script_hash.as_byte_array())
+                        .cloned()
+                        .unwrap_or_default();
+                    (script, entries)
+                })
+                .collect(),
+        ))
+    }
+
+    /// Replace the recorded history of the given scripts (synced up to `tip`),
+    /// and drop unreferenced transactions (rolled back in dry-run mode).
+    /// Entries missing from the new history are kept, marked as stale.
+    pub fn sync<S: AsRef<bitcoin::Script>>(
+        &mut self,
+        history: &[(S, Vec<Entry>)],
+        tip: (usize, bitcoin::BlockHash),
+    ) -> Result<SyncStats, Error> {
+        let mut stats = SyncStats {
+            inserted_txs: self.inserted_txs.take(),
+            ..Default::default()
+        };
+        let tx = self.db.transaction()?;
+        {
+            let mut select = tx.prepare(
+                "SELECT block_hash, block_offset FROM history WHERE script_hash = ?1 AND NOT stale",
+            )?;
+            let mut stale_rows = vec![];
+            let mut new_rows = vec![];
+            let mut watch_rows = Vec::with_capacity(history.len());
+            for (script, entries) in history {
+                let script_hash = ScriptHash::hash(script.as_ref().as_bytes()).to_byte_array();
+                let mut recorded: HashSet<([u8; 32], u64)> = select
+                    .query_map([script_hash], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect::<Result<_, _>>()?;
+                let mut new = HashMap::new();
+                for entry in entries {
+                    let key = (entry.block_hash.to_byte_array(), entry.block_offset);
+                    if !recorded.remove(&key) {
+                        new.insert(key, entry);
+                    }
+                }
+                // the remaining recorded rows are not part of the history anymore (so they become stale)
+                for (block_hash, block_offset) in recorded {
+                    stale_rows.push([blob(script_hash), blob(block_hash), int(block_offset)]);
+                }
+                for ((block_hash, block_offset), entry) in new {
+                    new_rows.push([
+                        blob(script_hash),
+                        blob(block_hash),
+                        int(block_offset),
+                        int(entry.block_height as u64),
+                        blob(entry.txid.to_byte_array()),
+                    ]);
+                    stats.new_txids.insert(entry.txid);
+                }
+                watch_rows.push([
+                    blob(script_hash),
+                    int(tip.0 as u64),
+                    blob(tip.1.to_byte_array()),
+                ]);
+            }
+            stats.pruned = execute_multi(
+                &tx,
+                "UPDATE history SET stale = 1 WHERE (script_hash, block_hash, block_offset) IN (VALUES",
+                &stale_rows,
+                ")",
+            )?;
+            stats.inserted = execute_multi(
+                &tx,
+                // also revives stale entries (`stale` is reset to its default)
+                "INSERT OR REPLACE INTO history (script_hash, block_hash, block_offset, block_height, txid) VALUES",
+                &new_rows,
+                "",
+            )?;
+            execute_multi(
+                &tx,
+                "INSERT OR REPLACE INTO watch (script_hash, synced_height, synced_block_hash) VALUES",
+                &watch_rows,
+                "",
+            )?;
+            stats.pruned_txs = prune_txs(&tx)?;
+            if let Some(max_bytes) = self.max_tx_bytes {
+                stats.evicted_txs = evict_txs(&tx, max_bytes)?;
+            }
+        }
+        if !self.dry_run {
+            tx.commit()?;
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(block: u8, block_offset: u64, tx: u8) -> Entry {
+        Entry {
+            block_hash: bitcoin::BlockHash::from_byte_array([block; 32]),
+            block_offset,
+            block_height: block.into(),
+            txid: bitcoin::Txid::from_byte_array([tx; 32]),
+            stale: false,
+        }
+    }
+
+    fn stale(entry: Entry) -> Entry {
+        Entry {
+            stale: true,
+            ..entry
+        }
+    }
+
+    #[test]
+    fn test_sync() -> Result<(), Error> {
+        let mut cache = Cache::open(":memory:")?;
+        let script = bitcoin::Script::from_bytes(b"\x51");
+        let tip = (3, bitcoin::BlockHash::from_byte_array([3; 32]));
+        assert_eq!(cache.synced(script)?, None);
+
+        let stats = cache.sync(&[(script, vec![entry(1, 2, 10), entry(2, 5, 20)])], tip)?;
+        assert_eq!((stats.inserted, stats.pruned), (2, 0));
+        assert_eq!(stats.new_txids.len(), 2);
+
+        // block 2 was reorged, and a new transaction was confirmed at block 3
+        let stats = cache.sync(&[(script, vec![entry(1, 2, 10), entry(3, 1, 30)])], tip)?;
+        assert_eq!((stats.inserted, stats.pruned), (1, 1));
+        assert_eq!(
+            stats.new_txids,
+            HashSet::from([bitcoin::Txid::from_byte_array([30; 32])])
+        );
+
+        let stats = cache.sync(&[(script, vec![entry(1, 2, 10), entry(3, 1, 30)])], tip)?;
+        assert_eq!((stats.inserted, stats.pruned), (0, 0));
+        assert_eq!(cache.synced(script)?, Some(tip));
+        assert_eq!(
+            cache.history(script)?,
+            vec![entry(1, 2, 10), stale(entry(2, 5, 20)), entry(3, 1, 30)]
+        );
+
+        // block 2 is back
+        let stats = cache.sync(&[(script, vec![entry(1, 2, 10), entry(2, 5, 20)])], tip)?;
+        assert_eq!((stats.inserted, stats.pruned), (1, 1));
+        assert_eq!(
+            cache.history(script)?,
+            vec![entry(1, 2, 10), entry(2, 5, 20), stale(entry(3, 1, 30))]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_synced_history() -> Result<(), Error> {
+        let mut cache = Cache::open(":memory:")?;
+        let (a, b) = (
+            bitcoin::ScriptBuf::from_bytes(b"\x51".to_vec()),
+            bitcoin::ScriptBuf::from_bytes(b"\x52".to_vec()),
+        );
+        let tip = (2, bitcoin::BlockHash::from_byte_array([2; 32]));
+        cache.sync(&[(&a, vec![entry(1, 2, 10), entry(2, 5, 20)])], tip)?;
+        cache.sync(&[(&a, vec![entry(2, 5, 20)]), (&b, vec![])], tip)?;
+        assert_eq!(
+            cache.synced_history([&a, &b], tip.1)?,
+            Some(vec![(&a, vec![entry(2, 5, 20)]), (&b, vec![])])
+        );
+
+        let c = bitcoin::ScriptBuf::from_bytes(b"\x53".to_vec());
+        assert_eq!(cache.synced_history([&a, &c], tip.1)?, None);
+
+        let new_tip = bitcoin::BlockHash::from_byte_array([3; 32]);
+        assert_eq!(cache.synced_history([&a], new_tip)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_forget() -> Result<(), Error> {
+        let mut cache = Cache::open(":memory:")?;
+        let (a, b) = (
+            bitcoin::Script::from_bytes(b"\x51"),
+            bitcoin::Script::from_bytes(b"\x52"),
+        );
+        let tip = (2, bitcoin::BlockHash::from_byte_array([2; 32]));
+        let shared = entry(1, 2, 10);
+        cache.sync(
+            &[
+                (a, vec![shared.clone(), entry(2, 5, 20)]),
+                (b, vec![shared]),
+            ],
+            tip,
+        )?;
+
+        assert_eq!(cache.forget(&[a])?, (2, 0));
+        assert_eq!(cache.synced(a)?, None);
+        assert_eq!(cache.history(a)?, vec![]);
+        assert_eq!(cache.history(b)?, vec![entry(1, 2, 10)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_evict_txs() -> Result<(), Error> {
+        let mut cache = Cache::open(":memory:")?;
+        let script = bitcoin::Script::from_bytes(b"\x51");
+        let tip = (1, bitcoin::BlockHash::from_byte_array([1; 32]));
+        let entries: Vec<_> = (0..3).map(|i| entry(1, i, 1)).collect();
+        cache.sync(&[(script, entries.clone())], tip)?;
+        for (offset, last_used) in [(0, 30), (1, 10), (2, 20)] {
+            cache.db.execute(
+                "INSERT INTO txcache (block_hash, block_offset, tx_bytes, last_used) VALUES (?1, ?2, ?3, ?4)",
+                ([1u8; 32], offset, [0u8; 100], last_used),
+            )?;
+        }
+        cache.set_max_tx_bytes(Some(250));
+        let stats = cache.sync(&[(script, entries)], tip)?;
+        assert_eq!(stats.evicted_txs, 1);
+        let offsets: Vec<u64> = cache
+            .db
+            .prepare("SELECT block_offset FROM txcache ORDER BY block_offset")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        assert_eq!(offsets, vec![0, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_deposits() -> Result<(), Error> {
+        let mut cache = Cache::open(":memory:")?;
+        let outpoint = bitcoin::OutPoint::new(bitcoin::Txid::from_byte_array([1; 32]), 2);
+        cache.set_dry_run(true);
+        cache.add_deposit(&outpoint)?;
+        assert!(!cache.has_deposit(&outpoint)?);
+        cache.set_dry_run(false);
+        cache.add_deposit(&outpoint)?;
+        cache.add_deposit(&outpoint)?;
+        assert!(cache.has_deposit(&outpoint)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_deferred_writes() -> Result<(), Error> {
+        let mut cache = Cache::open(":memory:")?;
+        let mut writer = Cache::open(":memory:")?;
+        cache.defer_writes();
+        let outpoint = bitcoin::OutPoint::new(bitcoin::Txid::from_byte_array([1; 32]), 2);
+        cache.add_deposit(&outpoint)?;
+        // not written yet, but already reported
+        assert!(cache.has_deposit(&outpoint)?);
+        let mut writes = cache.take_writes();
+        assert_eq!(writes.deposits, vec![outpoint]);
+        assert!(cache.take_writes().deposits.is_empty());
+
+        writes.extend(Writes {
+            txs: vec![(([1; 32], 2), vec![0; 100])],
+            ..Default::default()
+        });
+        writer.apply(writes)?;
+        assert!(writer.has_deposit(&outpoint)?);
+        // the applied transaction is referenced by the synced history (so it is not pruned)
+        let script = bitcoin::Script::from_bytes(b"\x51");
+        let tip = (1, bitcoin::BlockHash::from_byte_array([1; 32]));
+        let stats = writer.sync(&[(script, vec![entry(1, 2, 10)])], tip)?;
+        assert_eq!((stats.inserted_txs, stats.pruned_txs), (1, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_many() -> Result<(), Error> {
+        let mut cache = Cache::open(":memory:")?;
+        let script = bitcoin::Script::from_bytes(b"\x51");
+        let tip = (1, bitcoin::BlockHash::from_byte_array([1; 32]));
+        let entries: Vec<_> = (0..2500).map(|i| entry(1, i, 1)).collect();
+        let stats = cache.sync(&[(script, entries.clone())], tip)?;
+        assert_eq!((stats.inserted, stats.pruned), (2500, 0));
+
+        let stats = cache.sync(&[(script, entries[..500].to_vec())], tip)?;
+        assert_eq!((stats.inserted, stats.pruned), (0, 2000));
+        let history = cache.history(script)?;
+        assert_eq!(history[..500], entries[..500]);
+        assert!(history[500..].iter().all(|entry| entry.stale));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run() -> Result<(), Error> {
+        let mut cache = Cache::open(":memory:")?;
+        let script = bitcoin::Script::from_bytes(b"\x51");
+        let tip = (2, bitcoin::BlockHash::from_byte_array([2; 32]));
+        cache.sync(&[(script, vec![entry(1, 2, 10)])], tip)?;
+
+        cache.set_dry_run(true);
+        let stats = cache.sync(&[(script, vec![entry(2, 5, 20)])], tip)?;
+        assert_eq!((stats.inserted, stats.pruned), (1, 1));
+        assert_eq!(cache.history(script)?, vec![entry(1, 2, 10)]);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "sqlcipher"))]
+    #[test]
+    fn test_no_sqlcipher() {
+        let res = Cache::open_encrypted(":memory:", "passphrase");
+        assert!(matches!(res, Err(Error::NoSqlCipher)));
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[test]
+    fn test_sqlcipher() -> Result<(), Error> {
+        let path = std::env::temp_dir().join(format!("bindex-test-{}.sqlite", std::process::id()));
+        let script = bitcoin::Script::from_bytes(b"\x51");
+        let tip = (1, bitcoin::BlockHash::from_byte_array([1; 32]));
+        Cache::open_encrypted(&path, "passphrase")?
+            .sync(&[(script, vec![entry(1, 2, 10)])], tip)?;
+
+        let cache = Cache::open_encrypted(&path, "passphrase")?;
+        assert_eq!(cache.history(script)?, vec![entry(1, 2, 10)]);
+        assert!(Cache::open_encrypted(&path, "wrong").is_err());
+        assert!(Cache::open(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+        Ok(())
+    }
+}
@@ -7,13 +7,18 @@ pub struct Chain {
 }
 
 impl Chain {
-    pub fn new(rows: Vec<index::Header>) -> Self {
+    /// Fails with the height of the first header that doesn't follow the previous one.
+    pub fn new(rows: Vec<index::Header>) -> Result<Self, usize> {
         let mut block_hash = bitcoin::BlockHash::all_zeros();
-        for row in &rows {
-            assert_eq!(row.header().prev_blockhash, block_hash);
+        let mut txpos = index::TxPos::default();
+        for (height, row) in rows.iter().enumerate() {
+            if row.header().prev_blockhash != block_hash || row.next_txpos() < txpos {
+                return Err(height);
+            }
             block_hash = row.hash();
+            txpos = row.next_txpos();
         }
-        Self { rows }
+        Ok(Self { rows })
     }
 
     pub fn tip_hash(&self) -> Option<bitcoin::BlockHash> {
@@ -46,7 +51,20 @@ impl Chain {
         self.rows.get(height)
     }
 
-    pub fn find_by_txpos(&self, txpos: &index::TxPos) -> Option<Location> {
+    /// Median time of the 11 blocks ending at `height` (BIP113)
+    pub fn median_time_past(&self, height: usize) -> Option<u32> {
+        let rows = self.rows.get(height.saturating_sub(10)..=height)?;
+        let mut times: Vec<u32> = rows.iter().map(|row| row.header().time).collect();
+        times.sort_unstable();
+        Some(times[times.len() / 2])
+    }
+
+    pub fn get_height(&self, hash: &BlockHash) -> Option<usize> {
+        // recent blocks are more likely to be queried
+        self.rows.iter().rposition(|row| row.hash() == *hash)
+    }
+
+    pub fn find_by_txpos(&self, txpos: &index::TxPos) -> Option<Location<'_>> {
         let height = match self
             .rows
             .binary_search_by_key(txpos, index::Header::next_txpos)
@@ -81,6 +99,17 @@ pub struct Location<'a> {
     pub indexed_header: &'a index::Header,
 }
 
+impl Location<'_> {
+    /// Returns `None` if the block is no longer part of `chain` (e.g. after a reorg).
+    pub fn confirmations(&self, chain: &Chain) -> Option<usize> {
+        let header = chain.get_by_height(self.height)?;
+        if header.hash() != self.indexed_header.hash() {
+            return None;
+        }
+        Some(chain.tip_height()? - self.height + 1)
+    }
+}
+
 impl Ord for Location<'_> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         (self.height, self.offset).cmp(&(other.height, other.offset))
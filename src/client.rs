@@ -1,3 +1,9 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
 use bitcoin::{
     block::Header,
     consensus::{deserialize, Decodable},
@@ -5,7 +11,7 @@ use bitcoin::{
 };
 use log::*;
 
-use crate::index;
+use crate::{index, redact::Redacted};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -20,37 +26,273 @@ pub enum Error {
 
     #[error("bad JSON: {0}")]
     Json(#[from] serde_json::Error),
+
+    #[error("bad hex: {0}")]
+    Hex(#[from] hex::FromHexError),
+
+    #[error("RPC {method} failed: {message} ({code})")]
+    Rpc {
+        method: String,
+        code: i64,
+        message: String,
+    },
+}
+
+impl Error {
+    /// The node could not be reached (as opposed to failing the request)
+    pub fn is_unreachable(&self) -> bool {
+        match self {
+            Error::Http(ureq::Error::StatusCode(_)) => false,
+            Error::Http(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The requested data is not available (e.g. a stale or pruned block).
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Error::Http(ureq::Error::StatusCode(404)))
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(serde::Deserialize)]
+struct RpcResponse {
+    result: Option<serde_json::Value>,
+    error: Option<RpcError>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct Unspent {
+    pub txid: bitcoin::Txid,
+    pub vout: u32,
+    #[serde(with = "bitcoin::amount::serde::as_btc")]
+    pub amount: bitcoin::Amount,
+    pub height: usize,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct UtxoScan {
+    pub height: usize,
+    pub unspents: Vec<Unspent>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct ChainInfo {
+    /// The node's network (e.g. `main`, `test` or `regtest`)
+    pub chain: String,
+    pub blocks: usize,
+    pub headers: usize,
+    pub bestblockhash: BlockHash,
+    pub initialblockdownload: bool,
+    #[serde(default)]
+    pub pruned: bool,
+    /// The lowest height of the stored blocks (for pruned nodes)
+    pub pruneheight: Option<usize>,
 }
 
+/// A chain tip known to the node (see `getchaintips` RPC)
+#[derive(serde::Deserialize, Debug)]
+pub struct ChainTip {
+    pub height: usize,
+    pub hash: BlockHash,
+    /// e.g. `active`, `valid-fork`, `valid-headers`, `headers-only` or `invalid`
+    pub status: String,
+}
+
+/// JSON-RPC credentials
+#[derive(Clone)]
+pub enum Auth {
+    UserPass(String, String),
+    /// Re-read on each call (since the node creates a new cookie when restarted)
+    Cookie(PathBuf),
+}
+
+impl std::fmt::Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Auth::UserPass(user, _) => write!(f, "UserPass({:?}, ***)", user),
+            Auth::Cookie(path) => write!(f, "Cookie({:?})", path),
+        }
+    }
+}
+
+impl Auth {
+    fn header(&self) -> Result<String, Error> {
+        use bitcoin::base64::Engine;
+        let credentials = match self {
+            Auth::UserPass(user, pass) => format!("{}:{}", user, pass),
+            Auth::Cookie(path) => std::fs::read_to_string(path)?.trim_end().to_owned(),
+        };
+        let encoded = bitcoin::base64::engine::general_purpose::STANDARD.encode(credentials);
+        Ok(format!("Basic {}", encoded))
+    }
+}
+
+/// The clones share the rate limit (so it covers all the node requests).
+#[derive(Clone)]
 pub struct Client {
     agent: ureq::Agent,
-    url: String,
+    /// The active node URL is `urls[active]`
+    urls: Vec<String>,
+    active: usize,
+    min_interval: Option<Duration>,
+    last_request: Arc<Mutex<Option<Instant>>>,
+    retries: usize,
+    auth: Option<Auth>,
 }
 
 impl Client {
     pub fn new<T: Into<String>>(agent: ureq::Agent, url: T) -> Self {
         Self {
             agent,
-            url: url.into(),
+            urls: vec![url.into()],
+            active: 0,
+            min_interval: None,
+            last_request: Arc::default(),
+            retries: 0,
+            auth: None,
+        }
+    }
+
+    /// Alternative node URLs (for failing over when the active node is unreachable).
+    pub fn set_fallback_urls(&mut self, urls: Vec<String>) {
+        self.urls.truncate(1);
+        self.urls.extend(urls);
+    }
+
+    pub fn url(&self) -> &str {
+        &self.urls[self.active]
+    }
+
+    pub fn url_count(&self) -> usize {
+        self.urls.len()
+    }
+
+    /// Switch to the next node URL (wrapping around).
+    pub fn switch_url(&mut self) -> &str {
+        self.active = (self.active + 1) % self.urls.len();
+        self.url()
+    }
+
+    pub fn set_auth(&mut self, auth: Option<Auth>) {
+        self.auth = auth;
+    }
+
+    /// Retry timed-out (idempotent) REST requests at most `retries` times.
+    pub fn set_retries(&mut self, retries: usize) {
+        self.retries = retries;
+    }
+
+    /// Send at most `requests_per_sec` requests per second.
+    pub fn set_rate_limit(&mut self, requests_per_sec: Option<u32>) {
+        self.min_interval = requests_per_sec.map(|n| Duration::from_secs(1) / n.max(1));
+    }
+
+    fn throttle(&self) {
+        let Some(min_interval) = self.min_interval else {
+            return;
+        };
+        let mut last_request = self.last_request.lock().unwrap();
+        if let Some(elapsed) = last_request.map(|t| t.elapsed()) {
+            if elapsed < min_interval {
+                std::thread::sleep(min_interval - elapsed);
+            }
         }
+        *last_request = Some(Instant::now());
     }
 
     fn get_bytes(&self, url: &str) -> Result<Vec<u8>, Error> {
+        let mut attempt = 0;
+        loop {
+            match self.try_get_bytes(url) {
+                Err(Error::Http(ureq::Error::Timeout(timeout))) if attempt < self.retries => {
+                    attempt += 1;
+                    warn!(
+                        "{} timed out ({}), retrying ({}/{})",
+                        Redacted(url),
+                        timeout,
+                        attempt,
+                        self.retries
+                    );
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn try_get_bytes(&self, url: &str) -> Result<Vec<u8>, Error> {
+        self.throttle();
         let req = self.agent.get(url);
-        debug!("=> {:?}", req);
+        debug!("=> {:?}", Redacted(&req));
         let res = req.call()?;
         debug!("<= {:?}", res);
         Ok(res.into_body().read_to_vec()?)
     }
 
+    fn call<T: serde::de::DeserializeOwned>(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<T, Error> {
+        self.throttle();
+        let body =
+            serde_json::json!({"jsonrpc": "1.0", "id": 0, "method": method, "params": params});
+        let mut req = self
+            .agent
+            .post(self.url())
+            .header("Content-Type", "application/json");
+        if let Some(auth) = &self.auth {
+            req = req.header("Authorization", auth.header()?);
+        }
+        let req = req
+            .config()
+            .http_status_as_error(false) // RPC errors are returned with HTTP 500
+            .build();
+        debug!("=> {:?}: {}", Redacted(&req), Redacted(&body));
+        let res = req.send(body.to_string())?;
+        debug!("<= {:?}", res);
+        let res: RpcResponse = serde_json::from_slice(&res.into_body().read_to_vec()?)?;
+        if let Some(RpcError { code, message }) = res.error {
+            return Err(Error::Rpc {
+                method: method.to_owned(),
+                code,
+                message,
+            });
+        }
+        Ok(serde_json::from_value(res.result.unwrap_or_default())?)
+    }
+
+    pub fn scan_tx_out_set(&self, scripts: &[bitcoin::ScriptBuf]) -> Result<UtxoScan, Error> {
+        let descriptors: Vec<String> = scripts
+            .iter()
+            .map(|script| format!("raw({})", hex::encode(script.as_bytes())))
+            .collect();
+        self.call("scantxoutset", serde_json::json!(["start", descriptors]))
+    }
+
+    pub fn get_chain_info(&self) -> Result<ChainInfo, Error> {
+        let url = format!("{}/rest/chaininfo.json", self.url());
+        let data = self.get_bytes(&url)?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    pub fn get_chain_tips(&self) -> Result<Vec<ChainTip>, Error> {
+        self.call("getchaintips", serde_json::json!([]))
+    }
+
     pub fn get_blockhash_by_height(&self, height: usize) -> Result<BlockHash, Error> {
-        let url = format!("{}/rest/blockhashbyheight/{}.bin", self.url, height);
+        let url = format!("{}/rest/blockhashbyheight/{}.bin", self.url(), height);
         let data = self.get_bytes(&url)?;
         Ok(deserialize(&data)?)
     }
 
     pub fn get_headers(&self, hash: BlockHash, limit: usize) -> Result<Vec<Header>, Error> {
-        let url = format!("{}/rest/headers/{}/{}.bin", self.url, limit + 1, hash);
+        let url = format!("{}/rest/headers/{}/{}.bin", self.url(), limit + 1, hash);
         let data = self.get_bytes(&url)?;
         assert_eq!(data.len() % Header::SIZE, 0);
         let count = data.len() / Header::SIZE;
@@ -66,19 +308,78 @@ impl Client {
     }
 
     pub fn get_block_bytes(&self, hash: BlockHash) -> Result<index::BlockBytes, Error> {
-        let url = format!("{}/rest/block/{}.bin", self.url, hash);
+        let url = format!("{}/rest/block/{}.bin", self.url(), hash);
         let data = self.get_bytes(&url)?;
         Ok(index::BlockBytes::new(data))
     }
 
     pub fn get_spent_bytes(&self, hash: BlockHash) -> Result<index::SpentBytes, Error> {
-        let url = format!("{}/rest/spentoutputs/{}.bin", self.url, hash);
+        let url = format!("{}/rest/spentoutputs/{}.bin", self.url(), hash);
         let data = self.get_bytes(&url)?;
         Ok(index::SpentBytes::new(data))
     }
 
     pub fn get_tx_bytes_from_block(&self, hash: BlockHash, offset: u64) -> Result<Vec<u8>, Error> {
-        let url = format!("{}/rest/txfromblock/{}-{}.bin", self.url, hash, offset);
+        let url = format!("{}/rest/txfromblock/{}-{}.bin", self.url(), hash, offset);
         self.get_bytes(&url)
     }
+
+    pub fn send_raw_transaction(&self, tx: &bitcoin::Transaction) -> Result<bitcoin::Txid, Error> {
+        let tx_hex = bitcoin::consensus::encode::serialize_hex(tx);
+        self.call("sendrawtransaction", serde_json::json!([tx_hex]))
+    }
+
+    /// The node's minimum relay fee rate (per kvB).
+    pub fn get_relay_fee(&self) -> Result<bitcoin::Amount, Error> {
+        #[derive(serde::Deserialize)]
+        struct NetworkInfo {
+            #[serde(with = "bitcoin::amount::serde::as_btc")]
+            relayfee: bitcoin::Amount,
+        }
+        let info: NetworkInfo = self.call("getnetworkinfo", serde_json::json!([]))?;
+        Ok(info.relayfee)
+    }
+
+    /// The fee rate (per kvB) needed for confirming within `blocks` (if the node can estimate it).
+    pub fn estimate_smart_fee(&self, blocks: u16) -> Result<Option<bitcoin::Amount>, Error> {
+        #[derive(serde::Deserialize)]
+        struct FeeEstimate {
+            #[serde(default, with = "bitcoin::amount::serde::as_btc::opt")]
+            feerate: Option<bitcoin::Amount>,
+        }
+        let estimate: FeeEstimate = self.call("estimatesmartfee", serde_json::json!([blocks]))?;
+        Ok(estimate.feerate)
+    }
+
+    /// Requires `-txindex` for transactions in pruned blocks.
+    pub fn get_raw_transaction(&self, txid: bitcoin::Txid) -> Result<Vec<u8>, Error> {
+        let tx_hex: String = self.call("getrawtransaction", serde_json::json!([txid, false]))?;
+        Ok(hex::decode(tx_hex)?)
+    }
+
+    /// Whether the transaction is in the node's mempool (using `getmempoolentry` RPC).
+    pub fn in_mempool(&self, txid: bitcoin::Txid) -> Result<bool, Error> {
+        match self.call::<serde_json::Value>("getmempoolentry", serde_json::json!([txid])) {
+            Ok(_entry) => Ok(true),
+            Err(Error::Rpc { code: -5, .. }) => Ok(false), // RPC_INVALID_ADDRESS_OR_KEY
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_rate_limit() {
+        let mut client = Client::new(ureq::Agent::new_with_defaults(), "http://localhost:8332");
+        client.set_rate_limit(Some(20));
+        let clone = client.clone();
+        let t = Instant::now();
+        client.throttle();
+        clone.throttle();
+        client.throttle();
+        assert!(t.elapsed() >= Duration::from_millis(100));
+    }
 }
@@ -1,5 +1,7 @@
 use std::path::Path;
 
+use bitcoin::hashes::Hash;
+
 use crate::index;
 
 use log::*;
@@ -9,7 +11,7 @@ pub struct Store {
     compacting: bool,
 }
 
-fn default_opts() -> rocksdb::Options {
+fn default_opts(mem_budget: Option<usize>) -> rocksdb::Options {
     let mut opts = rocksdb::Options::default();
     opts.create_if_missing(true);
     opts.create_missing_column_families(true);
@@ -20,13 +22,27 @@ fn default_opts() -> rocksdb::Options {
     opts.set_max_open_files(256);
     opts.set_keep_log_file_num(10);
     opts.set_disable_auto_compactions(true);
+    if let Some(budget) = mem_budget {
+        // half of the budget is used for block cache, and a quarter for memtables
+        let cache = rocksdb::Cache::new_lru_cache(budget / 2);
+        let mut block_opts = rocksdb::BlockBasedOptions::default();
+        block_opts.set_block_cache(&cache);
+        opts.set_block_based_table_factory(&block_opts);
+        opts.set_db_write_buffer_size(budget / 4);
+    }
     opts
 }
 
 const HEADERS_CF: &str = "headers";
 const SCRIPT_HASH_CF: &str = "script_hash";
+const META_CF: &str = "meta";
+const FILTERS_CF: &str = "filters";
 
-const COLUMN_FAMILIES: &[&str] = &[HEADERS_CF, SCRIPT_HASH_CF];
+const COLUMN_FAMILIES: &[&str] = &[HEADERS_CF, SCRIPT_HASH_CF, META_CF, FILTERS_CF];
+
+const GENESIS_KEY: &[u8] = b"genesis";
+/// Set while a dump is being loaded (see `address::load`)
+const LOADING_KEY: &[u8] = b"loading";
 
 fn cf_descriptors(
     opts: &rocksdb::Options,
@@ -37,9 +53,18 @@ fn cf_descriptors(
 }
 
 impl Store {
-    pub fn open(path: impl AsRef<Path>) -> Result<Self, rocksdb::Error> {
-        let opts = default_opts();
-        let db = rocksdb::DB::open_cf_descriptors(&opts, path, cf_descriptors(&opts))?;
+    pub fn open(
+        path: impl AsRef<Path>,
+        mem_budget: Option<usize>,
+        read_only: bool,
+    ) -> Result<Self, rocksdb::Error> {
+        let opts = default_opts(mem_budget);
+        let db = if read_only {
+            // can be used while another process is writing to the DB
+            rocksdb::DB::open_cf_descriptors_read_only(&opts, path, cf_descriptors(&opts), false)?
+        } else {
+            rocksdb::DB::open_cf_descriptors(&opts, path, cf_descriptors(&opts))?
+        };
 
         let store = Self {
             db,
@@ -58,6 +83,14 @@ impl Store {
         Ok(store)
     }
 
+    /// Total size (in bytes) of the DB files.
+    pub fn size(&self) -> u64 {
+        COLUMN_FAMILIES
+            .iter()
+            .map(|&cf_name| self.db.get_column_family_metadata_cf(self.cf(cf_name)).size)
+            .sum()
+    }
+
     fn cf(&self, name: &str) -> &rocksdb::ColumnFamily {
         self.db
             .cf_handle(name)
@@ -76,6 +109,8 @@ impl Store {
                     .map(index::ScriptHashPrefixRow::key),
             );
         }
+        // Each (script hash prefix, txpos) pair is a separate key with an empty value,
+        // so indexing never reads existing rows (no read-modify-write or merge operator).
         script_hash_rows.sort_unstable();
         for row in script_hash_rows {
             write_batch.put_cf(cf, row, b"");
@@ -87,12 +122,86 @@ impl Store {
             write_batch.put_cf(cf, key, value);
         }
 
+        let cf = self.cf(FILTERS_CF);
+        for batch in batches {
+            if let Some(filter) = &batch.filter {
+                write_batch.put_cf(cf, batch.header.hash(), &filter.content);
+            }
+        }
+
         let mut opts = rocksdb::WriteOptions::default();
         opts.disable_wal(false);
         self.db.write_opt(write_batch, &opts)?;
         Ok(())
     }
 
+    /// Count the rows that are missing from the DB.
+    pub fn count_missing(
+        &self,
+        rows: &[index::ScriptHashPrefixRow],
+    ) -> Result<usize, rocksdb::Error> {
+        let cf = self.cf(SCRIPT_HASH_CF);
+        let mut missing = 0;
+        for row in rows {
+            if self.db.get_cf(cf, row.key())?.is_none() {
+                missing += 1;
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Write the rows that are missing from the DB, returning their number.
+    pub fn add_missing(
+        &self,
+        rows: &[index::ScriptHashPrefixRow],
+    ) -> Result<usize, rocksdb::Error> {
+        let mut write_batch = rocksdb::WriteBatch::default();
+        let cf = self.cf(SCRIPT_HASH_CF);
+        let mut missing = 0;
+        for row in rows {
+            if self.db.get_cf(cf, row.key())?.is_none() {
+                write_batch.put_cf(cf, row.key(), b"");
+                missing += 1;
+            }
+        }
+        self.db.write(write_batch)?;
+        Ok(missing)
+    }
+
+    /// Write the given rows (as-is) to the DB.
+    pub fn load(
+        &self,
+        headers: &[index::Header],
+        rows: &[index::ScriptHashPrefixRow],
+    ) -> Result<(), rocksdb::Error> {
+        let mut write_batch = rocksdb::WriteBatch::default();
+        let cf = self.cf(SCRIPT_HASH_CF);
+        for row in rows {
+            write_batch.put_cf(cf, row.key(), b"");
+        }
+        let cf = self.cf(HEADERS_CF);
+        for header in headers {
+            let (key, value) = header.serialize();
+            write_batch.put_cf(cf, key, value);
+        }
+        self.db.write(write_batch)
+    }
+
+    /// Iterate over all the script hash rows (in key order).
+    pub fn script_hash_rows(
+        &self,
+    ) -> impl Iterator<Item = Result<index::ScriptHashPrefixRow, rocksdb::Error>> + '_ {
+        let cf = self.cf(SCRIPT_HASH_CF);
+        self.db
+            .iterator_cf(cf, rocksdb::IteratorMode::Start)
+            .map(|kv| {
+                let (key, _) = kv?;
+                Ok(index::ScriptHashPrefixRow::from_bytes(
+                    key[..].try_into().unwrap(),
+                ))
+            })
+    }
+
     pub fn delete(&self, batches: &[index::Batch]) -> Result<(), rocksdb::Error> {
         let mut write_batch = rocksdb::WriteBatch::default();
         let cf = self.cf(SCRIPT_HASH_CF);
@@ -118,12 +227,64 @@ impl Store {
             write_batch.delete_cf(cf, key);
         }
 
+        let cf = self.cf(FILTERS_CF);
+        for batch in batches {
+            write_batch.delete_cf(cf, batch.header.hash());
+        }
+
         let mut opts = rocksdb::WriteOptions::default();
         opts.disable_wal(true);
         self.db.write_opt(write_batch, &opts)?;
         Ok(())
     }
 
+    /// Delete the rows starting from `txpos` and the given header, by scanning all the rows.
+    /// Only a fallback for `delete()`, when the stale block (needed for deriving its rows) is not
+    /// available. Returns the number of deleted rows.
+    pub fn delete_from(
+        &self,
+        txpos: index::TxPos,
+        header: &index::Header,
+    ) -> Result<usize, rocksdb::Error> {
+        warn!(
+            "scanning all rows to delete block={} (its contents are not available)",
+            header.hash()
+        );
+        let start = std::time::Instant::now();
+        let mut write_batch = rocksdb::WriteBatch::default();
+        let cf = self.cf(SCRIPT_HASH_CF);
+        let (mut scanned, mut deleted) = (0, 0);
+        for row in self.script_hash_rows() {
+            let row = row?;
+            scanned += 1;
+            if row.txpos() >= txpos {
+                write_batch.delete_cf(cf, row.key());
+                deleted += 1;
+            }
+        }
+        warn!(
+            "scanned {} rows in {:.3}s, deleting {} rows of block={}",
+            scanned,
+            start.elapsed().as_secs_f64(),
+            deleted,
+            header.hash()
+        );
+        let (key, _value) = header.serialize();
+        write_batch.delete_cf(self.cf(HEADERS_CF), key);
+        write_batch.delete_cf(self.cf(FILTERS_CF), header.hash());
+        self.db.write(write_batch)?;
+        Ok(deleted)
+    }
+
+    /// Returns the block's BIP158 filter (if it was stored).
+    pub fn get_filter(
+        &self,
+        hash: bitcoin::BlockHash,
+    ) -> Result<Option<bitcoin::bip158::BlockFilter>, rocksdb::Error> {
+        let content = self.db.get_cf(self.cf(FILTERS_CF), hash)?;
+        Ok(content.map(|content| bitcoin::bip158::BlockFilter::new(&content)))
+    }
+
     pub fn flush(&self) -> Result<(), rocksdb::Error> {
         let opts = rocksdb::FlushOptions::new();
         for cf in COLUMN_FAMILIES {
@@ -145,19 +306,66 @@ impl Store {
         Ok(())
     }
 
-    pub fn scan(&self, script: &bitcoin::Script) -> Result<Vec<index::TxPos>, rocksdb::Error> {
-        let prefix = index::ScriptHashPrefix::new(script);
+    /// Returns the transaction positions (within `positions`) matching the given script.
+    pub fn scan(
+        &self,
+        script: &bitcoin::Script,
+        positions: std::ops::Range<index::TxPos>,
+    ) -> Result<Vec<index::TxPos>, rocksdb::Error> {
+        self.scan_prefix(index::ScriptHashPrefix::new(script), positions)
+    }
+
+    /// Returns the transaction positions (within `positions`) matching the given script hash prefix.
+    pub fn scan_prefix(
+        &self,
+        prefix: index::ScriptHashPrefix,
+        positions: std::ops::Range<index::TxPos>,
+    ) -> Result<Vec<index::TxPos>, rocksdb::Error> {
+        self.scan_prefix_iter(prefix, positions).collect()
+    }
+
+    /// Iterate lazily over the transaction positions (within `positions`, in increasing order)
+    /// matching the given script hash prefix.
+    pub fn scan_prefix_iter(
+        &self,
+        prefix: index::ScriptHashPrefix,
+        positions: std::ops::Range<index::TxPos>,
+    ) -> impl Iterator<Item = Result<index::TxPos, rocksdb::Error>> + '_ {
+        let start = index::ScriptHashPrefixRow::new(prefix, positions.start);
         let cf = self.cf(SCRIPT_HASH_CF);
-        let mut result = vec![];
-        for kv in self.db.prefix_iterator_cf(cf, prefix.as_bytes()) {
-            let (key, _) = kv?;
+        let mode = rocksdb::IteratorMode::From(start.key(), rocksdb::Direction::Forward);
+        self.db.iterator_cf(cf, mode).map_while(move |kv| {
+            let (key, _) = match kv {
+                Ok(kv) => kv,
+                Err(e) => return Some(Err(e)),
+            };
             if !key.starts_with(prefix.as_bytes()) {
-                break;
+                return None;
             }
             let row = index::ScriptHashPrefixRow::from_bytes(key[..].try_into().unwrap());
-            result.push(row.txpos());
+            (row.txpos() < positions.end).then_some(Ok(row.txpos()))
+        })
+    }
+
+    pub fn genesis_hash(&self) -> Result<Option<bitcoin::BlockHash>, rocksdb::Error> {
+        let value = self.db.get_cf(self.cf(META_CF), GENESIS_KEY)?;
+        Ok(value.map(|v| bitcoin::BlockHash::from_byte_array(v[..].try_into().unwrap())))
+    }
+
+    pub fn set_genesis_hash(&self, hash: bitcoin::BlockHash) -> Result<(), rocksdb::Error> {
+        self.db
+            .put_cf(self.cf(META_CF), GENESIS_KEY, hash.as_byte_array())
+    }
+
+    pub fn is_loading(&self) -> Result<bool, rocksdb::Error> {
+        Ok(self.db.get_cf(self.cf(META_CF), LOADING_KEY)?.is_some())
+    }
+
+    pub fn set_loading(&self, loading: bool) -> Result<(), rocksdb::Error> {
+        match loading {
+            true => self.db.put_cf(self.cf(META_CF), LOADING_KEY, []),
+            false => self.db.delete_cf(self.cf(META_CF), LOADING_KEY),
         }
-        Ok(result)
     }
 
     pub fn headers(&self) -> Result<Vec<index::Header>, rocksdb::Error> {
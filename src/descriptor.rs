@@ -0,0 +1,246 @@
+//! Output script descriptors (BIP380-386, parsed using the `miniscript` crate), used for deriving
+//! the watched scripts.
+
+mod miniscript;
+
+use std::str::FromStr;
+
+use ::miniscript::{
+    descriptor::{ConversionError, DescriptorPublicKey, Wildcard},
+    ForEachKey,
+};
+use bitcoin::{
+    bip32::{ChildNumber, DerivationPath},
+    secp256k1::{Secp256k1, Verification},
+    NetworkKind, ScriptBuf,
+};
+
+pub use miniscript::{spend_path, SpendPath};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("invalid descriptor: {0}")]
+    Parse(#[from] ::miniscript::Error),
+
+    #[error("invalid key: {0}")]
+    Key(#[from] ConversionError),
+
+    #[error("unsupported descriptor: {0}")]
+    Unsupported(String),
+}
+
+/// The derivation path of the child key (starting from the key origin's fingerprint,
+/// or the extended key's own fingerprint), e.g. `d34db33f/48'/0'/0'/2'/0/5`.
+fn key_path(key: &DescriptorPublicKey, index: u32) -> Option<String> {
+    let (fingerprint, mut path) = match key {
+        DescriptorPublicKey::Single(single) => single.origin.clone()?,
+        DescriptorPublicKey::XPub(xkey) => match &xkey.origin {
+            Some(origin) => origin.clone(),
+            None => (xkey.xkey.fingerprint(), DerivationPath::master()),
+        },
+        DescriptorPublicKey::MultiXPub(_) => return None,
+    };
+    if let DescriptorPublicKey::XPub(xkey) = key {
+        path = path.extend(&xkey.derivation_path);
+        if xkey.wildcard != Wildcard::None {
+            path = path.child(ChildNumber::from_normal_idx(index).ok()?);
+        }
+    }
+    if path.is_master() {
+        return Some(fingerprint.to_string());
+    }
+    Some(format!("{}/{}", fingerprint, path))
+}
+
+/// A parsed descriptor, e.g. `wpkh(xpub/0/*)`, `sh(wpkh(...))`, `tr(xpub/0/*,pk(...))`
+/// or `wsh(sortedmulti(2,xpub1/0/*,xpub2/0/*))`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Descriptor(::miniscript::Descriptor<DescriptorPublicKey>);
+
+impl FromStr for Descriptor {
+    type Err = Error;
+
+    /// Verifies the checksum (if any), and rejects insane (e.g. malleable) miniscripts.
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let desc = ::miniscript::Descriptor::<DescriptorPublicKey>::from_str(s)?;
+        if desc.is_multipath() {
+            return Err(Error::Unsupported(format!("{}: multipath keys", s)));
+        }
+        // the xpubs' children can't be derived
+        let hardened = |key: &DescriptorPublicKey| match key {
+            DescriptorPublicKey::XPub(xkey) => {
+                key.has_hardened_step() || xkey.wildcard == Wildcard::Hardened
+            }
+            _ => false,
+        };
+        if desc.for_any_key(hardened) {
+            return Err(Error::Unsupported(format!("{}: hardened derivation", s)));
+        }
+        desc.sanity_check()?;
+        Ok(Descriptor(desc))
+    }
+}
+
+impl Descriptor {
+    /// Whether the descriptor derives a different script for each child index.
+    pub fn is_ranged(&self) -> bool {
+        self.0.has_wildcard()
+    }
+
+    /// The networks of the descriptor's extended keys.
+    pub fn networks(&self) -> impl Iterator<Item = NetworkKind> + '_ {
+        let mut networks = vec![];
+        self.0.for_each_key(|key| {
+            if let DescriptorPublicKey::XPub(xkey) = key {
+                networks.push(xkey.xkey.network);
+            }
+            true
+        });
+        networks.into_iter()
+    }
+
+    /// The derivation paths of the keys used by the given child index's script.
+    pub fn key_paths(&self, index: u32) -> Vec<String> {
+        let mut paths = vec![];
+        self.0.for_each_key(|key| {
+            paths.extend(key_path(key, index));
+            true
+        });
+        paths
+    }
+
+    /// The output script for the given child index (ignored if the descriptor is not ranged).
+    pub fn script_pubkey<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        index: u32,
+    ) -> Result<ScriptBuf, Error> {
+        Ok(self.0.derived_descriptor(secp, index)?.script_pubkey())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::PublicKey;
+
+    pub(super) const KEYS: [&str; 3] = [
+        "03a0434d9e47f3c86235477c7b1ae6ae5d3442d49b1943c2b752a68e2a47e247c7",
+        "03774ae7f858a9411e5ef4246b70c65aac5649980be5c17891bbec17895da008cb",
+        "03d01115d548e7561b15c38f004d734633687cf4419620095bc5b0f47070afe85a",
+    ];
+
+    // BIP32 test vector 1 (master key)
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+    #[test]
+    fn test_checksum() -> Result<(), Error> {
+        let desc = format!("wsh(multi(1,{}))", KEYS[0]);
+        let with_checksum = format!("{}#4u527yne", desc);
+        assert_eq!(
+            Descriptor::from_str(&with_checksum)?,
+            Descriptor::from_str(&desc)?
+        );
+        assert!(Descriptor::from_str(&format!("{}#00000000", desc)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sortedmulti() -> Result<(), Error> {
+        let secp = Secp256k1::verification_only();
+        let desc = |name: &str, keys: &[&str]| {
+            Descriptor::from_str(&format!("wsh({}(2,{}))", name, keys.join(",")))
+        };
+        let sorted = desc("sortedmulti", &KEYS)?.script_pubkey(&secp, 0)?;
+        let reversed = [KEYS[2], KEYS[1], KEYS[0]];
+        assert_eq!(
+            desc("sortedmulti", &reversed)?.script_pubkey(&secp, 0)?,
+            sorted
+        );
+        assert_ne!(desc("multi", &reversed)?.script_pubkey(&secp, 0)?, sorted);
+        assert!(sorted.is_p2wsh());
+        assert!(!desc("sortedmulti", &KEYS)?.is_ranged());
+        assert!(desc("sortedmulti", &KEYS[..1]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ranged() -> Result<(), Error> {
+        let secp = Secp256k1::verification_only();
+        let desc = Descriptor::from_str(&format!("wsh(sortedmulti(1,{}/0/*,{}))", XPUB, KEYS[0]))?;
+        assert!(desc.is_ranged());
+        assert_eq!(desc.networks().collect::<Vec<_>>(), [NetworkKind::Main]);
+        assert_eq!(desc.key_paths(5), ["3442193e/0/5"]);
+        assert_ne!(desc.script_pubkey(&secp, 0)?, desc.script_pubkey(&secp, 1)?);
+        let desc = Descriptor::from_str(&format!("wsh(pk([d34db33f/48'/0'/0'/2']{}/1/*))", XPUB))?;
+        assert_eq!(desc.key_paths(7), ["d34db33f/48'/0'/0'/2'/1/7"]);
+        assert!(Descriptor::from_str(&format!("wsh(multi(1,{}/0'/*))", XPUB)).is_err());
+        assert!(Descriptor::from_str(&format!("wsh(multi(1,{}/*/0))", XPUB)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_single_key() -> Result<(), Error> {
+        let secp = Secp256k1::verification_only();
+        let pubkey = PublicKey::from_str(KEYS[0]).unwrap();
+        let script = |desc: &str| Descriptor::from_str(desc)?.script_pubkey(&secp, 0);
+        assert_eq!(
+            script(&format!("pkh({})", KEYS[0]))?,
+            ScriptBuf::new_p2pkh(&pubkey.pubkey_hash())
+        );
+        let wpkh = script(&format!("wpkh({})", KEYS[0]))?;
+        assert_eq!(wpkh, ScriptBuf::new_p2wpkh(&pubkey.wpubkey_hash().unwrap()));
+        assert_eq!(
+            script(&format!("sh(wpkh({}))", KEYS[0]))?,
+            ScriptBuf::new_p2sh(&wpkh.script_hash())
+        );
+        let xonly = &KEYS[0][2..];
+        assert_eq!(
+            script(&format!("tr({})", KEYS[0]))?,
+            script(&format!("tr({})", xonly))?
+        );
+        assert!(script(&format!("wpkh(wpkh({}))", KEYS[0])).is_err());
+        assert!(script(&format!("sh(sh(wpkh({})))", KEYS[0])).is_err());
+        assert!(script(&format!("wsh(tr({}))", KEYS[0])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_taproot_tree() -> Result<(), Error> {
+        let secp = Secp256k1::verification_only();
+        let script = |desc: &str| Descriptor::from_str(desc)?.script_pubkey(&secp, 0);
+        let key_only = script(&format!("tr({})", KEYS[0]))?;
+        let single = script(&format!("tr({},pk({}))", KEYS[0], KEYS[1]))?;
+        let tree = script(&format!(
+            "tr({},{{pk({}),pk({})}})",
+            KEYS[0], KEYS[1], KEYS[2]
+        ))?;
+        assert!(single.is_p2tr() && tree.is_p2tr());
+        assert_ne!(single, key_only);
+        assert_ne!(tree, single);
+        let desc = Descriptor::from_str(&format!("tr({},pk({}/0/*))", KEYS[0], XPUB))?;
+        assert!(desc.is_ranged());
+        assert_eq!(desc.key_paths(3), ["3442193e/0/3"]);
+        assert_ne!(desc.script_pubkey(&secp, 0)?, desc.script_pubkey(&secp, 1)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bip86() -> Result<(), Error> {
+        // https://github.com/bitcoin/bips/blob/master/bip-0086.mediawiki#test-vectors
+        let secp = Secp256k1::verification_only();
+        let desc = Descriptor::from_str("tr([73c5da0a/86'/0'/0']xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2afYWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ/0/*)")?;
+        let addr = bitcoin::Address::from_script(
+            &desc.script_pubkey(&secp, 0)?,
+            bitcoin::Network::Bitcoin,
+        )
+        .unwrap();
+        assert_eq!(
+            addr.to_string(),
+            "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"
+        );
+        assert_eq!(desc.key_paths(0), ["73c5da0a/86'/0'/0'/0/0"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+//! Describing which spending path a P2WSH miniscript (BIP379) input has used.
+
+use ::miniscript::interpreter::{Interpreter, SatisfiedConstraint};
+use bitcoin::{absolute, PublicKey, Script, ScriptBuf, TxIn};
+
+/// The spending conditions satisfied by a P2WSH input (assuming its signatures are valid)
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SpendPath {
+    /// Keys with an (unverified) signature, including `multi()` ones
+    pub signed: Vec<PublicKey>,
+    /// `older()` arguments
+    pub older: Vec<u32>,
+    /// `after()` arguments
+    pub after: Vec<u32>,
+    /// Number of revealed hash preimages
+    pub preimages: usize,
+}
+
+impl std::fmt::Display for SpendPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = vec![];
+        for key in &self.signed {
+            parts.push(format!("sig({})", &key.to_string()[..8]));
+        }
+        for n in &self.older {
+            parts.push(format!("older({})", n));
+        }
+        for n in &self.after {
+            parts.push(format!("after({})", n));
+        }
+        if self.preimages > 0 {
+            parts.push(format!("preimages({})", self.preimages));
+        }
+        write!(f, "{}", parts.join("+"))
+    }
+}
+
+/// Describe the spending path of a P2WSH input (of a transaction with the given lock time),
+/// using the satisfied constraints of its witness script (if it is a miniscript).
+pub fn spend_path(txin: &TxIn, lock_time: absolute::LockTime) -> Option<SpendPath> {
+    let witness_script = Script::from_bytes(txin.witness.last()?);
+    let script_pubkey = ScriptBuf::new_p2wsh(&witness_script.wscript_hash());
+    let interpreter = Interpreter::from_txdata(
+        &script_pubkey,
+        &txin.script_sig,
+        &txin.witness,
+        txin.sequence,
+        lock_time,
+    )
+    .ok()?;
+    let mut path = SpendPath::default();
+    for constraint in interpreter.iter_assume_sigs() {
+        match constraint.ok()? {
+            SatisfiedConstraint::PublicKey { key_sig }
+            | SatisfiedConstraint::PublicKeyHash { key_sig, .. } => {
+                path.signed.push(key_sig.as_ecdsa()?.0);
+            }
+            SatisfiedConstraint::RelativeTimelock { n } => path.older.push(n.to_consensus_u32()),
+            SatisfiedConstraint::AbsoluteTimelock { n } => path.after.push(n.to_consensus_u32()),
+            SatisfiedConstraint::HashLock { .. } => path.preimages += 1,
+        }
+    }
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use ::miniscript::{Miniscript, Segwitv0};
+    use bitcoin::{
+        secp256k1::{self, Secp256k1},
+        Sequence, Witness,
+    };
+
+    use crate::descriptor::{tests::KEYS, Descriptor, Error};
+
+    /// The witness script of `wsh(ms)` (verifying that it matches the descriptor's script).
+    fn witness_script(ms: &str) -> Result<ScriptBuf, Error> {
+        let secp = Secp256k1::verification_only();
+        let script = Miniscript::<PublicKey, Segwitv0>::from_str(ms)?.encode();
+        let desc = Descriptor::from_str(&format!("wsh({})", ms))?;
+        assert_eq!(
+            desc.script_pubkey(&secp, 0)?,
+            ScriptBuf::new_p2wsh(&script.wscript_hash())
+        );
+        Ok(script)
+    }
+
+    #[test]
+    fn test_compile() -> Result<(), Error> {
+        let ms = format!("and_v(v:pk({}),older(144))", KEYS[0]);
+        let expected = format!("21{}ad029000b2", KEYS[0]);
+        assert_eq!(witness_script(&ms)?.to_hex_string(), expected);
+
+        let ms = format!("or_d(pk({}),and_v(v:pk({}),older(1000)))", KEYS[0], KEYS[1]);
+        let expected = format!("21{}ac736421{}ad02e803b268", KEYS[0], KEYS[1]);
+        assert_eq!(witness_script(&ms)?.to_hex_string(), expected);
+
+        let wsh = |ms: &str| Descriptor::from_str(&format!("wsh({})", ms));
+        assert!(wsh("x:older(1)").is_err());
+        assert!(wsh("thresh(3,older(1),older(2))").is_err());
+        // ill-typed fragments
+        assert!(wsh(&format!("and_v(pk({}),pk({}))", KEYS[0], KEYS[1])).is_err());
+        assert!(wsh(&format!("or_b(pk({}),pk({}))", KEYS[0], KEYS[1])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_spend_path() -> Result<(), Error> {
+        let ms = format!("or_d(pk({}),and_v(v:pk({}),older(1000)))", KEYS[0], KEYS[1]);
+        let script = witness_script(&ms)?;
+        // the signatures are not verified (so any valid encoding will do)
+        let secp = Secp256k1::signing_only();
+        let msg = secp256k1::Message::from_digest([1; 32]);
+        let secret = secp256k1::SecretKey::from_slice(&[1; 32]).unwrap();
+        let sig = bitcoin::ecdsa::Signature::sighash_all(secp.sign_ecdsa(&msg, &secret)).to_vec();
+        let txin = |witness: &[Vec<u8>], sequence| TxIn {
+            witness: Witness::from_slice(witness),
+            sequence,
+            ..Default::default()
+        };
+        let lock_time = absolute::LockTime::ZERO;
+
+        // the primary key signs
+        let witness = [sig.clone(), script.to_bytes()];
+        let path = spend_path(&txin(&witness, Sequence::MAX), lock_time).unwrap();
+        assert_eq!(path.signed, [PublicKey::from_str(KEYS[0]).unwrap()]);
+        assert!(path.older.is_empty());
+
+        // the recovery key signs (after the timelock)
+        let witness = [sig, vec![], script.to_bytes()];
+        let sequence = Sequence::from_height(1000);
+        let path = spend_path(&txin(&witness, sequence), lock_time).unwrap();
+        assert_eq!(path.signed, [PublicKey::from_str(KEYS[1]).unwrap()]);
+        assert_eq!(path.older, [1000]);
+        assert_eq!(
+            path.to_string(),
+            format!("sig({})+older(1000)", &KEYS[1][..8])
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,439 @@
+//! A minimal Electrum protocol server (confirmed history only).
+//!
+//! https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread,
+};
+
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use log::*;
+use serde_json::{json, Value};
+
+use crate::{
+    address::{self, TxPosition},
+    index::ScriptHash,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("I/O failed: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("bad JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("request is longer than {0} bytes")]
+    LineTooLong(usize),
+}
+
+const PROTOCOL_VERSION: &str = "1.4";
+
+/// Enough for broadcasting standard transactions (as hex)
+const MAX_LINE_BYTES: usize = 1 << 20;
+
+/// A subscribed script hash, and the last notified positions and status.
+struct Subscription {
+    /// As sent by the client
+    hex: String,
+    positions: Vec<TxPosition>,
+    status: Option<String>,
+}
+
+/// A connected client.
+struct Session {
+    writer: Mutex<TcpStream>,
+    headers: AtomicBool,
+    scripthashes: Mutex<HashMap<ScriptHash, Subscription>>,
+}
+
+impl Session {
+    fn send(&self, message: &Value) -> Result<(), Error> {
+        let mut writer = self.writer.lock().unwrap();
+        serde_json::to_writer(&mut *writer, message)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+pub struct Server<'a> {
+    index: &'a Mutex<address::Index>,
+    /// Used for fetching from the node without locking the index
+    fetcher: address::Fetcher,
+    sessions: Mutex<Vec<Weak<Session>>>,
+}
+
+impl<'a> Server<'a> {
+    pub fn new(index: &'a Mutex<address::Index>) -> Self {
+        let fetcher = index.lock().expect("index lock poisoned").fetcher();
+        Self {
+            index,
+            fetcher,
+            sessions: Mutex::new(vec![]),
+        }
+    }
+
+    /// Accept Electrum clients, handling each one in a separate thread.
+    pub fn serve(&self, listener: TcpListener) -> Result<(), Error> {
+        thread::scope(|s| {
+            for stream in listener.incoming() {
+                let stream = stream?;
+                let peer = stream.peer_addr()?;
+                debug!("{} connected", peer);
+                s.spawn(move || match self.handle_client(stream) {
+                    Ok(()) => debug!("{} disconnected", peer),
+                    Err(e) => warn!("{} failed: {}", peer, e),
+                });
+            }
+            Ok(())
+        })
+    }
+
+    /// Notify the subscribed clients about the new tip, and about their script hashes'
+    /// new statuses (should be called after new blocks are indexed).
+    pub fn notify(&self) {
+        let sessions: Vec<Arc<Session>> = {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.retain(|session| session.strong_count() > 0);
+            sessions.iter().filter_map(Weak::upgrade).collect()
+        };
+        if sessions.is_empty() {
+            return;
+        }
+        let tip = self.tip().ok();
+        for session in sessions {
+            if let Err(e) = self.notify_session(&session, tip.as_ref()) {
+                debug!("notification failed: {}", e);
+            }
+        }
+    }
+
+    fn notify_session(&self, session: &Session, tip: Option<&Value>) -> Result<(), Error> {
+        if let Some(tip) = tip.filter(|_| session.headers.load(Ordering::Relaxed)) {
+            session.send(&json!({
+                "jsonrpc": "2.0",
+                "method": "blockchain.headers.subscribe",
+                "params": [tip],
+            }))?;
+        }
+        let script_hashes: Vec<ScriptHash> = session
+            .scripthashes
+            .lock()
+            .unwrap()
+            .keys()
+            .copied()
+            .collect();
+        for script_hash in script_hashes {
+            let positions = match self.find_positions(&script_hash) {
+                Ok(positions) => positions,
+                Err(e) => {
+                    warn!("failed to find {}: {}", script_hash, e);
+                    continue;
+                }
+            };
+            let unchanged = |subs: &HashMap<ScriptHash, Subscription>| {
+                subs.get(&script_hash)
+                    .is_none_or(|sub| sub.positions == positions)
+            };
+            // only the scripts with new (or reorged) rows are re-fetched
+            if unchanged(&session.scripthashes.lock().unwrap()) {
+                continue;
+            }
+            let history = match self.fetcher.script_history(&script_hash, &positions) {
+                Ok(history) => history,
+                Err(e) => {
+                    warn!("failed to get {} history: {}", script_hash, e);
+                    continue;
+                }
+            };
+            let status = status(&history);
+            let notification = {
+                let mut subs = session.scripthashes.lock().unwrap();
+                let Some(sub) = subs.get_mut(&script_hash) else {
+                    continue;
+                };
+                sub.positions = positions;
+                if sub.status == status {
+                    continue;
+                }
+                sub.status.clone_from(&status);
+                json!({
+                    "jsonrpc": "2.0",
+                    "method": "blockchain.scripthash.subscribe",
+                    "params": [sub.hex, status],
+                })
+            };
+            session.send(&notification)?;
+        }
+        Ok(())
+    }
+
+    fn handle_client(&self, stream: TcpStream) -> Result<(), Error> {
+        let session = Arc::new(Session {
+            writer: Mutex::new(stream.try_clone()?),
+            headers: AtomicBool::new(false),
+            scripthashes: Mutex::new(HashMap::new()),
+        });
+        self.sessions.lock().unwrap().push(Arc::downgrade(&session));
+        let mut reader = BufReader::new(stream);
+        let mut line = vec![];
+        loop {
+            line.clear();
+            let limit = u64::try_from(MAX_LINE_BYTES).unwrap() + 1;
+            if (&mut reader).take(limit).read_until(b'\n', &mut line)? == 0 {
+                return Ok(());
+            }
+            if line.len() > MAX_LINE_BYTES && !line.ends_with(b"\n") {
+                return Err(Error::LineTooLong(MAX_LINE_BYTES));
+            }
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_slice::<Value>(&line) {
+                Ok(Value::Array(requests)) => Value::Array(
+                    requests
+                        .iter()
+                        .map(|request| self.handle_request(&session, request))
+                        .collect(),
+                ),
+                Ok(request) => self.handle_request(&session, &request),
+                Err(e) => json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": {"code": -32700, "message": e.to_string()},
+                }),
+            };
+            session.send(&response)?;
+        }
+    }
+
+    fn handle_request(&self, session: &Session, request: &Value) -> Value {
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let method = request.get("method").and_then(Value::as_str).unwrap_or("");
+        let no_params = vec![];
+        let params = match request.get("params") {
+            Some(Value::Array(params)) => params,
+            _ => &no_params,
+        };
+        match self.call(session, method, params) {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err(message) => {
+                debug!("{} failed: {}", method, message);
+                json!({"jsonrpc": "2.0", "id": id, "error": {"code": 1, "message": message}})
+            }
+        }
+    }
+
+    /// The index is locked only for reading its DB, and not while fetching from the node.
+    fn call(&self, session: &Session, method: &str, params: &[Value]) -> Result<Value, String> {
+        Ok(match method {
+            "server.version" => json!([
+                concat!("bindex ", env!("CARGO_PKG_VERSION")),
+                PROTOCOL_VERSION
+            ]),
+            "server.banner" => json!("bindex"),
+            "server.ping" => Value::Null,
+            "server.features" => json!({
+                "genesis_hash": self.lock().genesis_hash(),
+                "hosts": {},
+                "protocol_min": PROTOCOL_VERSION,
+                "protocol_max": PROTOCOL_VERSION,
+                "pruning": null,
+                "server_version": concat!("bindex ", env!("CARGO_PKG_VERSION")),
+                "hash_function": "sha256",
+            }),
+            "blockchain.headers.subscribe" => {
+                let tip = self.tip()?;
+                session.headers.store(true, Ordering::Relaxed);
+                tip
+            }
+            "blockchain.block.header" => {
+                let height = params
+                    .first()
+                    .and_then(Value::as_u64)
+                    .ok_or("missing height")?;
+                json!(header_hex(&self.lock(), height as usize)?)
+            }
+            "blockchain.relayfee" => {
+                let fee = self.fetcher.get_relay_fee().map_err(|e| e.to_string())?;
+                json!(fee.to_btc())
+            }
+            "blockchain.estimatefee" => {
+                let blocks = params
+                    .first()
+                    .and_then(Value::as_u64)
+                    .ok_or("missing number of blocks")?;
+                let blocks = u16::try_from(blocks).map_err(|e| e.to_string())?;
+                match self.fetcher.estimate_fee(blocks) {
+                    Ok(Some(fee)) => json!(fee.to_btc()),
+                    // as specified by the protocol
+                    Ok(None) => json!(-1),
+                    Err(e) => return Err(e.to_string()),
+                }
+            }
+            "blockchain.transaction.get" => {
+                let txid = params
+                    .first()
+                    .and_then(Value::as_str)
+                    .ok_or("missing txid")?;
+                let txid = bitcoin::Txid::from_str(txid).map_err(|e| e.to_string())?;
+                if params.get(1).and_then(Value::as_bool).unwrap_or(false) {
+                    return Err("verbose transactions are not supported".to_owned());
+                }
+                let tx_bytes = self
+                    .fetcher
+                    .get_raw_transaction(txid)
+                    .map_err(|e| e.to_string())?;
+                json!(hex::encode(tx_bytes))
+            }
+            "blockchain.transaction.broadcast" => {
+                let tx_hex = params
+                    .first()
+                    .and_then(Value::as_str)
+                    .ok_or("missing raw transaction")?;
+                let tx: bitcoin::Transaction = bitcoin::consensus::encode::deserialize_hex(tx_hex)
+                    .map_err(|e| format!("invalid transaction: {}", e))?;
+                let txid = self
+                    .fetcher
+                    .send_raw_transaction(&tx)
+                    .map_err(|e| e.to_string())?;
+                json!(txid)
+            }
+            "blockchain.scripthash.get_history" => {
+                let history = self.script_history(params)?.1;
+                history
+                    .txs
+                    .iter()
+                    .map(|(height, txid)| json!({"tx_hash": txid, "height": height}))
+                    .collect()
+            }
+            "blockchain.scripthash.get_balance" => {
+                let history = self.script_history(params)?.1;
+                let confirmed: bitcoin::Amount =
+                    history.unspent.values().map(|(_, value)| *value).sum();
+                json!({"confirmed": confirmed.to_sat(), "unconfirmed": 0})
+            }
+            "blockchain.scripthash.listunspent" => {
+                let history = self.script_history(params)?.1;
+                history
+                    .unspent
+                    .iter()
+                    .map(|(outpoint, (height, value))| {
+                        json!({
+                            "tx_hash": outpoint.txid,
+                            "tx_pos": outpoint.vout,
+                            "height": height,
+                            "value": value.to_sat(),
+                        })
+                    })
+                    .collect()
+            }
+            "blockchain.scripthash.subscribe" => {
+                let (script_hash, history, positions) = self.script_history(params)?;
+                let status = status(&history);
+                let hex = params[0].as_str().unwrap_or_default().to_owned();
+                session.scripthashes.lock().unwrap().insert(
+                    script_hash,
+                    Subscription {
+                        hex,
+                        positions,
+                        status: status.clone(),
+                    },
+                );
+                status.map_or(Value::Null, |status| json!(status))
+            }
+            "blockchain.scripthash.unsubscribe" => {
+                let script_hash = script_hash_param(params)?;
+                let removed = session.scripthashes.lock().unwrap().remove(&script_hash);
+                json!(removed.is_some())
+            }
+            _ => return Err(format!("unsupported method: {:?}", method)),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, address::Index> {
+        self.index.lock().expect("index lock poisoned")
+    }
+
+    fn tip(&self) -> Result<Value, String> {
+        let index = self.lock();
+        match index.tip_height() {
+            Some(height) => Ok(json!({"height": height, "hex": header_hex(&index, height)?})),
+            None => Err("no indexed blocks".to_owned()),
+        }
+    }
+
+    fn find_positions(&self, script_hash: &ScriptHash) -> Result<Vec<TxPosition>, address::Error> {
+        self.lock().find_positions(script_hash)
+    }
+
+    /// The transactions are fetched after the index is unlocked.
+    fn script_history(
+        &self,
+        params: &[Value],
+    ) -> Result<(ScriptHash, address::ScriptHistory, Vec<TxPosition>), String> {
+        let script_hash = script_hash_param(params)?;
+        let positions = self
+            .find_positions(&script_hash)
+            .map_err(|e| e.to_string())?;
+        let history = self
+            .fetcher
+            .script_history(&script_hash, &positions)
+            .map_err(|e| e.to_string())?;
+        Ok((script_hash, history, positions))
+    }
+}
+
+fn header_hex(index: &address::Index, height: usize) -> Result<String, String> {
+    let header = index
+        .get_header(height)
+        .ok_or_else(|| format!("height {} is not indexed", height))?;
+    Ok(bitcoin::consensus::encode::serialize_hex(header))
+}
+
+fn script_hash_param(params: &[Value]) -> Result<ScriptHash, String> {
+    let hex = params
+        .first()
+        .and_then(Value::as_str)
+        .ok_or("missing scripthash")?;
+    ScriptHash::from_str(hex).map_err(|e| format!("invalid scripthash: {}", e))
+}
+
+/// https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-basics.html#status
+fn status(history: &address::ScriptHistory) -> Option<String> {
+    if history.txs.is_empty() {
+        return None;
+    }
+    let mut engine = sha256::Hash::engine();
+    for (height, txid) in &history.txs {
+        engine.input(format!("{}:{}:", txid, height).as_bytes());
+    }
+    Some(sha256::Hash::from_engine(engine).to_string())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_hash_param() {
+        // https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-basics.html#script-hashes
+        let addr = bitcoin::Address::from_str("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa")
+            .unwrap()
+            .assume_checked();
+        let hex = "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161";
+        assert_eq!(
+            script_hash_param(&[json!(hex)]).unwrap(),
+            ScriptHash::hash(addr.script_pubkey().as_bytes())
+        );
+        assert!(script_hash_param(&[json!("00")]).is_err());
+        assert!(script_hash_param(&[]).is_err());
+    }
+}
@@ -0,0 +1,275 @@
+//! A minimal HTTP server for querying the index (using JSON responses):
+//! - `GET /address/{address}/history`: the address' confirmed transactions (in confirmation order)
+//! - `GET /address/{address}/balance`: the address' confirmed balance (in satoshis)
+//! - `GET /events`: server-sent events, reporting the new blocks with transactions touching
+//!   the watched addresses (`block`) and the rolled back blocks (`rollback`)
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Write,
+    net::{TcpListener, TcpStream},
+    str::FromStr,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use bitcoin::hashes::Hash;
+use log::*;
+use serde_json::{json, Value};
+
+use crate::{address, httpd, index::ScriptHash};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("I/O failed: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Idle event streams are sent a comment (so proxies and clients don't time them out)
+const KEEPALIVE_PERIOD: Duration = Duration::from_secs(15);
+
+/// The senders of the connected `/events` clients
+type EventClients = Arc<Mutex<Vec<mpsc::Sender<String>>>>;
+
+/// Accept HTTP clients, handling each one (a single request per connection) in a separate thread.
+/// The `events` (see `Index::subscribe`) are streamed to the `/events` clients.
+pub fn serve(
+    index: &Mutex<address::Index>,
+    listener: TcpListener,
+    network: bitcoin::Network,
+    events: Option<mpsc::Receiver<address::Event>>,
+) -> Result<(), Error> {
+    let clients = events.map(|events| {
+        let clients = EventClients::default();
+        let forwarded = clients.clone();
+        thread::spawn(move || forward_events(events, &forwarded, network));
+        clients
+    });
+    let fetcher = index.lock().expect("index lock poisoned").fetcher();
+    thread::scope(|s| {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let clients = clients.as_ref();
+            let fetcher = &fetcher;
+            s.spawn(move || {
+                if let Err(e) = handle_client(index, fetcher, stream, network, clients) {
+                    warn!("HTTP client failed: {}", e);
+                }
+            });
+        }
+        Ok(())
+    })
+}
+
+/// Send each indexed block's events (as a single message) to all the `/events` clients.
+fn forward_events(
+    events: mpsc::Receiver<address::Event>,
+    clients: &Mutex<Vec<mpsc::Sender<String>>>,
+    network: bitcoin::Network,
+) {
+    // the transactions' offsets and addresses (by block height)
+    let mut blocks = BTreeMap::<usize, BTreeMap<u64, BTreeSet<String>>>::new();
+    while let Ok(event) = events.recv() {
+        // a block's events are sent together (after it is indexed)
+        for event in std::iter::once(event).chain(events.try_iter()) {
+            match event {
+                address::Event::Tx {
+                    script,
+                    height,
+                    offset,
+                } => {
+                    let addr = bitcoin::Address::from_script(&script, network)
+                        .map_or_else(|_| script.to_hex_string(), |addr| addr.to_string());
+                    let txs = blocks.entry(height).or_default();
+                    txs.entry(offset).or_default().insert(addr);
+                }
+                address::Event::RolledBack { height } => {
+                    // report the preceding blocks first
+                    for (height, txs) in std::mem::take(&mut blocks) {
+                        broadcast(clients, block_message(height, txs));
+                    }
+                    broadcast(clients, sse("rollback", &json!({"height": height})));
+                }
+            }
+        }
+        for (height, txs) in std::mem::take(&mut blocks) {
+            broadcast(clients, block_message(height, txs));
+        }
+    }
+}
+
+fn block_message(height: usize, txs: BTreeMap<u64, BTreeSet<String>>) -> String {
+    let count = txs.len();
+    let addresses: BTreeSet<String> = txs.into_values().flatten().collect();
+    let data = json!({"height": height, "txs": count, "addresses": addresses});
+    sse("block", &data)
+}
+
+/// https://html.spec.whatwg.org/multipage/server-sent-events.html#event-stream-interpretation
+fn sse(event: &str, data: &Value) -> String {
+    format!("event: {}\ndata: {}\n\n", event, data)
+}
+
+fn broadcast(clients: &Mutex<Vec<mpsc::Sender<String>>>, message: String) {
+    let mut clients = clients.lock().expect("clients lock poisoned");
+    // disconnected clients' receivers are dropped
+    clients.retain(|client| client.send(message.clone()).is_ok());
+}
+
+/// Stream the events until the client disconnects.
+fn stream_events(stream: &mut TcpStream, clients: &EventClients) -> Result<(), Error> {
+    let (sender, messages) = mpsc::channel();
+    clients.lock().expect("clients lock poisoned").push(sender);
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n"
+    )?;
+    stream.flush()?;
+    loop {
+        let message = match messages.recv_timeout(KEEPALIVE_PERIOD) {
+            Ok(message) => message,
+            Err(mpsc::RecvTimeoutError::Timeout) => ": keepalive\n\n".to_owned(),
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        if let Err(e) = stream
+            .write_all(message.as_bytes())
+            .and_then(|()| stream.flush())
+        {
+            debug!("events client disconnected: {}", e);
+            return Ok(());
+        }
+    }
+}
+
+fn handle_client(
+    index: &Mutex<address::Index>,
+    fetcher: &address::Fetcher,
+    mut stream: TcpStream,
+    network: bitcoin::Network,
+    clients: Option<&EventClients>,
+) -> Result<(), Error> {
+    let request = httpd::read_request(&stream)?;
+    let (status, body) = match request
+        .as_ref()
+        .map(|r| (r.method.as_str(), r.path.as_str()))
+    {
+        Some(("GET", "/events")) => match clients {
+            Some(clients) => return stream_events(&mut stream, clients),
+            None => ("404 Not Found", json!({"error": "no watched addresses"})),
+        },
+        Some(("GET", path)) => {
+            debug!("GET {}", path);
+            handle_get(index, fetcher, path, network)
+        }
+        Some(_) => (
+            "405 Method Not Allowed",
+            json!({"error": "only GET is supported"}),
+        ),
+        _ => ("400 Bad Request", json!({"error": "invalid request line"})),
+    };
+    httpd::write_response(&stream, status, "application/json", &body.to_string())?;
+    Ok(())
+}
+
+/// The queried address' script, and whether its balance (or history) is requested
+fn parse_path(
+    path: &str,
+    network: bitcoin::Network,
+) -> Result<(bitcoin::ScriptBuf, bool), (&'static str, Value)> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let (addr, balance) = match segments[..] {
+        ["address", addr, "history"] => (addr, false),
+        ["address", addr, "balance"] => (addr, true),
+        _ => return Err(("404 Not Found", json!({"error": "unknown endpoint"}))),
+    };
+    bitcoin::Address::from_str(addr)
+        .map_err(|e| e.to_string())
+        .and_then(|addr| addr.require_network(network).map_err(|e| e.to_string()))
+        .map(|addr| (addr.script_pubkey(), balance))
+        .map_err(|e| ("400 Bad Request", json!({"error": e})))
+}
+
+/// The index is locked only for finding the transactions (which are fetched after it is unlocked).
+fn handle_get(
+    index: &Mutex<address::Index>,
+    fetcher: &address::Fetcher,
+    path: &str,
+    network: bitcoin::Network,
+) -> (&'static str, Value) {
+    let (script, balance) = match parse_path(path, network) {
+        Ok(query) => query,
+        Err(response) => return response,
+    };
+    let script_hash = ScriptHash::hash(script.as_bytes());
+    let (positions, tip_height) = {
+        let index = index.lock().expect("index lock poisoned");
+        (index.find_positions(&script_hash), index.tip_height())
+    };
+    let history =
+        match positions.and_then(|positions| fetcher.script_history(&script_hash, &positions)) {
+            Ok(history) => history,
+            Err(e) => return ("500 Internal Server Error", json!({"error": e.to_string()})),
+        };
+    let body = if balance {
+        let confirmed: bitcoin::Amount = history.unspent.values().map(|(_, value)| *value).sum();
+        json!({
+            "confirmed": confirmed.to_sat(),
+            "utxos": history.unspent.len(),
+            "height": tip_height,
+        })
+    } else {
+        history
+            .txs
+            .iter()
+            .map(|(height, txid)| json!({"txid": txid, "height": height}))
+            .collect()
+    };
+    ("200 OK", body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: &str = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+
+    #[test]
+    fn test_parse_path() {
+        let network = bitcoin::Network::Bitcoin;
+        let script = bitcoin::Address::from_str(ADDR)
+            .unwrap()
+            .assume_checked()
+            .script_pubkey();
+        let path = format!("/address/{}/history", ADDR);
+        assert_eq!(parse_path(&path, network), Ok((script.clone(), false)));
+        let path = format!("/address/{}/balance/", ADDR);
+        assert_eq!(parse_path(&path, network), Ok((script, true)));
+
+        let status = |path: &str, network| parse_path(path, network).unwrap_err().0;
+        assert_eq!(status("/", network), "404 Not Found");
+        assert_eq!(
+            status(&format!("/address/{}/utxos", ADDR), network),
+            "404 Not Found"
+        );
+        assert_eq!(status("/address/xyz/history", network), "400 Bad Request");
+        let path = format!("/address/{}/history", ADDR);
+        assert_eq!(status(&path, bitcoin::Network::Testnet), "400 Bad Request");
+    }
+
+    #[test]
+    fn test_events() {
+        let txs = BTreeMap::from([
+            (1, BTreeSet::from(["b".to_owned()])),
+            (5, BTreeSet::from(["a".to_owned(), "b".to_owned()])),
+        ]);
+        assert_eq!(
+            block_message(100, txs),
+            "event: block\ndata: {\"addresses\":[\"a\",\"b\"],\"height\":100,\"txs\":2}\n\n"
+        );
+        assert_eq!(
+            sse("rollback", &json!({"height": 99})),
+            "event: rollback\ndata: {\"height\":99}\n\n"
+        );
+    }
+}
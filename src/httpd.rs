@@ -0,0 +1,87 @@
+//! HTTP/1.1 request parsing and responses (a single request per connection), shared by the
+//! metrics and the REST servers.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+/// Maximum size of the request line and headers
+const MAX_REQUEST_BYTES: u64 = 8 << 10;
+
+/// Slow (or stuck) clients are disconnected, so they don't hold on to their threads
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) struct Request {
+    pub method: String,
+    pub path: String,
+}
+
+/// Read the request line and skip its headers (which are not used).
+/// Returns `None` if the request line is invalid.
+pub(crate) fn read_request(stream: &TcpStream) -> std::io::Result<Option<Request>> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_BYTES));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    Ok(match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => Some(Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+        }),
+        _ => None,
+    })
+}
+
+pub(crate) fn write_response(
+    mut stream: &TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    fn request(raw: &'static [u8]) -> std::io::Result<Option<Request>> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let client = std::thread::spawn(move || -> std::io::Result<()> {
+            let mut stream = TcpStream::connect(addr)?;
+            stream.write_all(raw)
+        });
+        let (stream, _) = listener.accept()?;
+        let request = read_request(&stream);
+        client.join().unwrap()?;
+        request
+    }
+
+    #[test]
+    fn test_read_request() -> std::io::Result<()> {
+        let req = request(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")?.unwrap();
+        assert_eq!(req.method, "GET");
+        assert_eq!(req.path, "/metrics");
+        assert!(request(b"GET\r\n\r\n")?.is_none());
+        Ok(())
+    }
+}
@@ -2,7 +2,12 @@ pub mod address;
 
 use std::ops::ControlFlow;
 
-use bitcoin::{consensus::Encodable, hashes::Hash, BlockHash};
+use bitcoin::{
+    bip158,
+    consensus::{Decodable, Encodable},
+    hashes::Hash,
+    BlockHash,
+};
 use bitcoin_slices::{bsl, Parse, Visit};
 
 use crate::chain::Chain;
@@ -21,18 +26,28 @@ pub enum Error {
 
 bitcoin::hashes::hash_newtype! {
     /// https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-basics.html#script-hashes
+    /// (parsed from and displayed as reversed hex, like the Electrum protocol does)
     #[hash_newtype(backward)]
-    struct ScriptHash(bitcoin::hashes::sha256::Hash);
+    pub struct ScriptHash(bitcoin::hashes::sha256::Hash);
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
+impl ScriptHash {
+    pub fn from_script(script: &bitcoin::Script) -> Self {
+        Self::hash(script.as_bytes())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
 pub struct ScriptHashPrefix([u8; ScriptHashPrefix::LEN]);
 
 impl ScriptHashPrefix {
     const LEN: usize = 8;
 
     pub fn new(script: &bitcoin::Script) -> Self {
-        let script_hash = ScriptHash::hash(script.as_bytes());
+        Self::from_script_hash(&ScriptHash::hash(script.as_bytes()))
+    }
+
+    pub(crate) fn from_script_hash(script_hash: &ScriptHash) -> Self {
         Self(script_hash[..ScriptHashPrefix::LEN].try_into().unwrap())
     }
 
@@ -60,7 +75,7 @@ pub struct ScriptHashPrefixRow {
 impl ScriptHashPrefixRow {
     const LEN: usize = ScriptHashPrefix::LEN + TxPos::LEN;
 
-    fn new(prefix: ScriptHashPrefix, txpos: TxPos) -> Self {
+    pub fn new(prefix: ScriptHashPrefix, txpos: TxPos) -> Self {
         let mut result = [0u8; ScriptHashPrefix::LEN + TxPos::LEN];
         result[..ScriptHashPrefix::LEN].copy_from_slice(&prefix.0);
         result[ScriptHashPrefix::LEN..].copy_from_slice(&txpos.0.to_be_bytes());
@@ -75,6 +90,10 @@ impl ScriptHashPrefixRow {
         Self { key }
     }
 
+    pub fn prefix(&self) -> ScriptHashPrefix {
+        ScriptHashPrefix(self.key[..ScriptHashPrefix::LEN].try_into().unwrap())
+    }
+
     pub fn txpos(&self) -> TxPos {
         TxPos(u64::from_be_bytes(
             self.key[ScriptHashPrefix::LEN..].try_into().unwrap(),
@@ -253,14 +272,67 @@ impl SpentBytes {
         SpentBytes(data)
     }
 
+    /// Total value of the outputs spent by the block.
+    pub fn total_value(&self) -> Result<bitcoin::Amount, Error> {
+        let mut r = &self.0[..];
+        let txs_count = bitcoin::VarInt::consensus_decode(&mut r)?.0;
+        let mut total = bitcoin::Amount::ZERO;
+        for _ in 0..txs_count {
+            let outputs = Vec::<bitcoin::TxOut>::consensus_decode(&mut r)?;
+            total += outputs.iter().map(|txo| txo.value).sum();
+        }
+        if !r.is_empty() {
+            return Err(Error::Leftover(r.len()));
+        }
+        Ok(total)
+    }
+
+    /// The outputs spent by the block's transaction at `offset` (in its inputs' order).
+    pub fn tx_outputs(&self, offset: usize) -> Result<Option<Vec<bitcoin::TxOut>>, Error> {
+        let mut r = &self.0[..];
+        let txs_count = bitcoin::VarInt::consensus_decode(&mut r)?.0;
+        if offset as u64 >= txs_count {
+            return Ok(None);
+        }
+        for _ in 0..offset {
+            let outputs_count = bitcoin::VarInt::consensus_decode(&mut r)?.0;
+            for _ in 0..outputs_count {
+                bitcoin::TxOut::consensus_decode(&mut r)?;
+            }
+        }
+        Ok(Some(Vec::<bitcoin::TxOut>::consensus_decode(&mut r)?))
+    }
+
     fn len(&self) -> usize {
         self.0.len()
     }
 }
 
+/// Build the block's BIP158 basic filter (its output scripts and the scripts it spends).
+fn block_filter(block: &BlockBytes, spent: &SpentBytes) -> Result<bip158::BlockFilter, Error> {
+    let block: bitcoin::Block = bitcoin::consensus::deserialize(&block.0)?;
+    let mut content = vec![];
+    let mut writer = bip158::BlockFilterWriter::new(&mut content, &block);
+    writer.add_output_scripts();
+    let mut r = &spent.0[..];
+    let txs_count = bitcoin::VarInt::consensus_decode(&mut r)?.0;
+    for _ in 0..txs_count {
+        for txo in Vec::<bitcoin::TxOut>::consensus_decode(&mut r)? {
+            writer.add_element(txo.script_pubkey.as_bytes());
+        }
+    }
+    if !r.is_empty() {
+        return Err(Error::Leftover(r.len()));
+    }
+    writer.finish().expect("writing to a Vec can't fail");
+    Ok(bip158::BlockFilter::new(&content))
+}
+
 pub struct Batch {
     pub script_hash_rows: Vec<ScriptHashPrefixRow>,
     pub header: Header,
+    /// BIP158 filter (if enabled)
+    pub filter: Option<bip158::BlockFilter>,
 }
 
 impl Batch {
@@ -285,6 +357,7 @@ impl Batch {
         Ok(Batch {
             script_hash_rows,
             header,
+            filter: None,
         })
     }
 }
@@ -293,6 +366,7 @@ struct Builder {
     batches: Vec<Batch>,
     next_txpos: TxPos,
     tip: bitcoin::BlockHash,
+    filters: bool,
 }
 
 impl Builder {
@@ -303,16 +377,26 @@ impl Builder {
             tip: chain
                 .tip_hash()
                 .unwrap_or_else(bitcoin::BlockHash::all_zeros),
+            filters: false,
         }
     }
 
+    /// Also build BIP158 filters for the indexed blocks.
+    fn with_filters(mut self, filters: bool) -> Self {
+        self.filters = filters;
+        self
+    }
+
     fn index(
         &mut self,
         hash: bitcoin::BlockHash,
         block_bytes: &BlockBytes,
         spent_bytes: &SpentBytes,
     ) -> Result<(), Error> {
-        let batch = Batch::build(hash, self.next_txpos, block_bytes, spent_bytes)?;
+        let mut batch = Batch::build(hash, self.next_txpos, block_bytes, spent_bytes)?;
+        if self.filters {
+            batch.filter = Some(block_filter(block_bytes, spent_bytes)?);
+        }
         assert_eq!(batch.header.header().prev_blockhash, self.tip);
         self.next_txpos = batch.header.next_txpos();
         self.tip = batch.header.hash;
@@ -320,6 +404,16 @@ impl Builder {
         Ok(())
     }
 
+    fn add_header(&mut self, hash: bitcoin::BlockHash, header: bitcoin::block::Header) {
+        assert_eq!(header.prev_blockhash, self.tip);
+        self.tip = hash;
+        self.batches.push(Batch {
+            script_hash_rows: vec![],
+            header: Header::new(self.next_txpos, hash, header),
+            filter: None,
+        });
+    }
+
     fn into_batches(self) -> Vec<Batch> {
         self.batches
     }
@@ -327,7 +421,7 @@ impl Builder {
 
 #[cfg(test)]
 mod tests {
-    use bitcoin::consensus::{deserialize, encode::Decodable};
+    use bitcoin::consensus::deserialize;
     use hex_lit::hex;
 
     use super::*;
@@ -336,6 +430,25 @@ mod tests {
     const BLOCK_HEX: &str = "0100000050120119172a610421a6c3011dd330d9df07b63616c2cc1f1cd00200000000006657a9252aacd5c0b2940996ecff952228c3067cc38d4885efb5a4ac4247e9f337221b4d4c86041b0f2b57100401000000010000000000000000000000000000000000000000000000000000000000000000ffffffff08044c86041b020602ffffffff0100f2052a010000004341041b0e8c2567c12536aa13357b79a073dc4444acb83c4ec7a0e2f99dd7457516c5817242da796924ca4e99947d087fedf9ce467cb9f7c6287078f801df276fdf84ac000000000100000001032e38e9c0a84c6046d687d10556dcacc41d275ec55fc00779ac88fdf357a187000000008c493046022100c352d3dd993a981beba4a63ad15c209275ca9470abfcd57da93b58e4eb5dce82022100840792bc1f456062819f15d33ee7055cf7b5ee1af1ebcc6028d9cdb1c3af7748014104f46db5e9d61a9dc27b8d64ad23e7383a4e6ca164593c2527c038c0857eb67ee8e825dca65046b82c9331586c82e0fd1f633f25f87c161bc6f8a630121df2b3d3ffffffff0200e32321000000001976a914c398efa9c392ba6013c5e04ee729755ef7f58b3288ac000fe208010000001976a914948c765a6914d43f2a7ac177da2c2f6b52de3d7c88ac000000000100000001c33ebff2a709f13d9f9a7569ab16a32786af7d7e2de09265e41c61d078294ecf010000008a4730440220032d30df5ee6f57fa46cddb5eb8d0d9fe8de6b342d27942ae90a3231e0ba333e02203deee8060fdc70230a7f5b4ad7d7bc3e628cbe219a886b84269eaeb81e26b4fe014104ae31c31bf91278d99b8377a35bbce5b27d9fff15456839e919453fc7b3f721f0ba403ff96c9deeb680e5fd341c0fc3a7b90da4631ee39560639db462e9cb850fffffffff0240420f00000000001976a914b0dcbf97eabf4404e31d952477ce822dadbe7e1088acc060d211000000001976a9146b1281eec25ab4e1e0793ff4e08ab1abb3409cd988ac0000000001000000010b6072b386d4a773235237f64c1126ac3b240c84b917a3909ba1c43ded5f51f4000000008c493046022100bb1ad26df930a51cce110cf44f7a48c3c561fd977500b1ae5d6b6fd13d0b3f4a022100c5b42951acedff14abba2736fd574bdb465f3e6f8da12e2c5303954aca7f78f3014104a7135bfe824c97ecc01ec7d7e336185c81e2aa2c41ab175407c09484ce9694b44953fcb751206564a9c24dd094d42fdbfdd5aad3e063ce6af4cfaaea4ea14fbbffffffff0140420f00000000001976a91439aa3d569e06a1d7926dc4be1193c99bf2eb9ee088ac00000000";
     const SPENT_HEX: &str = "04000100f2052a010000001976a91471d7dd96d9edda09180fe9d57a477b5acc9cad1188ac0100a3e111000000001976a91435fbee6a3bf8d99f17724ec54787567393a8a6b188ac0140420f00000000001976a914c4eb47ecfdcf609a1848ee79acc2fa49d3caad7088ac";
 
+    #[test]
+    fn test_spent_total_value() -> Result<(), Error> {
+        let spent_bytes = SpentBytes(hex!(SPENT_HEX).to_vec());
+        assert_eq!(
+            spent_bytes.total_value()?,
+            bitcoin::Amount::from_sat(5_301_000_000)
+        );
+        let mut total = bitcoin::Amount::ZERO;
+        let mut offset = 0;
+        while let Some(outputs) = spent_bytes.tx_outputs(offset)? {
+            total += outputs.iter().map(|txo| txo.value).sum();
+            offset += 1;
+        }
+        assert_eq!(total, spent_bytes.total_value()?);
+        // the coinbase doesn't spend any outputs
+        assert_eq!(spent_bytes.tx_outputs(0)?, Some(vec![]));
+        Ok(())
+    }
+
     #[test]
     fn test_index_block() -> Result<(), Error> {
         let block_bytes = BlockBytes(hex!(BLOCK_HEX).to_vec());
@@ -394,6 +507,43 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_block_filter() -> Result<(), Error> {
+        let block_bytes = BlockBytes(hex!(BLOCK_HEX).to_vec());
+        let spent_bytes = SpentBytes(hex!(SPENT_HEX).to_vec());
+        let block: bitcoin::Block = deserialize(&block_bytes.0).unwrap();
+
+        // the spent outputs are ordered by their spending inputs
+        let mut r = &spent_bytes.0[..];
+        let txs_count = bitcoin::VarInt::consensus_decode(&mut r)?.0;
+        let mut spent = std::collections::HashMap::new();
+        for tx in block.txdata.iter().take(txs_count as usize) {
+            let outputs = Vec::<bitcoin::TxOut>::consensus_decode(&mut r)?;
+            if tx.is_coinbase() {
+                continue;
+            }
+            for (txi, txo) in tx.input.iter().zip(outputs) {
+                spent.insert(txi.previous_output, txo.script_pubkey);
+            }
+        }
+        let expected = bip158::BlockFilter::new_script_filter(&block, |outpoint| {
+            spent
+                .get(outpoint)
+                .cloned()
+                .ok_or(bip158::Error::UtxoMissing(*outpoint))
+        })
+        .unwrap();
+
+        let filter = block_filter(&block_bytes, &spent_bytes)?;
+        assert_eq!(filter, expected);
+        let hash = block.block_hash();
+        let output = block.txdata[1].output[0].script_pubkey.as_bytes();
+        assert!(filter.match_any(&hash, std::iter::once(output)).unwrap());
+        let input = spent.values().next().unwrap().as_bytes();
+        assert!(filter.match_any(&hash, std::iter::once(input)).unwrap());
+        Ok(())
+    }
+
     fn decode_spent(
         buf: &[u8],
         txpos: TxPos,
@@ -418,6 +568,19 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_script_hash_hex() {
+        // https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-basics.html#script-hashes
+        let script =
+            bitcoin::ScriptBuf::from_hex("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac")
+                .unwrap();
+        let hex = "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161";
+        let script_hash: ScriptHash = hex.parse().unwrap();
+        assert_eq!(script_hash, ScriptHash::from_script(&script));
+        assert_eq!(script_hash.to_string(), hex);
+        assert!("8b01".parse::<ScriptHash>().is_err());
+    }
+
     #[test]
     fn test_serde_row() {
         let txpos = TxPos(0x123456789ABCDEF0);
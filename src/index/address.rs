@@ -1,10 +1,24 @@
-use std::path::Path;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    io::{Read, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use bitcoin::hashes::Hash;
 use log::*;
 
 use crate::{
+    cache::{self, Cache},
     chain::{self, Location},
     client, db, index,
+    mempool::{Mempool, UnconfirmedHistory},
+    metrics::Metrics,
+    redact::Redacted,
 };
 
 #[derive(thiserror::Error, Debug)]
@@ -18,11 +32,192 @@ pub enum Error {
     #[error("DB failed: {0}")]
     DB(#[from] rocksdb::Error),
 
-    #[error("Genesis block hash mismatch: {0} != {1}")]
-    ChainMismatch(bitcoin::BlockHash, bitcoin::BlockHash),
+    #[error("Network mismatch: index is {} but node is {}", network_name(.0), network_name(.1))]
+    NetworkMismatch(bitcoin::BlockHash, bitcoin::BlockHash),
+
+    #[error("Network mismatch: requested {} but node is {}", .0, network_name(.1))]
+    WrongNetwork(bitcoin::Network, bitcoin::BlockHash),
 
     #[error("Invalid transaction position: {0:?}")]
     InvalidPosition(index::TxPos),
+
+    #[error("Invalid transaction offset: block={0} offset={1}")]
+    InvalidOffset(bitcoin::BlockHash, u64),
+
+    #[error("Node doesn't have the indexed tip block={0}")]
+    UnknownTip(bitcoin::BlockHash),
+
+    #[error("Cannot sync a read-only index")]
+    ReadOnly,
+
+    #[error("Block at height={0} is not indexed")]
+    NotIndexed(usize),
+
+    #[error("Index mode mismatch: DB is {}, requested {}", mode_name(*.0), mode_name(*.1))]
+    ModeMismatch(bool, bool),
+
+    #[error("I/O failed: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("cache failed: {0}")]
+    Cache(#[from] cache::Error),
+
+    #[error("Invalid index dump: {0}")]
+    InvalidDump(&'static str),
+
+    #[error("Cannot load a dump into a non-empty index")]
+    NotEmpty,
+
+    #[error("Loading the index dump was interrupted (delete the index DB and load it again)")]
+    LoadInterrupted,
+
+    #[error("decoding failed: {0}")]
+    Decoding(#[from] bitcoin::consensus::encode::Error),
+
+    #[error(
+        "Block at height={0} was pruned by the node (prune height={1}), so it can't be indexed"
+    )]
+    Pruned(usize, usize),
+
+    #[error("Header at height={0} doesn't follow the previous one")]
+    BrokenChain(usize),
+
+    #[error("Node is on an unknown network: {0:?}")]
+    UnknownNetwork(String),
+
+    #[error("No filter for block at height={0}")]
+    MissingFilter(usize),
+
+    #[error("filter matching failed: {0}")]
+    Filter(#[from] bitcoin::bip158::Error),
+}
+
+fn network_name(genesis_hash: &bitcoin::BlockHash) -> String {
+    use bitcoin::Network::*;
+    [Bitcoin, Testnet, Testnet4, Signet, Regtest]
+        .into_iter()
+        .find(|&n| bitcoin::constants::genesis_block(n).block_hash() == *genesis_hash)
+        .map_or_else(
+            || format!("unknown (genesis={})", genesis_hash),
+            |n| n.to_string(),
+        )
+}
+
+fn mode_name(headers_only: bool) -> &'static str {
+    if headers_only {
+        "headers-only"
+    } else {
+        "full"
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct Options {
+    /// Maintain only the header chain (no script hash rows)
+    pub headers_only: bool,
+    /// Total memory (in bytes) for DB caches and sync batches
+    pub mem_budget: Option<usize>,
+    /// Maximum number of node requests per second
+    pub rpc_rate_limit: Option<u32>,
+    /// Timeout for connecting to the node
+    pub rpc_connect_timeout: Option<Duration>,
+    /// Timeout for receiving each node response (headers and body)
+    pub rpc_read_timeout: Option<Duration>,
+    /// Number of retries for timed-out REST requests
+    pub rpc_retries: usize,
+    /// Credentials for JSON-RPC calls (REST requests don't need them)
+    pub rpc_auth: Option<client::Auth>,
+    /// Open the DB read-only (syncing is not supported)
+    pub read_only: bool,
+    /// Alternative node URLs (used when the active node is unreachable)
+    pub rpc_fallback_urls: Vec<String>,
+    /// Build and store a BIP158 filter for each indexed block
+    pub filters: bool,
+    /// Refuse to open the index if the node is on another network
+    pub network: Option<bitcoin::Network>,
+}
+
+const DEFAULT_SYNC_LIMIT: usize = 1000;
+// the node returns at most 2000 headers per REST request (including the requested one)
+const MAX_HEADERS: usize = 1999;
+const MAX_IDLE_CONNECTIONS: usize = 8;
+// rough upper bound for the script hash rows of a single block
+const BATCH_BYTES_PER_BLOCK: usize = 1 << 20;
+
+impl Options {
+    /// Number of blocks to index in a single `Index::sync()` call
+    pub fn sync_limit(&self) -> usize {
+        // a quarter of the budget is left for the sync batches
+        self.mem_budget.map_or(DEFAULT_SYNC_LIMIT, |budget| {
+            (budget / 4 / BATCH_BYTES_PER_BLOCK).max(1)
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Alert {
+    /// The node's best block is too old
+    StaleTip { height: usize, age: Duration },
+    /// The node has headers that it didn't (or couldn't) validate
+    UnvalidatedHeaders { blocks: usize, headers: usize },
+    /// The node rejected a chain which is longer than its active chain
+    InvalidChain {
+        height: usize,
+        hash: bitcoin::BlockHash,
+    },
+}
+
+impl std::fmt::Display for Alert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Alert::StaleTip { height, age } => {
+                write!(f, "node tip at height={} is {}s old", height, age.as_secs())
+            }
+            Alert::UnvalidatedHeaders { blocks, headers } => write!(
+                f,
+                "node has {} unvalidated headers (blocks={} headers={})",
+                headers - blocks,
+                blocks,
+                headers
+            ),
+            Alert::InvalidChain { height, hash } => {
+                write!(f, "node rejected block={} at height={}", hash, height)
+            }
+        }
+    }
+}
+
+/// An inconsistency between the index and the node's blocks
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Corruption {
+    /// The re-indexed block doesn't match its stored header (or transactions' positions)
+    Header { height: usize },
+    /// Some of the block's script hash rows are missing
+    MissingRows { height: usize, count: usize },
+    /// The transaction at the indexed offset differs from the block's
+    Tx { height: usize, offset: u64 },
+}
+
+impl std::fmt::Display for Corruption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Corruption::Header { height } => {
+                write!(
+                    f,
+                    "block at height={} doesn't match its index entry",
+                    height
+                )
+            }
+            Corruption::MissingRows { height, count } => {
+                write!(f, "block at height={} has {} missing rows", height, count)
+            }
+            Corruption::Tx { height, offset } => write!(
+                f,
+                "tx at height={} offset={} doesn't match the block",
+                height, offset
+            ),
+        }
+    }
 }
 
 pub struct Index {
@@ -30,30 +225,375 @@ pub struct Index {
     chain: chain::Chain,
     client: client::Client,
     store: db::Store,
+    options: Options,
+    max_height: Option<usize>,
+    /// Headers fetched from the node, but not indexed yet
+    pending: VecDeque<bitcoin::block::Header>,
+    progress: Option<ProgressFn>,
+    cancel: Option<Arc<AtomicBool>>,
+    metrics: Option<Arc<Metrics>>,
+    subscriptions: Vec<Subscription>,
+    mempool: Mempool,
+}
+
+/// Reported to subscribers (see `Index::subscribe`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A newly indexed transaction may fund or spend the script
+    /// (like `Index::find`, it is matched by a script hash prefix)
+    Tx {
+        script: bitcoin::ScriptBuf,
+        height: usize,
+        offset: u64,
+    },
+    /// The block at `height` was rolled back (so its transactions' events are stale)
+    RolledBack { height: usize },
+}
+
+struct Subscription {
+    scripts: HashMap<index::ScriptHashPrefix, Vec<bitcoin::ScriptBuf>>,
+    events: mpsc::Sender<Event>,
+}
+
+type ProgressFn = Box<dyn FnMut(&Progress) + Send>;
+
+/// Reported after each indexed batch (see `Index::set_progress`)
+#[derive(Clone, Debug)]
+pub struct Progress {
+    /// Heights of the batch's first and last blocks
+    pub heights: std::ops::RangeInclusive<usize>,
+    pub blocks: usize,
+    pub bytes: usize,
+    /// Headers fetched from the node, which are still waiting to be indexed
+    pub pending: usize,
+    /// Time spent fetching (and indexing) the batch's blocks
+    pub fetch: Duration,
+    /// Time spent writing the batch to the DB
+    pub write: Duration,
 }
 
 #[derive(Default)]
 pub struct Stats {
     pub indexed_blocks: usize,
+    pub rolled_back_blocks: usize,
     pub size_read: usize,
     pub elapsed: std::time::Duration,
+    /// The batch was interrupted (after indexing its first `indexed_blocks` blocks)
+    pub cancelled: bool,
+}
+
+#[derive(Default, Debug)]
+pub struct BenchStats {
+    pub blocks: usize,
+    pub bytes: usize,
+    pub rows: usize,
+    pub fetch: Duration,
+    pub index: Duration,
+}
+
+#[derive(Default, Debug)]
+pub struct RescanStats {
+    pub blocks: usize,
+    /// Rows of the given scripts found in the rescanned blocks
+    pub matched: usize,
+    /// Matched rows that were missing from the index
+    pub restored: usize,
+}
+
+#[derive(Default, Debug)]
+pub struct DumpStats {
+    pub headers: usize,
+    pub rows: usize,
+}
+
+/// The confirmed transactions funding or spending a script.
+#[derive(Debug, Default)]
+pub struct ScriptHistory {
+    /// In confirmation order
+    pub txs: Vec<(usize, bitcoin::Txid)>,
+    pub unspent: BTreeMap<bitcoin::OutPoint, (usize, bitcoin::Amount)>,
+}
+
+/// The position of a confirmed transaction, without borrowing the index
+/// (so it can be fetched while the index is used by others, see `Fetcher`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TxPosition {
+    pub height: usize,
+    pub offset: u64,
+    pub block_hash: bitcoin::BlockHash,
+}
+
+/// Fetches data from the node, without borrowing the index (see `Index::fetcher`).
+#[derive(Clone)]
+pub struct Fetcher {
+    client: client::Client,
+}
+
+impl Fetcher {
+    /// Like `Index::script_history`, given the positions from `Index::find_positions`.
+    pub fn script_history(
+        &self,
+        script_hash: &index::ScriptHash,
+        positions: &[TxPosition],
+    ) -> Result<ScriptHistory, Error> {
+        build_history(&self.client, script_hash, positions)
+    }
+
+    pub fn get_tx_bytes(&self, position: &TxPosition) -> Result<Vec<u8>, Error> {
+        Ok(self
+            .client
+            .get_tx_bytes_from_block(position.block_hash, position.offset)?)
+    }
+
+    /// Requires the node's `-txindex` for confirmed transactions.
+    pub fn get_raw_transaction(&self, txid: bitcoin::Txid) -> Result<Vec<u8>, Error> {
+        Ok(self.client.get_raw_transaction(txid)?)
+    }
+
+    pub fn send_raw_transaction(&self, tx: &bitcoin::Transaction) -> Result<bitcoin::Txid, Error> {
+        Ok(self.client.send_raw_transaction(tx)?)
+    }
+
+    /// The node's minimum relay fee rate (per kvB).
+    pub fn get_relay_fee(&self) -> Result<bitcoin::Amount, Error> {
+        Ok(self.client.get_relay_fee()?)
+    }
+
+    /// The fee rate (per kvB) needed for confirming within `blocks` (if the node can estimate it).
+    pub fn estimate_fee(&self, blocks: u16) -> Result<Option<bitcoin::Amount>, Error> {
+        Ok(self.client.estimate_smart_fee(blocks)?)
+    }
+}
+
+/// Fetch the transactions at `positions` (in confirmation order), keeping the ones funding
+/// the script, or spending an output funded by an earlier transaction.
+fn build_history(
+    client: &client::Client,
+    script_hash: &index::ScriptHash,
+    positions: &[TxPosition],
+) -> Result<ScriptHistory, Error> {
+    let mut history = ScriptHistory {
+        txs: vec![],
+        unspent: BTreeMap::new(),
+    };
+    for pos in positions {
+        let tx_bytes = client.get_tx_bytes_from_block(pos.block_hash, pos.offset)?;
+        let tx: bitcoin::Transaction = bitcoin::consensus::deserialize(&tx_bytes)?;
+        let txid = tx.compute_txid();
+        let mut matched = false;
+        for txi in &tx.input {
+            matched |= history.unspent.remove(&txi.previous_output).is_some();
+        }
+        for (vout, txo) in tx.output.iter().enumerate() {
+            if index::ScriptHash::hash(txo.script_pubkey.as_bytes()) == *script_hash {
+                let outpoint = bitcoin::OutPoint::new(txid, vout.try_into().unwrap());
+                history.unspent.insert(outpoint, (pos.height, txo.value));
+                matched = true;
+            }
+        }
+        if matched {
+            history.txs.push((pos.height, txid));
+        }
+    }
+    Ok(history)
+}
+
+/// A confirmed unspent output (see `Index::list_unspent`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Utxo {
+    pub outpoint: bitcoin::OutPoint,
+    pub value: bitcoin::Amount,
+    /// The confirmation height of the funding transaction
+    pub height: usize,
+    pub script: bitcoin::ScriptBuf,
+}
+
+/// Index dump format (all integers are big-endian, so it doesn't depend on the architecture):
+/// - magic: `b"BINDEX"`, followed by the format version (`u16`, currently 1)
+/// - genesis block hash (32 bytes)
+/// - number of headers (`u64`), followed by the header rows: the next block's first txpos (`u64`),
+///   the block hash (32 bytes) and the consensus-encoded block header (80 bytes)
+/// - script hash rows (until EOF): the script hash prefix (8 bytes) and txpos (`u64`)
+const DUMP_MAGIC: &[u8; 6] = b"BINDEX";
+const DUMP_VERSION: u16 = 1;
+
+/// Rows are written in batches when loading a dump
+const LOAD_BATCH_ROWS: usize = 1_000_000;
+
+/// Read exactly `N` bytes, returning `None` on EOF (before the first byte).
+fn read_array<const N: usize>(input: &mut impl Read) -> Result<Option<[u8; N]>, Error> {
+    let mut buf = [0u8; N];
+    let mut filled = 0;
+    while filled < N {
+        match input.read(&mut buf[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(Error::InvalidDump("truncated")),
+            n => filled += n,
+        }
+    }
+    Ok(Some(buf))
+}
+
+fn read_exact<const N: usize>(input: &mut impl Read) -> Result<[u8; N], Error> {
+    read_array(input)?.ok_or(Error::InvalidDump("truncated"))
+}
+
+/// Query the node's network (using `getblockchaininfo` via REST).
+pub fn node_network(url: impl Into<String>) -> Result<bitcoin::Network, Error> {
+    let client = client::Client::new(ureq::Agent::new_with_defaults(), url);
+    let info = client.get_chain_info()?;
+    bitcoin::Network::from_core_arg(&info.chain).map_err(|_| Error::UnknownNetwork(info.chain))
+}
+
+/// Load an index dump (see `Index::dump`) into a new DB, without connecting to the node.
+pub fn load(
+    db_path: impl AsRef<Path>,
+    mut input: impl Read,
+    mem_budget: Option<usize>,
+) -> Result<DumpStats, Error> {
+    if read_exact::<6>(&mut input)? != *DUMP_MAGIC {
+        return Err(Error::InvalidDump("bad magic"));
+    }
+    if u16::from_be_bytes(read_exact(&mut input)?) != DUMP_VERSION {
+        return Err(Error::InvalidDump("unsupported version"));
+    }
+    let genesis_hash = bitcoin::BlockHash::from_byte_array(read_exact(&mut input)?);
+
+    let store = db::Store::open(db_path, mem_budget, false)?;
+    if store.is_loading()? {
+        return Err(Error::LoadInterrupted);
+    }
+    if store.genesis_hash()?.is_some() || !store.headers()?.is_empty() {
+        return Err(Error::NotEmpty);
+    }
+    // cleared only after the whole dump is written
+    store.set_loading(true)?;
+    store.flush()?;
+    let count = u64::from_be_bytes(read_exact(&mut input)?);
+    let mut headers = Vec::with_capacity(count.min(1 << 24) as usize);
+    for _ in 0..count {
+        let key = read_exact(&mut input)?;
+        let value = read_exact(&mut input)?;
+        let header = index::Header::deserialize((key, value));
+        let prev_blockhash = headers
+            .last()
+            .map_or(bitcoin::BlockHash::all_zeros(), index::Header::hash);
+        if header.header().prev_blockhash != prev_blockhash
+            || header.header().block_hash() != header.hash()
+        {
+            return Err(Error::InvalidDump("headers are not chained"));
+        }
+        headers.push(header);
+    }
+    if headers.first().is_some_and(|h| h.hash() != genesis_hash) {
+        return Err(Error::InvalidDump("first header is not the genesis block"));
+    }
+    let mut stats = DumpStats {
+        headers: headers.len(),
+        rows: 0,
+    };
+    // the rows must point into the dumped blocks
+    let end = headers
+        .last()
+        .map_or(index::TxPos::default(), index::Header::next_txpos);
+    let mut rows = Vec::with_capacity(LOAD_BATCH_ROWS);
+    while let Some(row) = read_array(&mut input)? {
+        let row = index::ScriptHashPrefixRow::from_bytes(row);
+        if row.txpos() >= end {
+            return Err(Error::InvalidDump("row is beyond the last header"));
+        }
+        rows.push(row);
+        if rows.len() == LOAD_BATCH_ROWS {
+            store.load(&[], &rows)?;
+            stats.rows += rows.len();
+            rows.clear();
+        }
+    }
+    stats.rows += rows.len();
+    // headers are written last, so an interrupted load doesn't look like a valid index
+    store.load(&headers, &rows)?;
+    store.set_genesis_hash(genesis_hash)?;
+    store.flush()?;
+    store.set_loading(false)?;
+    store.flush()?;
+    Ok(stats)
+}
+
+#[derive(Debug)]
+pub struct BlockSummary {
+    pub height: usize,
+    pub header: bitcoin::block::Header,
+    pub tx_count: usize,
+    /// `None` if the block's undo data is not available
+    pub fees: Option<bitcoin::Amount>,
 }
 
 impl Index {
     pub fn open(db_path: impl AsRef<Path>, url: impl Into<String>) -> Result<Self, Error> {
+        Self::open_with(db_path, url, Options::default())
+    }
+
+    pub fn open_with(
+        db_path: impl AsRef<Path>,
+        url: impl Into<String>,
+        options: Options,
+    ) -> Result<Self, Error> {
         let agent = ureq::Agent::new_with_config(
             ureq::config::Config::builder()
                 .max_response_header_size(usize::MAX) // Disabled as a workaround
+                // keep connections alive between polls (bitcoind closes idle ones after 30s)
+                .max_idle_age(Duration::from_secs(25))
+                .max_idle_connections_per_host(MAX_IDLE_CONNECTIONS)
+                .max_idle_connections(MAX_IDLE_CONNECTIONS)
+                .timeout_connect(options.rpc_connect_timeout)
+                .timeout_recv_response(options.rpc_read_timeout)
+                .timeout_recv_body(options.rpc_read_timeout)
                 .build(),
         );
-        let client = client::Client::new(agent, url);
-        let genesis_hash = client.get_blockhash_by_height(0)?;
+        let mut client = client::Client::new(agent, url);
+        client.set_rate_limit(options.rpc_rate_limit);
+        client.set_retries(options.rpc_retries);
+        client.set_auth(options.rpc_auth.clone());
+        client.set_fallback_urls(options.rpc_fallback_urls.clone());
+        let mut attempts = 1;
+        let genesis_hash = loop {
+            match client.get_blockhash_by_height(0) {
+                Ok(hash) => break hash,
+                Err(e) if e.is_unreachable() && attempts < client.url_count() => {
+                    attempts += 1;
+                    let url = client.switch_url();
+                    warn!("node is unreachable ({}), trying {}", e, Redacted(url));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        if let Some(network) = options.network {
+            // checked before opening (and possibly creating) the DB
+            if bitcoin::constants::genesis_block(network).block_hash() != genesis_hash {
+                return Err(Error::WrongNetwork(network, genesis_hash));
+            }
+        }
 
-        let store = db::Store::open(db_path)?;
-        let chain = chain::Chain::new(store.headers()?);
+        let store = db::Store::open(db_path, options.mem_budget, options.read_only)?;
+        if store.is_loading()? {
+            return Err(Error::LoadInterrupted);
+        }
+        let indexed_genesis_hash = store.genesis_hash()?;
+        if let Some(indexed) = indexed_genesis_hash {
+            if indexed != genesis_hash {
+                return Err(Error::NetworkMismatch(indexed, genesis_hash));
+            }
+        }
+        let chain = chain::Chain::new(store.headers()?).map_err(Error::BrokenChain)?;
         if let Some(indexed_genesis) = chain.get_by_height(0) {
             if indexed_genesis.hash() != genesis_hash {
-                return Err(Error::ChainMismatch(indexed_genesis.hash(), genesis_hash));
+                // e.g. an index created before its genesis hash was stored
+                return Err(Error::NetworkMismatch(indexed_genesis.hash(), genesis_hash));
+            }
+            // a full index always has transactions (starting from the genesis coinbase)
+            let headers_only = chain.next_txpos() == index::TxPos::default();
+            if headers_only != options.headers_only {
+                return Err(Error::ModeMismatch(headers_only, options.headers_only));
             }
             info!(
                 "block={} height={} headers loaded",
@@ -61,52 +601,311 @@ impl Index {
                 chain.tip_height().unwrap()
             );
         }
-        Ok(Index {
+        if indexed_genesis_hash.is_none() && !options.read_only {
+            store.set_genesis_hash(genesis_hash)?;
+        }
+        let index = Index {
             genesis_hash,
             chain,
             client,
             store,
-        })
+            options,
+            max_height: None,
+            pending: VecDeque::new(),
+            progress: None,
+            cancel: None,
+            metrics: None,
+            subscriptions: vec![],
+            mempool: Mempool::default(),
+        };
+        if attempts > 1 {
+            index.check_node_chain()?;
+        }
+        Ok(index)
+    }
+
+    /// Verify that the active node is on the indexed chain (so it can be used for syncing).
+    fn check_node_chain(&self) -> Result<(), Error> {
+        let genesis_hash = self.client.get_blockhash_by_height(0)?;
+        if genesis_hash != self.genesis_hash {
+            return Err(Error::NetworkMismatch(self.genesis_hash, genesis_hash));
+        }
+        if let Some(tip) = self.chain.tip_hash() {
+            // a lagging node doesn't know our tip (and would seem to have rolled it back)
+            let headers = self.client.get_headers(tip, 0)?;
+            if headers.first().map(bitcoin::block::Header::block_hash) != Some(tip) {
+                return Err(Error::UnknownTip(tip));
+            }
+        }
+        Ok(())
+    }
+
+    /// Switch to the next reachable node that is on the indexed chain.
+    /// Returns `false` (keeping the active node) if there is none.
+    fn failover(&mut self) -> bool {
+        for _ in 1..self.client.url_count() {
+            let url = Redacted(self.client.switch_url()).to_string();
+            match self.check_node_chain() {
+                Ok(()) => {
+                    warn!("failed over to {}", url);
+                    return true;
+                }
+                Err(e) => warn!("cannot fail over to {}: {}", url, e),
+            }
+        }
+        self.client.switch_url(); // back to the original node
+        false
+    }
+
+    /// Call `progress` after each batch is indexed by `sync`.
+    pub fn set_progress(&mut self, progress: impl FnMut(&Progress) + Send + 'static) {
+        self.progress = Some(Box::new(progress));
     }
 
+    /// Stop `sync` (after writing the blocks indexed so far) when `cancel` is set.
+    pub fn set_cancel(&mut self, cancel: Arc<AtomicBool>) {
+        self.cancel = Some(cancel);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+
+    /// Update the given metrics while syncing and querying the index.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        metrics.set_tip_height(self.chain.tip_height().unwrap_or_default());
+        metrics.set_db_size(self.store.size());
+        self.metrics = Some(metrics);
+    }
+
+    /// Subscribe to the transactions (indexed by the following syncs) touching the given scripts,
+    /// and to blocks' rollbacks. The events can be received after each sync (e.g. using `try_iter()`),
+    /// and the subscription is dropped with its receiver.
+    pub fn subscribe(
+        &mut self,
+        scripts: impl IntoIterator<Item = bitcoin::ScriptBuf>,
+    ) -> mpsc::Receiver<Event> {
+        let (events, receiver) = mpsc::channel();
+        let mut subscription = Subscription {
+            scripts: HashMap::new(),
+            events,
+        };
+        for script in scripts {
+            let prefix = index::ScriptHashPrefix::new(&script);
+            subscription.scripts.entry(prefix).or_default().push(script);
+        }
+        self.subscriptions.push(subscription);
+        receiver
+    }
+
+    /// Report the batch's matching transactions (before it is added to the chain).
+    fn notify_indexed(&mut self, batch: &index::Batch) {
+        let height = self.chain.tip_height().map_or(0, |h| h + 1);
+        let first = self.chain.next_txpos();
+        self.subscriptions.retain(|sub| {
+            // a transaction may have several rows for the same script (in confirmation order)
+            let mut matched = BTreeSet::new();
+            for row in &batch.script_hash_rows {
+                if let Some(scripts) = sub.scripts.get(&row.prefix()) {
+                    let offset = row.txpos().offset_from(first).unwrap();
+                    matched.extend(scripts.iter().map(|script| (offset, script)));
+                }
+            }
+            matched.into_iter().all(|(offset, script)| {
+                let script = script.clone();
+                let event = Event::Tx {
+                    script,
+                    height,
+                    offset,
+                };
+                sub.events.send(event).is_ok()
+            })
+        });
+    }
+
+    fn notify(&mut self, event: Event) {
+        self.subscriptions
+            .retain(|sub| sub.events.send(event.clone()).is_ok());
+    }
+
+    /// Stop indexing at `height` (rolling back any blocks above it).
+    pub fn set_max_height(&mut self, height: Option<usize>) {
+        self.max_height = height;
+    }
+
+    /// Delete the tip's header and rows, and roll the in-memory chain back (only once they are
+    /// deleted, so a failure leaves both the chain and the DB unchanged).
     fn drop_tip(&mut self) -> Result<bitcoin::BlockHash, Error> {
         let stale = self.chain.pop().expect("cannot drop tip of an empty chain");
-        let block_bytes = self.client.get_block_bytes(stale.hash())?;
-        let spent_bytes = self.client.get_spent_bytes(stale.hash())?;
+        if let Err(e) = self.delete_stale(&stale) {
+            self.chain.add(stale);
+            return Err(e);
+        }
+        self.pending.clear(); // they follow the stale block
+        let height = self.chain.tip_height().map_or(0, |h| h + 1);
+        self.notify(Event::RolledBack { height });
+        Ok(stale.hash())
+    }
+
+    /// Delete the stale block's rows (following the current tip) and its header.
+    fn delete_stale(&self, stale: &index::Header) -> Result<(), Error> {
         let mut builder = index::Builder::new(&self.chain);
-        builder.index(stale.hash(), &block_bytes, &spent_bytes)?;
+        if self.options.headers_only {
+            builder.add_header(stale.hash(), *stale.header());
+        } else {
+            // the stale block's rows are re-derived from its contents (and undo data)
+            let fetched = self
+                .client
+                .get_block_bytes(stale.hash())
+                .and_then(|block_bytes| {
+                    Ok((block_bytes, self.client.get_spent_bytes(stale.hash())?))
+                });
+            match fetched {
+                Ok((block_bytes, spent_bytes)) => {
+                    builder.index(stale.hash(), &block_bytes, &spent_bytes)?
+                }
+                Err(e) if e.is_not_found() => {
+                    // otherwise, they are the ones following the new tip
+                    warn!("block={} is not available: {}", stale.hash(), e);
+                    self.store.delete_from(self.chain.next_txpos(), stale)?;
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
         self.store.delete(&builder.into_batches())?;
-        Ok(stale.hash())
+        Ok(())
+    }
+
+    /// Fetch the new headers from the node (in large batches), rolling back stale blocks.
+    fn fetch_headers(&mut self, stats: &mut Stats) -> Result<(), Error> {
+        loop {
+            let known_tip = self
+                .pending
+                .back()
+                .map(bitcoin::block::Header::block_hash)
+                .or(self.chain.tip_hash());
+            let blockhash = known_tip.unwrap_or(self.genesis_hash);
+            let headers = self.client.get_headers(blockhash, MAX_HEADERS)?;
+            let first = match headers.first() {
+                Some(first) => first.block_hash(),
+                None if !self.pending.is_empty() => {
+                    warn!("block={} was reorged before being indexed", blockhash);
+                    self.pending.clear();
+                    continue;
+                }
+                None => {
+                    warn!(
+                        "block={} height={} was rolled back",
+                        blockhash,
+                        self.chain.tip_height().unwrap(),
+                    );
+                    assert_eq!(blockhash, self.drop_tip()?);
+                    stats.rolled_back_blocks += 1;
+                    continue;
+                }
+            };
+            // skip first response header (when asking for non-genesis block)
+            let skip_first = Some(first) == known_tip;
+            let count = headers.len() - usize::from(skip_first);
+            self.pending
+                .extend(headers.into_iter().skip(usize::from(skip_first)));
+            if count < MAX_HEADERS {
+                if self.pending.len() > MAX_HEADERS {
+                    info!("fetched {} new headers", self.pending.len());
+                }
+                return Ok(());
+            }
+        }
     }
 
+    /// Index the next (at most `limit`) blocks, failing over to another node if the active one is unreachable.
     pub fn sync(&mut self, limit: usize) -> Result<Stats, Error> {
+        let result = match self.sync_once(limit) {
+            Err(Error::Client(e)) if e.is_unreachable() && self.client.url_count() > 1 => {
+                warn!("{} is unreachable: {}", Redacted(self.client.url()), e);
+                if let Some(metrics) = &self.metrics {
+                    metrics.add_rpc_error();
+                }
+                if !self.failover() {
+                    return Err(e.into());
+                }
+                self.sync_once(limit)
+            }
+            res => res,
+        };
+        if let Some(metrics) = &self.metrics {
+            match &result {
+                Ok(stats) if stats.indexed_blocks > 0 || stats.rolled_back_blocks > 0 => {
+                    metrics.add_indexed_blocks(stats.indexed_blocks);
+                    metrics.set_tip_height(self.chain.tip_height().unwrap_or_default());
+                    metrics.set_db_size(self.store.size());
+                }
+                Err(Error::Client(_)) => metrics.add_rpc_error(),
+                _ => (),
+            }
+        }
+        result
+    }
+
+    fn sync_once(&mut self, limit: usize) -> Result<Stats, Error> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly);
+        }
         let mut stats = Stats::default();
         let t = std::time::Instant::now();
 
-        let headers = loop {
-            let blockhash = self.chain.tip_hash().unwrap_or(self.genesis_hash);
-            let headers = self.client.get_headers(blockhash, limit)?;
-            if let Some(first) = headers.first() {
-                // skip first response header (when asking for non-genesis block)
-                let skip_first = Some(first.block_hash()) == self.chain.tip_hash();
-                break headers.into_iter().skip(if skip_first { 1 } else { 0 });
+        if let Some(max_height) = self.max_height {
+            while self.chain.tip_height().is_some_and(|h| h > max_height) {
+                let blockhash = self.drop_tip()?;
+                stats.rolled_back_blocks += 1;
+                warn!(
+                    "block={} height={} was rolled back (max height={})",
+                    blockhash,
+                    self.chain.tip_height().map_or(0, |h| h + 1),
+                    max_height
+                );
             }
-            warn!(
-                "block={} height={} was rolled back",
-                blockhash,
-                self.chain.tip_height().unwrap(),
-            );
-            assert_eq!(blockhash, self.drop_tip()?);
-        };
+        }
+        let next_height = self.chain.tip_height().map_or(0, |h| h + 1);
+        let remaining = self
+            .max_height
+            .map_or(usize::MAX, |h| (h + 1).saturating_sub(next_height));
 
-        let mut builder = index::Builder::new(&self.chain);
-        for header in headers {
+        self.fetch_headers(&mut stats)?;
+        let count = self.pending.len().min(limit).min(remaining);
+        let mut headers: VecDeque<_> = self.pending.drain(..count).collect();
+
+        let mut builder = index::Builder::new(&self.chain).with_filters(self.options.filters);
+        while let Some(header) = headers.pop_front() {
+            if self.is_cancelled() {
+                // keep the rest of the headers for the next batch
+                headers.push_front(header);
+                for header in headers.drain(..).rev() {
+                    self.pending.push_front(header);
+                }
+                stats.cancelled = true;
+                break;
+            }
             let blockhash = header.block_hash();
             if self.chain.tip_hash() == Some(blockhash) {
                 continue; // skip first header from response
             }
+            if self.options.headers_only {
+                builder.add_header(blockhash, header);
+                stats.indexed_blocks += 1;
+                continue;
+            }
             // TODO: can be done concurrently
-            let block_bytes = self.client.get_block_bytes(blockhash)?;
+            let block_bytes = match self.client.get_block_bytes(blockhash) {
+                Ok(block_bytes) => block_bytes,
+                Err(e) if e.is_not_found() => {
+                    return Err(self.missing_block(next_height + stats.indexed_blocks, e))
+                }
+                Err(e) => return Err(e.into()),
+            };
             let spent_bytes = self.client.get_spent_bytes(blockhash)?;
             builder.index(blockhash, &block_bytes, &spent_bytes)?;
 
@@ -114,15 +913,33 @@ impl Index {
             stats.size_read += spent_bytes.len();
             stats.indexed_blocks += 1;
         }
+        let fetch = t.elapsed();
         let batches = builder.into_batches();
         self.store.write(&batches)?;
         for batch in batches {
+            if !self.subscriptions.is_empty() {
+                self.notify_indexed(&batch);
+            }
             self.chain.add(batch.header);
         }
 
         stats.elapsed = t.elapsed();
         if stats.indexed_blocks > 0 {
             self.store.flush()?;
+            if !self.mempool.is_empty() {
+                self.prune_mempool()?;
+            }
+            if let Some(progress) = self.progress.as_mut() {
+                let tip_height = self.chain.tip_height().unwrap();
+                progress(&Progress {
+                    heights: tip_height + 1 - stats.indexed_blocks..=tip_height,
+                    blocks: stats.indexed_blocks,
+                    bytes: stats.size_read,
+                    pending: self.pending.len(),
+                    fetch,
+                    write: t.elapsed() - fetch,
+                });
+            }
             info!(
                 "block={} height={}: indexed {} blocks, {:.3}[MB], dt = {:.3}[s]: {:.3} [ms/block], {:.3} [MB/block], {:.3} [MB/s]",
                 self.chain.tip_hash().unwrap(),
@@ -134,14 +951,427 @@ impl Index {
                 stats.size_read as f64 / (1e6 * stats.indexed_blocks as f64),
                 stats.size_read as f64 / (1e6 * stats.elapsed.as_secs_f64()),
             );
-        } else {
+        } else if !stats.cancelled {
             self.store.start_compactions()?;
         }
         Ok(stats)
     }
 
-    pub fn find(&self, script: &bitcoin::Script) -> Result<Vec<Location>, Error> {
-        let positions = self.store.scan(script)?;
+    /// Write the index (headers and script hash rows) in a portable format (see `load`).
+    pub fn dump(&self, mut output: impl Write) -> Result<DumpStats, Error> {
+        let mut stats = DumpStats::default();
+        output.write_all(DUMP_MAGIC)?;
+        output.write_all(&DUMP_VERSION.to_be_bytes())?;
+        output.write_all(self.genesis_hash.as_byte_array())?;
+        let headers = self.store.headers()?;
+        output.write_all(&(headers.len() as u64).to_be_bytes())?;
+        for header in &headers {
+            let (key, value) = header.serialize();
+            output.write_all(&key)?;
+            output.write_all(&value)?;
+        }
+        stats.headers = headers.len();
+        for row in self.store.script_hash_rows() {
+            output.write_all(row?.key())?;
+            stats.rows += 1;
+        }
+        output.flush()?;
+        Ok(stats)
+    }
+
+    /// Total size (in bytes) of the index DB files.
+    pub fn db_size(&self) -> u64 {
+        self.store.size()
+    }
+
+    pub fn tip_height(&self) -> Option<usize> {
+        self.chain.tip_height()
+    }
+
+    /// The highest block height with at least `min_conf` confirmations (if any).
+    pub fn trusted_height(&self, min_conf: usize) -> Option<usize> {
+        (self.tip_height()? + 1).checked_sub(min_conf.max(1))
+    }
+
+    pub fn tip_hash(&self) -> Option<bitcoin::BlockHash> {
+        self.chain.tip_hash()
+    }
+
+    pub fn get_header(&self, height: usize) -> Option<&bitcoin::block::Header> {
+        self.chain.get_by_height(height).map(index::Header::header)
+    }
+
+    pub fn get_height(&self, blockhash: &bitcoin::BlockHash) -> Option<usize> {
+        self.chain.get_height(blockhash)
+    }
+
+    pub fn get_block_hash(&self, height: usize) -> Option<bitcoin::BlockHash> {
+        self.chain.get_by_height(height).map(index::Header::hash)
+    }
+
+    pub fn median_time_past(&self, height: usize) -> Option<u32> {
+        self.chain.median_time_past(height)
+    }
+
+    pub fn get_location(&self, height: usize, offset: u64) -> Option<Location<'_>> {
+        Some(Location {
+            height,
+            offset,
+            indexed_header: self.chain.get_by_height(height)?,
+        })
+    }
+
+    pub fn chain(&self) -> &chain::Chain {
+        &self.chain
+    }
+
+    /// Returns `None` if the block is not part of the indexed chain.
+    pub fn confirmations(&self, blockhash: &bitcoin::BlockHash) -> Option<usize> {
+        let height = self.chain.get_height(blockhash)?;
+        Some(self.chain.tip_height()? - height + 1)
+    }
+
+    fn node_tip_header(
+        &self,
+        info: &client::ChainInfo,
+    ) -> Result<Option<bitcoin::block::Header>, Error> {
+        Ok(match self.chain.get_by_height(info.blocks) {
+            Some(header) if header.hash() == info.bestblockhash => Some(*header.header()),
+            _ => self
+                .client
+                .get_headers(info.bestblockhash, 0)?
+                .first()
+                .copied(),
+        })
+    }
+
+    /// Verify that the indexed tip is on the node's active chain, rolling back the diverging blocks
+    /// (e.g. after a reorg that happened while not syncing). Returns the number of rolled back blocks.
+    pub fn check_tip(&mut self) -> Result<usize, Error> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let info = self.client.get_chain_info()?;
+        let mut rolled_back = 0;
+        while let Some(tip_height) = self.chain.tip_height() {
+            // a lagging node can only be compared up to its own tip
+            let height = tip_height.min(info.blocks);
+            let node_hash = self.client.get_blockhash_by_height(height)?;
+            let indexed = self.chain.get_by_height(height).expect("missing header");
+            if indexed.hash() == node_hash {
+                break;
+            }
+            let blockhash = self.drop_tip()?;
+            rolled_back += 1;
+            warn!(
+                "block={} height={} diverged from the node's chain (rolled back)",
+                blockhash, tip_height
+            );
+        }
+        if rolled_back > 0 {
+            self.pending.clear();
+        }
+        Ok(rolled_back)
+    }
+
+    /// Returns the height of the node's first stored block, if it is pruned.
+    pub fn node_prune_height(&self) -> Result<Option<usize>, Error> {
+        let info = self.client.get_chain_info()?;
+        Ok(info.pruneheight.filter(|_| info.pruned))
+    }
+
+    /// Explain why the node doesn't have the block at `height` (e.g. since it was pruned).
+    fn missing_block(&self, height: usize, err: client::Error) -> Error {
+        match self.node_prune_height() {
+            Ok(Some(prune_height)) if height < prune_height => Error::Pruned(height, prune_height),
+            _ => err.into(),
+        }
+    }
+
+    /// Returns the node's best block height and header.
+    pub fn node_tip(&self) -> Result<Option<(usize, bitcoin::block::Header)>, Error> {
+        let info = self.client.get_chain_info()?;
+        Ok(self
+            .node_tip_header(&info)?
+            .map(|header| (info.blocks, header)))
+    }
+
+    /// Check whether the node seems to be stuck (compared to wall-clock time),
+    /// or rejected a longer chain (using `getchaintips` RPC).
+    pub fn check_node(&self, max_tip_age: Duration) -> Result<Vec<Alert>, Error> {
+        let alerts = self.node_alerts(max_tip_age)?;
+        if let Some(metrics) = &self.metrics {
+            metrics.set_node_alerts(alerts.len());
+        }
+        Ok(alerts)
+    }
+
+    fn node_alerts(&self, max_tip_age: Duration) -> Result<Vec<Alert>, Error> {
+        let info = self.client.get_chain_info()?;
+        let mut alerts = vec![];
+        if !info.initialblockdownload && info.headers > info.blocks {
+            alerts.push(Alert::UnvalidatedHeaders {
+                blocks: info.blocks,
+                headers: info.headers,
+            });
+        }
+        let invalid = self
+            .client
+            .get_chain_tips()?
+            .into_iter()
+            .filter(|tip| tip.status == "invalid" && tip.height > info.blocks)
+            .max_by_key(|tip| tip.height);
+        if let Some(tip) = invalid {
+            alerts.push(Alert::InvalidChain {
+                height: tip.height,
+                hash: tip.hash,
+            });
+        }
+        let tip = match self.node_tip_header(&info)? {
+            Some(header) => header,
+            None => return Ok(alerts),
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let age = now.saturating_sub(Duration::from_secs(tip.time.into()));
+        if age > max_tip_age {
+            alerts.push(Alert::StaleTip {
+                height: info.blocks,
+                age: Duration::from_secs(age.as_secs()),
+            });
+        }
+        Ok(alerts)
+    }
+
+    /// Relay the transaction through the node (using `sendrawtransaction` RPC),
+    /// and add it to the mempool view (see `unconfirmed_history`) until it confirms.
+    pub fn broadcast(&mut self, tx: &bitcoin::Transaction) -> Result<bitcoin::Txid, Error> {
+        let txid = self.client.send_raw_transaction(tx)?;
+        info!("broadcasted txid={}", txid);
+        self.mempool.add(tx.clone());
+        Ok(txid)
+    }
+
+    /// The broadcast transactions, which are not confirmed yet.
+    pub fn mempool(&self) -> &Mempool {
+        &self.mempool
+    }
+
+    /// The script's history, including its broadcast transactions (which are not confirmed yet).
+    pub fn unconfirmed_history(
+        &self,
+        script: &bitcoin::Script,
+    ) -> Result<UnconfirmedHistory, Error> {
+        let script_hash = index::ScriptHash::hash(script.as_bytes());
+        let confirmed = self.script_history(&script_hash)?;
+        Ok(self.mempool.history(script, &confirmed))
+    }
+
+    /// Drop the broadcast transactions which were confirmed (or evicted from the node's mempool).
+    fn prune_mempool(&mut self) -> Result<(), Error> {
+        let mut pruned = vec![];
+        for tx in self.mempool.txs() {
+            let txid = tx.compute_txid();
+            if let Some(location) = self.locate_tx(tx)? {
+                debug!("txid={} confirmed at height={}", txid, location.height);
+                pruned.push(txid);
+            } else if self.pending.is_empty() && !self.client.in_mempool(txid)? {
+                // the node's blocks are indexed, so it was not confirmed
+                warn!("txid={} was evicted from the mempool", txid);
+                pruned.push(txid);
+            }
+        }
+        for txid in pruned {
+            self.mempool.remove(txid);
+        }
+        Ok(())
+    }
+
+    /// Scan the node's UTXO set for the given scripts (using `scantxoutset` RPC).
+    pub fn scan_node_utxos(
+        &self,
+        scripts: &[bitcoin::ScriptBuf],
+    ) -> Result<client::UtxoScan, Error> {
+        Ok(self.client.scan_tx_out_set(scripts)?)
+    }
+
+    /// Fetch and index the given blocks in memory (without writing them to the DB).
+    pub fn bench(&self, heights: std::ops::RangeInclusive<usize>) -> Result<BenchStats, Error> {
+        let mut stats = BenchStats::default();
+        for height in heights {
+            let t = std::time::Instant::now();
+            let hash = self.client.get_blockhash_by_height(height)?;
+            let block_bytes = self.client.get_block_bytes(hash)?;
+            let spent_bytes = self.client.get_spent_bytes(hash)?;
+            stats.fetch += t.elapsed();
+
+            let t = std::time::Instant::now();
+            let batch =
+                index::Batch::build(hash, index::TxPos::default(), &block_bytes, &spent_bytes)?;
+            stats.index += t.elapsed();
+
+            stats.blocks += 1;
+            stats.bytes += block_bytes.len() + spent_bytes.len();
+            stats.rows += batch.script_hash_rows.len();
+        }
+        Ok(stats)
+    }
+
+    /// Re-index the given (already indexed) blocks, restoring the missing rows of the given scripts
+    /// (skipping the blocks below each script's birthday height).
+    pub fn rescan(
+        &self,
+        heights: std::ops::RangeInclusive<usize>,
+        scripts: &[(bitcoin::ScriptBuf, usize)],
+    ) -> Result<RescanStats, Error> {
+        // each prefix is rescanned from its earliest script's birthday height
+        let mut prefixes = std::collections::HashMap::new();
+        for (script, birthday) in scripts {
+            prefixes
+                .entry(index::ScriptHashPrefix::new(script))
+                .and_modify(|height: &mut usize| *height = (*height).min(*birthday))
+                .or_insert(*birthday);
+        }
+        let mut stats = RescanStats::default();
+        for height in heights {
+            let header = self
+                .chain
+                .get_by_height(height)
+                .ok_or(Error::NotIndexed(height))?;
+            let txpos = match height.checked_sub(1) {
+                None => index::TxPos::default(),
+                Some(prev_height) => self.chain.get_by_height(prev_height).unwrap().next_txpos(),
+            };
+            let block_bytes = self.client.get_block_bytes(header.hash())?;
+            let spent_bytes = self.client.get_spent_bytes(header.hash())?;
+            let batch = index::Batch::build(header.hash(), txpos, &block_bytes, &spent_bytes)?;
+            if batch.header != *header {
+                return Err(Error::InvalidPosition(header.next_txpos()));
+            }
+            let rows: Vec<_> = batch
+                .script_hash_rows
+                .into_iter()
+                .filter(|row| prefixes.get(&row.prefix()).is_some_and(|&b| b <= height))
+                .collect();
+            let restored = self.store.add_missing(&rows)?;
+            if restored > 0 {
+                warn!(
+                    "block={} height={}: restored {} missing rows",
+                    header.hash(),
+                    height,
+                    restored
+                );
+            }
+            stats.blocks += 1;
+            stats.matched += rows.len();
+            stats.restored += restored;
+        }
+        Ok(stats)
+    }
+
+    /// Verify that each stored block hash matches its header (the header chain's links
+    /// are verified when the index is opened).
+    pub fn verify_headers(&self) -> Vec<Corruption> {
+        let heights = 0..self.chain.tip_height().map_or(0, |h| h + 1);
+        heights
+            .filter(|&height| {
+                let header = self.chain.get_by_height(height).unwrap();
+                header.hash() != header.header().block_hash()
+            })
+            .map(|height| Corruption::Header { height })
+            .collect()
+    }
+
+    /// Re-fetch an indexed block from the node, and verify its stored header and rows,
+    /// and the transaction at `offset` (modulo the block's transactions count).
+    pub fn audit(&self, height: usize, offset: u64) -> Result<Vec<Corruption>, Error> {
+        let header = self
+            .chain
+            .get_by_height(height)
+            .ok_or(Error::NotIndexed(height))?;
+        let mut found = vec![];
+        if self.options.headers_only {
+            let headers = self.client.get_headers(header.hash(), 0)?;
+            if headers.first() != Some(header.header()) {
+                found.push(Corruption::Header { height });
+            }
+            return Ok(found);
+        }
+        let txpos = match height.checked_sub(1) {
+            None => index::TxPos::default(),
+            Some(prev_height) => self.chain.get_by_height(prev_height).unwrap().next_txpos(),
+        };
+        let block_bytes = self.client.get_block_bytes(header.hash())?;
+        let spent_bytes = self.client.get_spent_bytes(header.hash())?;
+        let batch = index::Batch::build(header.hash(), txpos, &block_bytes, &spent_bytes)?;
+        if batch.header != *header {
+            found.push(Corruption::Header { height });
+        }
+        let count = self.store.count_missing(&batch.script_hash_rows)?;
+        if count > 0 {
+            found.push(Corruption::MissingRows { height, count });
+        }
+        let block: bitcoin::Block =
+            bitcoin::consensus::deserialize(&block_bytes.0).map_err(index::Error::Decode)?;
+        let offset = offset % block.txdata.len() as u64;
+        let location = Location {
+            height,
+            offset,
+            indexed_header: header,
+        };
+        let tx = &block.txdata[usize::try_from(offset).unwrap()];
+        if self.get_tx_bytes(&location)? != bitcoin::consensus::serialize(tx) {
+            found.push(Corruption::Tx { height, offset });
+        }
+        Ok(found)
+    }
+
+    pub fn find(&self, script: &bitcoin::Script) -> Result<Vec<Location<'_>>, Error> {
+        self.find_since(script, 0)
+    }
+
+    /// Find the transactions confirmed at `height` or above.
+    pub fn find_since(
+        &self,
+        script: &bitcoin::Script,
+        height: usize,
+    ) -> Result<Vec<Location<'_>>, Error> {
+        self.find_in_range(script, height..usize::MAX)
+    }
+
+    /// The position of the first transaction at `height` (if its previous block is indexed).
+    fn first_txpos(&self, height: usize) -> Option<index::TxPos> {
+        match height.checked_sub(1) {
+            None => Some(index::TxPos::default()),
+            Some(prev_height) => self
+                .chain
+                .get_by_height(prev_height)
+                .map(index::Header::next_txpos),
+        }
+    }
+
+    /// Find the transactions confirmed within the given heights
+    /// (scanning only their rows, so recent activity is cheap to query).
+    pub fn find_in_range(
+        &self,
+        script: &bitcoin::Script,
+        heights: std::ops::Range<usize>,
+    ) -> Result<Vec<Location<'_>>, Error> {
+        let Some(from) = self.first_txpos(heights.start) else {
+            return Ok(vec![]);
+        };
+        let until = self
+            .first_txpos(heights.end)
+            .unwrap_or_else(|| self.chain.next_txpos());
+        if from >= until {
+            return Ok(vec![]);
+        }
+        let t = std::time::Instant::now();
+        let positions = self.store.scan(script, from..until)?;
+        if let Some(metrics) = &self.metrics {
+            metrics.observe_query(t.elapsed());
+        }
         positions
             .into_iter()
             .map(|txpos| {
@@ -152,9 +1382,305 @@ impl Index {
             .collect::<Result<Vec<Location>, Error>>()
     }
 
-    pub fn get_tx_bytes(&self, location: &Location) -> Result<Vec<u8>, Error> {
-        Ok(self
+    /// Iterate lazily over the transactions matching the script (in confirmation order),
+    /// so a busy address' history can be paginated without loading all of it.
+    pub fn find_iter(
+        &self,
+        script: &bitcoin::Script,
+    ) -> impl Iterator<Item = Result<Location<'_>, Error>> + '_ {
+        let prefix = index::ScriptHashPrefix::new(script);
+        self.store
+            .scan_prefix_iter(prefix, index::TxPos::default()..self.chain.next_txpos())
+            .map(|txpos| {
+                let txpos = txpos?;
+                self.chain
+                    .find_by_txpos(&txpos)
+                    .ok_or(Error::InvalidPosition(txpos))
+            })
+    }
+
+    /// Returns at most `limit` of the transactions matching the script (in confirmation order),
+    /// skipping the first `offset` ones.
+    pub fn find_page(
+        &self,
+        script: &bitcoin::Script,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<Location<'_>>, Error> {
+        self.find_iter(script).skip(offset).take(limit).collect()
+    }
+
+    /// Find the transactions matching the script hash prefix (which may include false positives,
+    /// see `script_history()` for a verified history).
+    pub fn find_by_script_hash(
+        &self,
+        script_hash: &index::ScriptHash,
+    ) -> Result<Vec<Location<'_>>, Error> {
+        let prefix = index::ScriptHashPrefix::from_script_hash(script_hash);
+        let t = std::time::Instant::now();
+        let positions = self
+            .store
+            .scan_prefix(prefix, index::TxPos::default()..self.chain.next_txpos())?;
+        if let Some(metrics) = &self.metrics {
+            metrics.observe_query(t.elapsed());
+        }
+        positions
+            .into_iter()
+            .map(|txpos| {
+                self.chain
+                    .find_by_txpos(&txpos)
+                    .ok_or_else(|| Error::InvalidPosition(txpos))
+            })
+            .collect()
+    }
+
+    /// Returns the confirmed transactions funding or spending the script (given its hash).
+    /// The index rows match only a prefix of the script hash, so each transaction is verified:
+    /// it must fund the script, or spend an output funded by an earlier transaction.
+    pub fn script_history(&self, script_hash: &index::ScriptHash) -> Result<ScriptHistory, Error> {
+        build_history(
+            &self.client,
+            script_hash,
+            &self.find_positions(script_hash)?,
+        )
+    }
+
+    /// The positions of the transactions matching the script hash's prefix
+    /// (in confirmation order, to be fetched using `Fetcher::script_history`).
+    pub fn find_positions(
+        &self,
+        script_hash: &index::ScriptHash,
+    ) -> Result<Vec<TxPosition>, Error> {
+        let mut positions: Vec<TxPosition> = self
+            .find_by_script_hash(script_hash)?
+            .into_iter()
+            .map(|loc| TxPosition {
+                height: loc.height,
+                offset: loc.offset,
+                block_hash: loc.indexed_header.hash(),
+            })
+            .collect();
+        positions.sort_unstable();
+        Ok(positions)
+    }
+
+    /// A handle for fetching data from the node, without borrowing the index.
+    pub fn fetcher(&self) -> Fetcher {
+        Fetcher {
+            client: self.client.clone(),
+        }
+    }
+
+    pub fn genesis_hash(&self) -> bitcoin::BlockHash {
+        self.genesis_hash
+    }
+
+    /// Returns the script's confirmed unspent outputs (ordered by outpoint).
+    pub fn list_unspent(&self, script: &bitcoin::Script) -> Result<Vec<Utxo>, Error> {
+        let script_hash = index::ScriptHash::hash(script.as_bytes());
+        let history = self.script_history(&script_hash)?;
+        Ok(history
+            .unspent
+            .into_iter()
+            .map(|(outpoint, (height, value))| Utxo {
+                outpoint,
+                value,
+                height,
+                script: script.to_owned(),
+            })
+            .collect())
+    }
+
+    /// Returns the script's confirmed balance (the index doesn't track the mempool).
+    pub fn get_balance(&self, script: &bitcoin::Script) -> Result<bitcoin::Amount, Error> {
+        let script_hash = index::ScriptHash::hash(script.as_bytes());
+        let history = self.script_history(&script_hash)?;
+        Ok(history
+            .unspent
+            .values()
+            .map(|(_height, value)| *value)
+            .sum())
+    }
+
+    /// Returns the BIP158 filter of the block at `height` (if it was built during sync).
+    pub fn get_filter(&self, height: usize) -> Result<Option<bitcoin::bip158::BlockFilter>, Error> {
+        match self.chain.get_by_height(height) {
+            Some(header) => Ok(self.store.get_filter(header.hash())?),
+            None => Err(Error::NotIndexed(height)),
+        }
+    }
+
+    /// Returns the heights (in the given range) whose block filters match any of the scripts.
+    /// Filters may have false positives, so the matching blocks should be verified.
+    pub fn match_filters(
+        &self,
+        scripts: &[bitcoin::ScriptBuf],
+        heights: std::ops::RangeInclusive<usize>,
+    ) -> Result<Vec<usize>, Error> {
+        let mut matched = vec![];
+        for height in heights {
+            let filter = self
+                .get_filter(height)?
+                .ok_or(Error::MissingFilter(height))?;
+            let hash = self.chain.get_by_height(height).unwrap().hash();
+            if filter.match_any(&hash, scripts.iter().map(|s| s.as_bytes()))? {
+                matched.push(height);
+            }
+        }
+        Ok(matched)
+    }
+
+    /// Returns a merkle proof (with the block header) for the transaction at `location`.
+    pub fn get_merkle_proof(&self, location: &Location) -> Result<bitcoin::MerkleBlock, Error> {
+        let block_bytes = self
             .client
-            .get_tx_bytes_from_block(location.indexed_header.hash(), location.offset)?)
+            .get_block_bytes(location.indexed_header.hash())?;
+        let block: bitcoin::Block =
+            bitcoin::consensus::deserialize(&block_bytes.0).map_err(index::Error::Decode)?;
+        let txid = block
+            .txdata
+            .get(usize::try_from(location.offset).unwrap())
+            .ok_or_else(|| Error::InvalidOffset(location.indexed_header.hash(), location.offset))?
+            .compute_txid();
+        Ok(bitcoin::MerkleBlock::from_block_with_predicate(
+            &block,
+            |t| *t == txid,
+        ))
+    }
+
+    /// Fetch the block at `height` from the node (and its undo data, for computing the fees).
+    pub fn get_block_summary(&self, height: usize) -> Result<Option<BlockSummary>, Error> {
+        let header = match self.chain.get_by_height(height) {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let block_bytes = self.client.get_block_bytes(header.hash())?;
+        let block: bitcoin::Block =
+            bitcoin::consensus::deserialize(&block_bytes.0).map_err(index::Error::Decode)?;
+        let fees = match self.client.get_spent_bytes(header.hash()) {
+            Ok(spent_bytes) => {
+                let inputs = spent_bytes.total_value()?;
+                let outputs: bitcoin::Amount = block
+                    .txdata
+                    .iter()
+                    .filter(|tx| !tx.is_coinbase())
+                    .flat_map(|tx| &tx.output)
+                    .map(|txo| txo.value)
+                    .sum();
+                Some(inputs - outputs)
+            }
+            Err(e) => {
+                warn!("block={} has no undo data: {}", header.hash(), e);
+                None
+            }
+        };
+        Ok(Some(BlockSummary {
+            height,
+            header: *header.header(),
+            tx_count: block.txdata.len(),
+            fees,
+        }))
+    }
+
+    /// Compute the fee paid by `tx` (confirmed at `location`), using its block's undo data
+    /// for the input values. Returns `None` for coinbase transactions.
+    pub fn get_tx_fee(
+        &self,
+        location: &Location,
+        tx: &bitcoin::Transaction,
+    ) -> Result<Option<bitcoin::Amount>, Error> {
+        if tx.is_coinbase() {
+            return Ok(None);
+        }
+        let inputs: bitcoin::Amount = self
+            .get_spent_outputs(location)?
+            .iter()
+            .map(|txo| txo.value)
+            .sum();
+        let outputs: bitcoin::Amount = tx.output.iter().map(|txo| txo.value).sum();
+        Ok(Some(inputs - outputs))
+    }
+
+    /// The outputs spent by the transaction at `location` (using its block's undo data).
+    pub fn get_spent_outputs(&self, location: &Location) -> Result<Vec<bitcoin::TxOut>, Error> {
+        let hash = location.indexed_header.hash();
+        let spent_bytes = self.client.get_spent_bytes(hash)?;
+        let offset = usize::try_from(location.offset).unwrap();
+        spent_bytes
+            .tx_outputs(offset)?
+            .ok_or(Error::InvalidOffset(hash, location.offset))
+    }
+
+    /// Fetch a transaction by its txid (requiring the node's `-txindex` for confirmed ones).
+    pub fn get_transaction(&self, txid: bitcoin::Txid) -> Result<bitcoin::Transaction, Error> {
+        let tx_bytes = self.client.get_raw_transaction(txid)?;
+        Ok(bitcoin::consensus::deserialize(&tx_bytes).map_err(index::Error::Decode)?)
+    }
+
+    /// Find where `tx` is confirmed, by intersecting its output scripts' histories
+    /// (so only the few remaining candidates are fetched from the node).
+    pub fn locate_tx(&self, tx: &bitcoin::Transaction) -> Result<Option<Location<'_>>, Error> {
+        let mut candidates: Option<BTreeSet<Location>> = None;
+        // unspendable outputs are not indexed
+        for txo in tx
+            .output
+            .iter()
+            .filter(|txo| !txo.script_pubkey.is_op_return())
+        {
+            let locations = self.find(&txo.script_pubkey)?;
+            match candidates.as_mut() {
+                None => candidates = Some(locations.into_iter().collect()),
+                Some(candidates) => candidates.retain(|loc| locations.contains(loc)),
+            }
+        }
+        let tx_bytes = bitcoin::consensus::serialize(tx);
+        for location in candidates.unwrap_or_default() {
+            if self.get_tx_bytes(&location)? == tx_bytes {
+                return Ok(Some(location));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn get_tx_bytes(&self, location: &Location) -> Result<Vec<u8>, Error> {
+        let hash = location.indexed_header.hash();
+        Ok(self.client.get_tx_bytes_from_block(hash, location.offset)?)
+    }
+
+    /// Like `get_tx_bytes`, but falls back to `getrawtransaction` (requiring the node's `-txindex`)
+    /// when the block is not available, e.g. after it was pruned by the node.
+    pub fn get_tx_bytes_by_txid(
+        &self,
+        location: &Location,
+        txid: bitcoin::Txid,
+    ) -> Result<Vec<u8>, Error> {
+        let hash = location.indexed_header.hash();
+        let err = match self.client.get_tx_bytes_from_block(hash, location.offset) {
+            Ok(tx_bytes) => return Ok(tx_bytes),
+            Err(err) => err,
+        };
+        warn!(
+            "failed to get tx from block={} offset={}: {}, falling back to RPC for {}",
+            hash, location.offset, err, txid
+        );
+        Ok(self.client.get_raw_transaction(txid)?)
+    }
+
+    /// Returns the transaction at `location` from the cache, or fetches it (using its cached txid
+    /// for the `getrawtransaction` fallback) and adds it to the cache.
+    pub fn get_cached_tx_bytes(
+        &self,
+        location: &Location,
+        cache: &Cache,
+    ) -> Result<Vec<u8>, Error> {
+        if let Some(tx_bytes) = cache.get_tx_bytes(location)? {
+            return Ok(tx_bytes);
+        }
+        let tx_bytes = match cache.get_txid(location)? {
+            Some(txid) => self.get_tx_bytes_by_txid(location, txid)?,
+            None => self.get_tx_bytes(location)?,
+        };
+        cache.add_tx_bytes(location, &tx_bytes)?;
+        Ok(tx_bytes)
     }
 }
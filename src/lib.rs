@@ -1,7 +1,20 @@
+pub mod cache;
 mod chain;
 mod client;
 mod db;
+pub mod descriptor;
+pub mod electrum;
+#[cfg(feature = "http")]
+pub mod http;
+mod httpd;
 mod index;
+pub mod mempool;
+pub mod metrics;
+pub mod network;
+pub mod notify;
+pub mod redact;
+pub mod zmq;
 
-pub use chain::Location;
-pub use index::address;
+pub use chain::{Chain, Location};
+pub use client::{Auth, Unspent, UtxoScan};
+pub use index::{address, ScriptHash};
@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+
+use bitcoin::{Amount, OutPoint, Script, Transaction, Txid};
+
+use crate::address::ScriptHistory;
+
+/// The transactions broadcast using `Index::broadcast`, until they confirm
+/// (or are dropped from the node's mempool).
+#[derive(Default)]
+pub struct Mempool {
+    /// In broadcast order (so a transaction is added after its unconfirmed parents)
+    txs: Vec<(Txid, Transaction)>,
+}
+
+/// A script's confirmed history, updated by the unconfirmed transactions
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct UnconfirmedHistory {
+    /// The unconfirmed transactions funding the script or spending its outputs (in broadcast order)
+    pub txs: Vec<Txid>,
+    /// The confirmed and unconfirmed outputs, which are not spent by an unconfirmed transaction
+    pub unspent: BTreeMap<OutPoint, Amount>,
+}
+
+impl UnconfirmedHistory {
+    pub fn balance(&self) -> Amount {
+        self.unspent.values().copied().sum()
+    }
+}
+
+impl Mempool {
+    pub fn add(&mut self, tx: Transaction) {
+        let txid = tx.compute_txid();
+        if self.get(txid).is_none() {
+            self.txs.push((txid, tx));
+        }
+    }
+
+    pub fn get(&self, txid: Txid) -> Option<&Transaction> {
+        self.txs
+            .iter()
+            .find_map(|(id, tx)| (*id == txid).then_some(tx))
+    }
+
+    pub fn remove(&mut self, txid: Txid) -> Option<Transaction> {
+        let i = self.txs.iter().position(|(id, _tx)| *id == txid)?;
+        Some(self.txs.remove(i).1)
+    }
+
+    pub fn txs(&self) -> impl Iterator<Item = &Transaction> {
+        self.txs.iter().map(|(_txid, tx)| tx)
+    }
+
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    /// Apply the unconfirmed transactions to the script's confirmed history.
+    pub fn history(&self, script: &Script, confirmed: &ScriptHistory) -> UnconfirmedHistory {
+        let mut history = UnconfirmedHistory {
+            txs: vec![],
+            unspent: confirmed
+                .unspent
+                .iter()
+                .map(|(outpoint, (_height, value))| (*outpoint, *value))
+                .collect(),
+        };
+        for (txid, tx) in &self.txs {
+            let mut matched = false;
+            for txin in &tx.input {
+                matched |= history.unspent.remove(&txin.previous_output).is_some();
+            }
+            for (vout, txo) in tx.output.iter().enumerate() {
+                if txo.script_pubkey.as_script() == script {
+                    history
+                        .unspent
+                        .insert(OutPoint::new(*txid, vout as u32), txo.value);
+                    matched = true;
+                }
+            }
+            if matched {
+                history.txs.push(*txid);
+            }
+        }
+        history
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::{hashes::Hash, ScriptBuf, TxIn, TxOut};
+
+    fn tx(inputs: &[OutPoint], outputs: &[(&Script, u64)]) -> Transaction {
+        Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|outpoint| TxIn {
+                    previous_output: *outpoint,
+                    ..TxIn::default()
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|(script, value)| TxOut {
+                    script_pubkey: script.to_owned().into(),
+                    value: Amount::from_sat(*value),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let script = ScriptBuf::from_bytes(b"\x51".to_vec());
+        let other = ScriptBuf::from_bytes(b"\x52".to_vec());
+        let confirmed_txid = Txid::from_byte_array([1; 32]);
+        let confirmed = ScriptHistory {
+            txs: vec![(100, confirmed_txid)],
+            unspent: [
+                (
+                    OutPoint::new(confirmed_txid, 0),
+                    (100, Amount::from_sat(10)),
+                ),
+                (
+                    OutPoint::new(confirmed_txid, 1),
+                    (100, Amount::from_sat(20)),
+                ),
+            ]
+            .into(),
+        };
+        let mut mempool = Mempool::default();
+        assert_eq!(mempool.history(&script, &confirmed).balance().to_sat(), 30);
+
+        // spends the first output, sending 3 sats back to the script
+        let spend = tx(
+            &[OutPoint::new(confirmed_txid, 0)],
+            &[(&other, 6), (&script, 3)],
+        );
+        let spend_txid = spend.compute_txid();
+        mempool.add(spend.clone());
+        mempool.add(spend.clone());
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.get(spend_txid), Some(&spend));
+
+        // unrelated to the script
+        let unrelated = tx(&[OutPoint::new(spend_txid, 0)], &[(&other, 5)]);
+        let unrelated_txid = unrelated.compute_txid();
+        mempool.add(unrelated);
+
+        let history = mempool.history(&script, &confirmed);
+        assert_eq!(history.txs, vec![spend_txid]);
+        assert_eq!(
+            history.unspent,
+            [
+                (OutPoint::new(confirmed_txid, 1), Amount::from_sat(20)),
+                (OutPoint::new(spend_txid, 1), Amount::from_sat(3)),
+            ]
+            .into()
+        );
+        assert_eq!(
+            mempool.history(&other, &ScriptHistory::default()).txs.len(),
+            2
+        );
+
+        assert_eq!(mempool.remove(spend_txid), Some(spend));
+        assert_eq!(mempool.remove(spend_txid), None);
+        assert_eq!(
+            mempool
+                .txs()
+                .map(|tx| tx.compute_txid())
+                .collect::<Vec<_>>(),
+            vec![unrelated_txid]
+        );
+        assert_eq!(mempool.history(&script, &confirmed).balance().to_sat(), 30);
+    }
+}
@@ -0,0 +1,188 @@
+//! Prometheus metrics (exposed in the text format over HTTP).
+//!
+//! https://prometheus.io/docs/instrumenting/exposition_formats/
+
+use std::{
+    fmt::Write as _,
+    net::{TcpListener, TcpStream},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use log::*;
+
+use crate::httpd;
+
+/// Upper bounds (in seconds) of the query latency histogram buckets
+const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.002, 0.005, 0.01, 0.02, 0.05, 0.1, 0.2, 0.5, 1.0];
+
+#[derive(Default)]
+pub struct Metrics {
+    tip_height: AtomicU64,
+    indexed_blocks: AtomicU64,
+    db_size: AtomicU64,
+    rpc_errors: AtomicU64,
+    node_alerts: AtomicU64,
+    query_latency: Histogram,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let micros = duration.as_micros().try_into().unwrap_or(u64::MAX);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative).unwrap();
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count).unwrap();
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        writeln!(out, "{}_sum {}", name, sum).unwrap();
+        writeln!(out, "{}_count {}", name, count).unwrap();
+    }
+}
+
+impl Metrics {
+    pub fn set_tip_height(&self, height: usize) {
+        self.tip_height.store(height as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_indexed_blocks(&self, blocks: usize) {
+        self.indexed_blocks
+            .fetch_add(blocks as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_db_size(&self, size: u64) {
+        self.db_size.store(size, Ordering::Relaxed);
+    }
+
+    pub fn add_rpc_error(&self) {
+        self.rpc_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_node_alerts(&self, alerts: usize) {
+        self.node_alerts.store(alerts as u64, Ordering::Relaxed);
+    }
+
+    pub fn observe_query(&self, duration: Duration) {
+        self.query_latency.observe(duration);
+    }
+
+    /// Render the metrics using Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &AtomicU64| {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+            writeln!(out, "{} {}", name, value.load(Ordering::Relaxed)).unwrap();
+        };
+        metric(
+            "bindex_tip_height",
+            "gauge",
+            "Height of the indexed tip",
+            &self.tip_height,
+        );
+        metric(
+            "bindex_indexed_blocks_total",
+            "counter",
+            "Number of blocks indexed by this process",
+            &self.indexed_blocks,
+        );
+        metric(
+            "bindex_db_size_bytes",
+            "gauge",
+            "Total size of the DB files",
+            &self.db_size,
+        );
+        metric(
+            "bindex_rpc_errors_total",
+            "counter",
+            "Number of failed node requests",
+            &self.rpc_errors,
+        );
+        metric(
+            "bindex_node_alerts",
+            "gauge",
+            "Number of the node's current health alerts",
+            &self.node_alerts,
+        );
+        self.query_latency.render(
+            &mut out,
+            "bindex_query_duration_seconds",
+            "Latency of index lookups",
+        );
+        out
+    }
+}
+
+/// Serve the metrics over HTTP (at `/metrics`), handling each client in a separate thread.
+pub fn serve(metrics: &Metrics, listener: TcpListener) -> std::io::Result<()> {
+    std::thread::scope(|s| {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            s.spawn(move || {
+                if let Err(e) = handle_client(metrics, stream) {
+                    warn!("metrics client failed: {}", e);
+                }
+            });
+        }
+        Ok(())
+    })
+}
+
+fn handle_client(metrics: &Metrics, stream: TcpStream) -> std::io::Result<()> {
+    let request = httpd::read_request(&stream)?;
+    let (status, body) = match request
+        .as_ref()
+        .map(|r| (r.method.as_str(), r.path.as_str()))
+    {
+        Some(("GET", "/metrics")) => ("200 OK", metrics.render()),
+        Some(("GET", _)) => ("404 Not Found", "not found\n".to_owned()),
+        _ => ("400 Bad Request", "bad request\n".to_owned()),
+    };
+    httpd::write_response(&stream, status, "text/plain; version=0.0.4", &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.set_tip_height(123);
+        metrics.add_indexed_blocks(10);
+        metrics.add_indexed_blocks(5);
+        metrics.set_node_alerts(2);
+        metrics.observe_query(Duration::from_micros(1500));
+        metrics.observe_query(Duration::from_secs(2));
+        let text = metrics.render();
+        assert!(text.contains("\nbindex_tip_height 123\n"));
+        assert!(text.contains("\nbindex_indexed_blocks_total 15\n"));
+        assert!(text.contains("\nbindex_node_alerts 2\n"));
+        assert!(text.contains("\nbindex_query_duration_seconds_bucket{le=\"0.001\"} 0\n"));
+        assert!(text.contains("\nbindex_query_duration_seconds_bucket{le=\"0.002\"} 1\n"));
+        assert!(text.contains("\nbindex_query_duration_seconds_bucket{le=\"1\"} 1\n"));
+        assert!(text.contains("\nbindex_query_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("\nbindex_query_duration_seconds_sum 2.0015\n"));
+        assert!(text.contains("\nbindex_query_duration_seconds_count 2\n"));
+    }
+}
@@ -0,0 +1,87 @@
+//! Supported networks, with their default node RPC ports and index DB subdirectories.
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("unsupported network: {0}")]
+    Unsupported(bitcoin::Network),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, clap::ValueEnum, Debug)]
+pub enum Network {
+    Bitcoin,
+    Testnet,
+    Testnet4,
+    Regtest,
+    Signet,
+}
+
+impl Network {
+    /// Default JSON-RPC port of the node
+    pub fn default_rpc_port(self) -> u16 {
+        match self {
+            Network::Bitcoin => 8332,
+            Network::Testnet => 18332,
+            Network::Testnet4 => 48332,
+            Network::Regtest => 18443,
+            Network::Signet => 38332,
+        }
+    }
+
+    /// Default node URL (on localhost)
+    pub fn default_rpc_url(self) -> String {
+        format!("http://localhost:{}", self.default_rpc_port())
+    }
+
+    /// Subdirectory name for the network's index DB
+    pub fn db_dir(self) -> &'static str {
+        match self {
+            Network::Bitcoin => "bitcoin",
+            Network::Testnet => "testnet",
+            Network::Testnet4 => "testnet4",
+            Network::Regtest => "regtest",
+            Network::Signet => "signet",
+        }
+    }
+}
+
+impl From<Network> for bitcoin::Network {
+    fn from(network: Network) -> Self {
+        match network {
+            Network::Bitcoin => bitcoin::Network::Bitcoin,
+            Network::Testnet => bitcoin::Network::Testnet,
+            Network::Testnet4 => bitcoin::Network::Testnet4,
+            Network::Regtest => bitcoin::Network::Regtest,
+            Network::Signet => bitcoin::Network::Signet,
+        }
+    }
+}
+
+impl TryFrom<bitcoin::Network> for Network {
+    type Error = Error;
+
+    fn try_from(network: bitcoin::Network) -> Result<Self, Error> {
+        Ok(match network {
+            bitcoin::Network::Bitcoin => Network::Bitcoin,
+            bitcoin::Network::Testnet => Network::Testnet,
+            bitcoin::Network::Testnet4 => Network::Testnet4,
+            bitcoin::Network::Regtest => Network::Regtest,
+            bitcoin::Network::Signet => Network::Signet,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Network;
+    use clap::ValueEnum;
+
+    #[test]
+    fn test_roundtrip() {
+        for &network in Network::value_variants() {
+            let converted = bitcoin::Network::from(network);
+            assert_eq!(Network::try_from(converted).unwrap(), network);
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! Notification channels for the watched addresses' new transactions, each with its own filters.
+//!
+//! A channel is specified as comma-separated `key=value` pairs, starting with its target:
+//! - `webhook=http://host:port/path`: POST the notification as a JSON object (over plain HTTP)
+//! - `exec=/path/to/command`: run the command, passing the notification in `BINDEX_*` variables
+//!
+//! followed by its (optional) filters:
+//! - `direction=incoming` or `direction=outgoing`
+//! - `min-amount=<BTC>`: skip transactions moving less than this amount
+//! - `address=<address>` (may be repeated): only notify about these addresses
+//!   (which must be on the indexed network, see `Channel::check_network`)
+//!
+//! e.g. `webhook=http://localhost:8000/,direction=incoming,min-amount=0.001`
+//!
+//! Alerts (e.g. about the node's health) are sent to all the channels, regardless of their filters:
+//! as a `{"alert": <message>}` JSON object, or in the `BINDEX_ALERT` variable.
+//!
+//! There are no desktop or Telegram backends: an `exec` command can forward the notifications
+//! (e.g. using `notify-send` or the Telegram bot API).
+
+use std::{
+    collections::HashSet,
+    process::{Child, Command, ExitStatus},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use bitcoin::{address::NetworkUnchecked, Address, Amount, ScriptBuf, SignedAmount, Txid};
+use log::*;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("invalid channel {0:?}: {1}")]
+    Spec(String, String),
+
+    #[error("webhook failed: {0}")]
+    Webhook(#[from] ureq::Error),
+
+    #[error("command failed: {0}")]
+    Exec(#[from] std::io::Error),
+
+    #[error("command exited with {0}")]
+    ExitStatus(ExitStatus),
+
+    #[error("command was killed after {0:?}")]
+    Timeout(Duration),
+
+    #[error("{0} is not a {1} address")]
+    WrongNetwork(String, bitcoin::Network),
+}
+
+/// A slow webhook (or command) mustn't stall the notifications' thread
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// A watched address' net amount in a new transaction
+#[derive(Clone, Debug)]
+pub struct Notification {
+    pub txid: Txid,
+    /// Formatted as an address (or a hex-encoded script, if it has none)
+    pub address: String,
+    pub script: ScriptBuf,
+    pub delta: SignedAmount,
+    pub height: usize,
+}
+
+impl Notification {
+    fn direction(&self) -> Direction {
+        if self.delta.is_negative() {
+            Direction::Outgoing
+        } else {
+            Direction::Incoming
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "txid": self.txid,
+            "address": self.address,
+            "direction": match self.direction() {
+                Direction::Incoming => "incoming",
+                Direction::Outgoing => "outgoing",
+            },
+            "amount": self.delta.to_sat(),
+            "height": self.height,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Target {
+    Webhook(String),
+    Exec(String),
+}
+
+#[derive(Clone, Debug, Default)]
+struct Filter {
+    direction: Option<Direction>,
+    min_amount: Amount,
+    addresses: Vec<Address<NetworkUnchecked>>,
+    scripts: HashSet<ScriptBuf>,
+}
+
+impl Filter {
+    fn matches(&self, notification: &Notification) -> bool {
+        self.direction.is_none_or(|d| d == notification.direction())
+            && notification.delta.unsigned_abs() >= self.min_amount
+            && (self.scripts.is_empty() || self.scripts.contains(&notification.script))
+    }
+}
+
+/// A notification target, with its filters
+#[derive(Clone, Debug)]
+pub struct Channel {
+    target: Target,
+    filter: Filter,
+}
+
+impl FromStr for Channel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = |msg: String| Error::Spec(s.to_owned(), msg);
+        let mut pairs = s.split(',').map(|pair| {
+            pair.split_once('=')
+                .ok_or_else(|| invalid(format!("{:?} is not a key=value pair", pair)))
+        });
+        let target = match pairs.next().transpose()? {
+            Some(("webhook", url)) => Target::Webhook(url.to_owned()),
+            Some(("exec", command)) => Target::Exec(command.to_owned()),
+            _ => {
+                return Err(invalid(
+                    "expected webhook=<url> or exec=<command>".to_owned(),
+                ))
+            }
+        };
+        let mut filter = Filter::default();
+        for pair in pairs {
+            match pair? {
+                ("direction", "incoming") => filter.direction = Some(Direction::Incoming),
+                ("direction", "outgoing") => filter.direction = Some(Direction::Outgoing),
+                ("min-amount", amount) => {
+                    filter.min_amount = Amount::from_str_in(amount, bitcoin::Denomination::Bitcoin)
+                        .map_err(|e| invalid(format!("invalid amount {:?}: {}", amount, e)))?;
+                }
+                ("address", addr) => {
+                    let addr = Address::from_str(addr)
+                        .map_err(|e| invalid(format!("invalid address {:?}: {}", addr, e)))?;
+                    // the script doesn't depend on the network (which is checked separately)
+                    filter
+                        .scripts
+                        .insert(addr.assume_checked_ref().script_pubkey());
+                    filter.addresses.push(addr);
+                }
+                (key, value) => return Err(invalid(format!("unknown filter {}={}", key, value))),
+            }
+        }
+        Ok(Channel { target, filter })
+    }
+}
+
+impl Channel {
+    /// Verify that the `address=` filters are valid for the network.
+    pub fn check_network(&self, network: bitcoin::Network) -> Result<(), Error> {
+        match self
+            .filter
+            .addresses
+            .iter()
+            .find(|addr| !addr.is_valid_for_network(network))
+        {
+            Some(addr) => Err(Error::WrongNetwork(
+                addr.assume_checked_ref().to_string(),
+                network,
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub fn matches(&self, notification: &Notification) -> bool {
+        self.filter.matches(notification)
+    }
+
+    pub fn send(&self, notification: &Notification) -> Result<(), Error> {
+        self.deliver(
+            notification.to_json(),
+            &[
+                ("BINDEX_TXID", notification.txid.to_string()),
+                ("BINDEX_ADDRESS", notification.address.clone()),
+                ("BINDEX_AMOUNT", notification.delta.to_sat().to_string()),
+                ("BINDEX_HEIGHT", notification.height.to_string()),
+            ],
+        )
+    }
+
+    pub fn send_alert(&self, alert: &str) -> Result<(), Error> {
+        self.deliver(
+            serde_json::json!({"alert": alert}),
+            &[("BINDEX_ALERT", alert.to_owned())],
+        )
+    }
+
+    fn deliver(&self, json: serde_json::Value, vars: &[(&str, String)]) -> Result<(), Error> {
+        match &self.target {
+            Target::Webhook(url) => {
+                let agent = ureq::Agent::new_with_config(
+                    ureq::config::Config::builder()
+                        .timeout_global(Some(TIMEOUT))
+                        .build(),
+                );
+                agent
+                    .post(url)
+                    .header("Content-Type", "application/json")
+                    .send(json.to_string())?;
+            }
+            Target::Exec(command) => {
+                let mut child = Command::new(command)
+                    .envs(vars.iter().map(|(name, value)| (name, value)))
+                    .spawn()?;
+                let status = wait_or_kill(&mut child, TIMEOUT)?;
+                if !status.success() {
+                    return Err(Error::ExitStatus(status));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn wait_or_kill(child: &mut Child, timeout: Duration) -> Result<ExitStatus, Error> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Err(Error::Timeout(timeout));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Send the notifications to the matching channels (failures are logged and skipped).
+pub fn send_all(channels: &[Channel], notifications: &[Notification]) {
+    for notification in notifications {
+        for channel in channels.iter().filter(|c| c.matches(notification)) {
+            if let Err(e) = channel.send(notification) {
+                warn!("notification of txid={} failed: {}", notification.txid, e);
+            }
+        }
+    }
+}
+
+/// Send the alerts to all the channels (failures are logged and skipped).
+pub fn alert_all(channels: &[Channel], alerts: &[String]) {
+    for alert in alerts {
+        for channel in channels {
+            if let Err(e) = channel.send_alert(alert) {
+                warn!("alert {:?} failed: {}", alert, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::hashes::Hash;
+
+    const ADDR: &str = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+
+    fn notification(delta: i64) -> Notification {
+        let addr = bitcoin::Address::from_str(ADDR).unwrap().assume_checked();
+        Notification {
+            txid: Txid::all_zeros(),
+            address: addr.to_string(),
+            script: addr.script_pubkey(),
+            delta: SignedAmount::from_sat(delta),
+            height: 100,
+        }
+    }
+
+    #[test]
+    fn test_filters() -> Result<(), Error> {
+        let channel = Channel::from_str("exec=true")?;
+        assert!(channel.matches(&notification(1)));
+        assert!(channel.matches(&notification(-1)));
+
+        let channel = Channel::from_str("webhook=http://localhost/,direction=incoming")?;
+        assert!(channel.matches(&notification(1)));
+        assert!(!channel.matches(&notification(-1)));
+
+        let channel = Channel::from_str("exec=true,direction=outgoing,min-amount=0.001")?;
+        assert!(!channel.matches(&notification(-99_999)));
+        assert!(channel.matches(&notification(-100_000)));
+        assert!(!channel.matches(&notification(100_000)));
+
+        let other = "bc1qxy2kgdygjrsqtzq2n0yrf2493p83kkfjhx0wlh";
+        let channel = Channel::from_str(&format!("exec=true,address={}", other))?;
+        assert!(!channel.matches(&notification(1)));
+        let channel = Channel::from_str(&format!("exec=true,address={},address={}", other, ADDR))?;
+        assert!(channel.matches(&notification(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Channel::from_str("").is_err());
+        assert!(Channel::from_str("direction=incoming").is_err());
+        assert!(Channel::from_str("exec=true,direction=sideways").is_err());
+        assert!(Channel::from_str("exec=true,min-amount=abc").is_err());
+        assert!(Channel::from_str("exec=true,address=xyz").is_err());
+        assert!(Channel::from_str("exec=true,color=red").is_err());
+    }
+
+    #[test]
+    fn test_exec() {
+        assert!(Channel::from_str("exec=true")
+            .unwrap()
+            .send(&notification(1))
+            .is_ok());
+        assert!(matches!(
+            Channel::from_str("exec=false")
+                .unwrap()
+                .send(&notification(1)),
+            Err(Error::ExitStatus(_))
+        ));
+        let channel = Channel::from_str("exec=true,direction=incoming").unwrap();
+        assert!(channel.send_alert("node is stuck").is_ok());
+
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let t = Instant::now();
+        assert!(matches!(
+            wait_or_kill(&mut child, Duration::from_millis(100)),
+            Err(Error::Timeout(_))
+        ));
+        assert!(t.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_check_network() -> Result<(), Error> {
+        let channel = Channel::from_str(&format!("exec=true,address={}", ADDR))?;
+        channel.check_network(bitcoin::Network::Bitcoin)?;
+        assert!(matches!(
+            channel.check_network(bitcoin::Network::Testnet),
+            Err(Error::WrongNetwork(..))
+        ));
+        Channel::from_str("exec=true")?.check_network(bitcoin::Network::Testnet)
+    }
+}
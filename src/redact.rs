@@ -0,0 +1,41 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use bitcoin::hashes::{sha256, Hash};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Replace sensitive values (addresses, txids, scripts, URLs) in log output by short hashes.
+pub fn enable(value: bool) {
+    ENABLED.store(value, Ordering::Relaxed);
+}
+
+/// A value that is formatted as a short hash (if redaction is enabled).
+pub struct Redacted<T>(pub T);
+
+fn write_hash(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    let hash = sha256::Hash::hash(s.as_bytes());
+    write!(f, "<{}>", &hash.to_string()[..8])
+}
+
+impl<T: fmt::Display> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if ENABLED.load(Ordering::Relaxed) {
+            write_hash(f, &self.0.to_string())
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if ENABLED.load(Ordering::Relaxed) {
+            write_hash(f, &format!("{:?}", self.0))
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
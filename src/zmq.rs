@@ -0,0 +1,170 @@
+//! A minimal ZeroMQ SUB socket (ZMTP 3.0 over TCP, using the NULL mechanism), for receiving
+//! bitcoind notifications (e.g. `-zmqpubhashblock=tcp://127.0.0.1:28332`).
+//!
+//! https://rfc.zeromq.org/spec/23/
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("I/O failed: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid endpoint: {0:?} (expected tcp://host:port)")]
+    Endpoint(String),
+
+    #[error("protocol error: {0}")]
+    Protocol(&'static str),
+}
+
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+
+/// Larger frames are rejected (bitcoind's largest notification is a raw block)
+const MAX_FRAME_SIZE: u64 = 64 << 20;
+
+pub struct Subscriber {
+    stream: TcpStream,
+}
+
+impl Subscriber {
+    /// Connect to a publisher, subscribing to the given topics (e.g. `hashblock`).
+    pub fn connect(endpoint: &str, topics: &[&str]) -> Result<Self, Error> {
+        let addr = endpoint
+            .strip_prefix("tcp://")
+            .ok_or_else(|| Error::Endpoint(endpoint.to_owned()))?;
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+
+        stream.write_all(&greeting())?;
+        let mut peer = [0u8; 64];
+        stream.read_exact(&mut peer)?;
+        if peer[0] != 0xFF || peer[9] != 0x7F {
+            return Err(Error::Protocol("invalid greeting"));
+        }
+        if peer[10] < 3 {
+            return Err(Error::Protocol("unsupported ZMTP version"));
+        }
+        if &peer[12..17] != b"NULL\0" {
+            return Err(Error::Protocol("unsupported security mechanism"));
+        }
+
+        let mut ready = command_name("READY");
+        ready.extend(property("Socket-Type", b"SUB"));
+        write_frame(&mut stream, FLAG_COMMAND, &ready)?;
+        let (flags, body) = read_frame(&mut stream)?;
+        if flags & FLAG_COMMAND == 0 || !body.starts_with(&command_name("READY")) {
+            return Err(Error::Protocol("expected READY command"));
+        }
+
+        for topic in topics {
+            // ZMTP 3.0 subscriptions are sent as messages (prefixed by 0x01)
+            let mut subscribe = vec![0x01];
+            subscribe.extend_from_slice(topic.as_bytes());
+            write_frame(&mut stream, 0, &subscribe)?;
+        }
+        Ok(Self { stream })
+    }
+
+    /// Block until the next (multipart) message is received.
+    pub fn recv(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+        read_message(&mut self.stream)
+    }
+}
+
+fn greeting() -> [u8; 64] {
+    let mut greeting = [0u8; 64];
+    greeting[0] = 0xFF; // signature
+    greeting[9] = 0x7F;
+    greeting[10] = 3; // version 3.0
+    greeting[12..16].copy_from_slice(b"NULL");
+    greeting
+}
+
+fn command_name(name: &str) -> Vec<u8> {
+    let mut bytes = vec![name.len() as u8];
+    bytes.extend_from_slice(name.as_bytes());
+    bytes
+}
+
+fn property(name: &str, value: &[u8]) -> Vec<u8> {
+    let mut bytes = command_name(name);
+    bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(value);
+    bytes
+}
+
+fn write_frame(mut w: impl Write, flags: u8, body: &[u8]) -> Result<(), Error> {
+    match u8::try_from(body.len()) {
+        Ok(size) => w.write_all(&[flags, size])?,
+        Err(_) => {
+            w.write_all(&[flags | FLAG_LONG])?;
+            w.write_all(&(body.len() as u64).to_be_bytes())?;
+        }
+    }
+    w.write_all(body)?;
+    Ok(())
+}
+
+fn read_frame(mut r: impl Read) -> Result<(u8, Vec<u8>), Error> {
+    let mut flags = [0u8];
+    r.read_exact(&mut flags)?;
+    let flags = flags[0];
+    let size = if flags & FLAG_LONG != 0 {
+        let mut size = [0u8; 8];
+        r.read_exact(&mut size)?;
+        u64::from_be_bytes(size)
+    } else {
+        let mut size = [0u8];
+        r.read_exact(&mut size)?;
+        size[0].into()
+    };
+    if size > MAX_FRAME_SIZE {
+        return Err(Error::Protocol("frame too large"));
+    }
+    let mut body = vec![0u8; size as usize];
+    r.read_exact(&mut body)?;
+    Ok((flags, body))
+}
+
+/// Read the next message's frames (skipping commands).
+fn read_message(mut r: impl Read) -> Result<Vec<Vec<u8>>, Error> {
+    let mut frames = vec![];
+    loop {
+        let (flags, body) = read_frame(&mut r)?;
+        if flags & FLAG_COMMAND != 0 {
+            continue;
+        }
+        frames.push(body);
+        if flags & FLAG_MORE == 0 {
+            return Ok(frames);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message() {
+        let hash = [0xAB; 32];
+        let raw = vec![0xCD; 300]; // uses a long frame
+        let mut bytes = vec![];
+        write_frame(&mut bytes, FLAG_COMMAND, &command_name("PING")).unwrap();
+        write_frame(&mut bytes, FLAG_MORE, b"hashblock").unwrap();
+        write_frame(&mut bytes, FLAG_MORE, &hash).unwrap();
+        write_frame(&mut bytes, 0, &raw).unwrap();
+        write_frame(&mut bytes, 0, b"next").unwrap();
+
+        let mut r = bytes.as_slice();
+        let frames = read_message(&mut r).unwrap();
+        assert_eq!(frames, vec![b"hashblock".to_vec(), hash.to_vec(), raw]);
+        assert_eq!(read_message(&mut r).unwrap(), vec![b"next".to_vec()]);
+        assert!(read_message(&mut r).is_err());
+    }
+}